@@ -0,0 +1,44 @@
+//! The `debug` subcommand for the cannon binary
+
+use super::CannonSubcommandDispatcher;
+use anyhow::Result;
+use cannon::KernelBuilder;
+use clap::Args;
+
+/// Command line arguments for `cannon debug`
+#[derive(Args, Debug)]
+#[command(author, version, about)]
+pub(crate) struct DebugArgs {
+    /// The preimage oracle command
+    #[arg(long)]
+    preimage_server: String,
+
+    /// The path to the input JSON state.
+    #[arg(long)]
+    input: String,
+
+    /// The address to serve the GDB remote serial protocol on, e.g. `127.0.0.1:1234`. Attach
+    /// with `gdb-multiarch` and `target remote <address>`.
+    #[arg(long)]
+    gdb: String,
+
+    /// Fault the VM on syscalls it does not know, instead of returning 0 like `MIPS.sol` does.
+    #[arg(long)]
+    strict_syscalls: bool,
+
+    /// The path to a file to serve the guest's stdin from.
+    #[arg(long)]
+    stdin: Option<String>,
+}
+
+impl CannonSubcommandDispatcher for DebugArgs {
+    fn dispatch(self) -> Result<()> {
+        let kernel = KernelBuilder::default()
+            .with_preimage_server(self.preimage_server.replace('"', ""))
+            .with_input(self.input)
+            .with_strict_syscalls(self.strict_syscalls)
+            .with_stdin(self.stdin)
+            .build()?;
+        kernel.debug(self.gdb)
+    }
+}
@@ -4,7 +4,7 @@ use super::CannonSubcommandDispatcher;
 use alloy_primitives::B256;
 use anyhow::Result;
 use cannon::gz::compress_bytes;
-use cannon_mipsevm::{load_elf, patch_go, patch_stack, StateWitnessHasher};
+use cannon_mipsevm::{load_elf, patch_go, patch_stack, SymbolTable};
 use clap::Args;
 use std::{
     fmt::Display,
@@ -26,10 +26,20 @@ pub(crate) struct LoadElfArgs {
     #[arg(long, default_values = ["go", "stack"])]
     patch_kind: Vec<PatchKind>,
 
+    /// Run the program in multithreaded mode. Unpatched Go runtimes require this, so omit the
+    /// `go` patch kind when it is set.
+    #[arg(long)]
+    multithreaded: bool,
+
     /// The output path to write the JSON state to. State will be dumped to stdout if set to `-`.
     /// Not written if not provided.
     #[arg(long)]
     output: Option<String>,
+
+    /// The output path to write the JSON symbol table of the ELF file to, for symbolizing
+    /// program counters with `cannon run --meta`. Not written if not provided.
+    #[arg(long)]
+    meta: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -78,6 +88,11 @@ impl CannonSubcommandDispatcher for LoadElfArgs {
             }?;
         }
 
+        if self.multithreaded {
+            state.enable_threading();
+            tracing::info!(target: "cannon-cli::load-elf", "Enabled multithreaded mode");
+        }
+
         if let Some(ref path_str) = self.output {
             if path_str == "-" {
                 println!("{}", serde_json::to_string(&state)?);
@@ -89,7 +104,13 @@ impl CannonSubcommandDispatcher for LoadElfArgs {
             }
         }
 
-        tracing::info!(target: "cannon-cli::load-elf", "Patched the ELF file and dumped the State successfully. state hash: {} mem size: {} pages: {}", B256::from(state.encode_witness()?.state_hash()), state.memory.usage(), state.memory.page_count());
+        if let Some(ref path_str) = self.meta {
+            let symbols = SymbolTable::from_elf(&elf_raw)?;
+            serde_json::to_writer(BufWriter::new(File::create(path_str)?), &symbols)?;
+            tracing::info!(target: "cannon-cli::load-elf", "Wrote {} symbols to {}", symbols.symbols().len(), path_str);
+        }
+
+        tracing::info!(target: "cannon-cli::load-elf", "Patched the ELF file and dumped the State successfully. state hash: {} mem size: {} pages: {}", B256::from(state.state_hash()?), state.memory.usage(), state.memory.page_count());
 
         Ok(())
     }
@@ -3,6 +3,7 @@
 use anyhow::Result;
 use clap::Subcommand;
 
+mod debug;
 mod load_elf;
 mod run;
 mod witness;
@@ -15,17 +16,19 @@ pub(crate) trait CannonSubcommandDispatcher {
 /// The subcommands for the `cannon` binary
 #[derive(Subcommand, Debug)]
 pub(crate) enum CannonSubcommand {
-    Run(run::RunArgs),
+    Run(Box<run::RunArgs>),
     Witness(witness::WitnessArgs),
     LoadElf(load_elf::LoadElfArgs),
+    Debug(debug::DebugArgs),
 }
 
 impl CannonSubcommandDispatcher for CannonSubcommand {
     fn dispatch(self) -> Result<()> {
         match self {
-            CannonSubcommand::Run(args) => args.dispatch(),
+            CannonSubcommand::Run(args) => (*args).dispatch(),
             CannonSubcommand::Witness(args) => args.dispatch(),
             CannonSubcommand::LoadElf(args) => args.dispatch(),
+            CannonSubcommand::Debug(args) => args.dispatch(),
         }
     }
 }
@@ -45,6 +45,85 @@ pub(crate) struct RunArgs {
     /// The pattern to print information at.
     #[arg(long)]
     info_at: Option<String>,
+
+    /// Fault the VM on syscalls it does not know, instead of returning 0 like `MIPS.sol` does.
+    #[arg(long)]
+    strict_syscalls: bool,
+
+    /// The address the guest's heap may not grow past, e.g. `0x60000000`. Anonymous mmaps past
+    /// it fail with ENOMEM.
+    #[arg(long, value_parser = parse_address)]
+    heap_limit: Option<u32>,
+
+    /// The maximum number of 4KiB memory pages the guest may allocate. The run is aborted when
+    /// it is exceeded.
+    #[arg(long)]
+    page_budget: Option<usize>,
+
+    /// The path to a file to evict cold memory pages to, for guests whose memory does not fit in
+    /// RAM. The file is truncated, deleted when the run ends, and grows to about the size of the
+    /// guest's memory beyond `--resident-pages`.
+    #[arg(long)]
+    page_store: Option<String>,
+
+    /// The maximum number of 4KiB memory pages kept in RAM when `--page-store` is set. Defaults
+    /// to 65536.
+    #[arg(long)]
+    resident_pages: Option<usize>,
+
+    /// The path to a file to serve the guest's stdin from. Stdin is empty by default, like it is
+    /// in `MIPS.sol`, so runs that read from it cannot be proven on-chain.
+    #[arg(long)]
+    stdin: Option<String>,
+
+    /// The path to a JSON cost table with the `alu`, `memory`, `branch`, `syscall` and
+    /// `preimageRead` instruction costs. Costs that are left out default to 1.
+    #[arg(long)]
+    cost_table: Option<String>,
+
+    /// The cost after which the run is stopped. The final state is still written, and the run
+    /// fails with a distinct error.
+    #[arg(long)]
+    cost_budget: Option<u64>,
+
+    /// The path to write a binary execution trace to, with a record of the register, memory and
+    /// syscall effects of every executed instruction. Tracing disables the fast-forward engine.
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// The path to the JSON symbol table written by `cannon load-elf --meta`. Program counters
+    /// in info logs are printed as `function+offset`.
+    #[arg(long)]
+    meta: Option<String>,
+
+    /// Reconstruct the guest's call stack and print it in info logs. Tracking the call stack
+    /// disables the fast-forward engine.
+    #[arg(long)]
+    call_stack: bool,
+
+    /// The path to write a profile of the guest to, as folded stacks for `flamegraph.pl` or
+    /// `inferno-flamegraph`. Samples are resolved against the `--meta` symbols, and include the
+    /// call stack with `--call-stack`.
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// The number of steps between profile samples. Defaults to 10000.
+    #[arg(long)]
+    profile_interval: Option<u64>,
+
+    /// The path to write JSON run statistics to when the VM exits, or `-` for stdout: the
+    /// instruction mix, syscall counts, preimage and hint bytes, and allocated pages over time.
+    /// Collecting statistics disables the fast-forward engine.
+    #[arg(long)]
+    stats: Option<String>,
+}
+
+/// Parses a hex (`0x` prefixed) or decimal address.
+fn parse_address(s: &str) -> Result<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u32::from_str_radix(hex, 16)?),
+        None => Ok(s.parse()?),
+    }
 }
 
 impl CannonSubcommandDispatcher for RunArgs {
@@ -59,6 +138,20 @@ impl CannonSubcommandDispatcher for RunArgs {
             .with_snapshot_format(self.snapshot_format)
             .with_stop_at(self.stop_at)
             .with_info_at(self.info_at)
+            .with_strict_syscalls(self.strict_syscalls)
+            .with_heap_limit(self.heap_limit)
+            .with_page_budget(self.page_budget)
+            .with_page_store(self.page_store)
+            .with_resident_pages(self.resident_pages)
+            .with_stdin(self.stdin)
+            .with_cost_table(self.cost_table)
+            .with_cost_budget(self.cost_budget)
+            .with_trace(self.trace)
+            .with_meta(self.meta)
+            .with_call_stack(self.call_stack)
+            .with_profile(self.profile)
+            .with_profile_interval(self.profile_interval)
+            .with_stats(self.stats)
             .build()?;
         kernel.run()
     }
@@ -4,7 +4,7 @@ use super::CannonSubcommandDispatcher;
 use alloy_primitives::B256;
 use anyhow::Result;
 use cannon::gz::decompress_bytes;
-use cannon_mipsevm::{State, StateWitnessHasher};
+use cannon_mipsevm::State;
 use clap::Args;
 use std::{fs, path::PathBuf};
 
@@ -30,8 +30,8 @@ impl CannonSubcommandDispatcher for WitnessArgs {
 
         tracing::info!(target: "cannon-cli::witness", "Loaded state JSON dump and deserialized the State");
 
-        let witness = state.encode_witness()?;
-        let witness_hash = witness.state_hash();
+        let witness = state.encode_witness_bytes()?;
+        let witness_hash = state.state_hash()?;
 
         tracing::info!(target: "cannon-cli::witness", "Encoded witness and computed witness hash: {}", B256::from(witness_hash));
 
@@ -1,14 +1,23 @@
 //! The [KernelBuilder] struct is a helper for building a [Kernel] struct.
 
-use crate::{gz, ChildWithFds, Kernel, ProcessPreimageOracle};
+use crate::{gz, ChildWithFds, Kernel, ProcessPreimageOracle, Profiler};
 use anyhow::{anyhow, Result};
-use cannon_mipsevm::{InstrumentedState, State};
+use cannon_mipsevm::{
+    CostMeter, CostTable, FilePageStore, InstrumentedState, State, SymbolTable, TraceRecorder,
+};
 use std::{
     fs::{self, File},
     io::{self, BufReader, Read, Stderr, Stdout},
     path::PathBuf,
+    sync::Arc,
 };
 
+/// The default number of steps between profile samples.
+const DEFAULT_PROFILE_INTERVAL: u64 = 10_000;
+
+/// The default number of memory pages kept in memory when pages are evicted to a page store.
+const DEFAULT_RESIDENT_PAGES: usize = 1 << 16;
+
 /// The [KernelBuilder] struct is a helper for building a [Kernel] struct.
 #[derive(Default, Debug)]
 pub struct KernelBuilder {
@@ -31,6 +40,34 @@ pub struct KernelBuilder {
     stop_at: Option<String>,
     /// The pattern to print information at.
     info_at: Option<String>,
+    /// Whether or not unknown syscalls fault the VM.
+    strict_syscalls: bool,
+    /// The address the guest's heap may not grow past.
+    heap_limit: Option<u32>,
+    /// The maximum number of memory pages the guest may allocate.
+    page_budget: Option<usize>,
+    /// The path to the file cold memory pages are evicted to.
+    page_store: Option<String>,
+    /// The maximum number of memory pages kept in memory when pages are evicted.
+    resident_pages: Option<usize>,
+    /// The path to the file the guest's stdin is read from.
+    stdin: Option<String>,
+    /// The path to the JSON [CostTable] instructions are charged from.
+    cost_table: Option<String>,
+    /// The cost after which the guest is stopped.
+    cost_budget: Option<u64>,
+    /// The path to write the binary execution trace to.
+    trace: Option<String>,
+    /// The path to the JSON [SymbolTable] of the guest program.
+    meta: Option<String>,
+    /// Whether or not the guest's call stack is tracked.
+    call_stack: bool,
+    /// The path to write the folded stacks of the guest profile to.
+    profile: Option<String>,
+    /// The number of steps between profile samples.
+    profile_interval: Option<u64>,
+    /// The path to write the JSON run statistics to.
+    stats: Option<String>,
 }
 
 impl KernelBuilder {
@@ -47,7 +84,14 @@ impl KernelBuilder {
         let mut raw_state = Vec::with_capacity(f_sz as usize);
         reader.read_to_end(&mut raw_state)?;
         let raw_state = fs::read(&self.input)?;
-        let state: State = serde_json::from_slice(&gz::decompress_bytes(&raw_state)?)?;
+        let mut state: State = serde_json::from_slice(&gz::decompress_bytes(&raw_state)?)?;
+        state.memory.page_budget = self.page_budget;
+        if let Some(path) = &self.page_store {
+            state.memory.set_page_store(
+                Arc::new(FilePageStore::create(path)?),
+                self.resident_pages.unwrap_or(DEFAULT_RESIDENT_PAGES),
+            )?;
+        }
 
         let (hint_cl_rw, hint_oracle_rw) = preimage_oracle::create_bidirectional_channel()?;
         let (pre_cl_rw, pre_oracle_rw) = preimage_oracle::create_bidirectional_channel()?;
@@ -76,10 +120,35 @@ impl KernelBuilder {
         });
 
         // TODO(clabby): Allow for the stdout / stderr to be configurable.
-        let instrumented = InstrumentedState::new(state, oracle, io::stdout(), io::stderr());
+        let mut instrumented = InstrumentedState::new(state, oracle, io::stdout(), io::stderr())
+            .with_strict_syscalls(self.strict_syscalls)
+            .with_heap_limit(self.heap_limit)
+            .with_call_stack(self.call_stack)
+            .with_stats(self.stats.is_some());
+        if let Some(stdin) = &self.stdin {
+            instrumented = instrumented.with_stdin(BufReader::new(File::open(stdin)?));
+        }
+        if self.cost_table.is_some() || self.cost_budget.is_some() {
+            let table: CostTable = match &self.cost_table {
+                Some(path) => serde_json::from_slice(&fs::read(path)?)?,
+                None => CostTable::default(),
+            };
+            instrumented =
+                instrumented.with_cost_meter(CostMeter::new(table).with_budget(self.cost_budget));
+        }
+        if let Some(trace) = &self.trace {
+            instrumented =
+                instrumented.with_trace_recorder(TraceRecorder::new(File::create(trace)?)?);
+        }
+
+        let symbols: Option<SymbolTable> = match &self.meta {
+            Some(path) => Some(serde_json::from_slice(&fs::read(path)?)?),
+            None => None,
+        };
 
         Ok(Kernel::new(
             instrumented,
+            symbols,
             server_proc,
             self.input,
             self.output,
@@ -89,6 +158,11 @@ impl KernelBuilder {
             self.snapshot_format,
             self.stop_at,
             self.info_at,
+            self.profile
+                .is_some()
+                .then(|| Profiler::new(self.profile_interval.unwrap_or(DEFAULT_PROFILE_INTERVAL))),
+            self.profile,
+            self.stats,
         ))
     }
 
@@ -136,4 +210,74 @@ impl KernelBuilder {
         self.info_at = info_at;
         self
     }
+
+    pub fn with_strict_syscalls(mut self, strict_syscalls: bool) -> Self {
+        self.strict_syscalls = strict_syscalls;
+        self
+    }
+
+    pub fn with_heap_limit(mut self, heap_limit: Option<u32>) -> Self {
+        self.heap_limit = heap_limit;
+        self
+    }
+
+    pub fn with_page_budget(mut self, page_budget: Option<usize>) -> Self {
+        self.page_budget = page_budget;
+        self
+    }
+
+    pub fn with_page_store(mut self, page_store: Option<String>) -> Self {
+        self.page_store = page_store;
+        self
+    }
+
+    pub fn with_resident_pages(mut self, resident_pages: Option<usize>) -> Self {
+        self.resident_pages = resident_pages;
+        self
+    }
+
+    pub fn with_stdin(mut self, stdin: Option<String>) -> Self {
+        self.stdin = stdin;
+        self
+    }
+
+    pub fn with_cost_table(mut self, cost_table: Option<String>) -> Self {
+        self.cost_table = cost_table;
+        self
+    }
+
+    pub fn with_cost_budget(mut self, cost_budget: Option<u64>) -> Self {
+        self.cost_budget = cost_budget;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: Option<String>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_meta(mut self, meta: Option<String>) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    pub fn with_call_stack(mut self, call_stack: bool) -> Self {
+        self.call_stack = call_stack;
+        self
+    }
+
+    pub fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn with_profile_interval(mut self, profile_interval: Option<u64>) -> Self {
+        self.profile_interval = profile_interval;
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Option<String>) -> Self {
+        self.stats = stats;
+        self
+    }
 }
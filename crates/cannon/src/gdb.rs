@@ -0,0 +1,600 @@
+//! This module contains the [GdbStub], which exposes an [InstrumentedState] over the GDB remote
+//! serial protocol so that the guest can be debugged with `gdb-multiarch`.
+
+use anyhow::Result;
+use cannon_mipsevm::{
+    Address, FaultKind, InstrumentedState, PreimageOracle, StopReason, VmError, WatchKind,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+/// The number of steps performed between checks for an interrupt from the debugger while
+/// continuing.
+const INTERRUPT_POLL_INTERVAL: u64 = 100_000;
+
+/// The maximum number of bytes of a packet's payload that the stub accepts.
+const MAX_PACKET_SIZE: usize = 0x4000;
+
+/// The number of registers reported by the `g` packet: the 32 general purpose registers, followed
+/// by `sr`, `lo`, `hi`, `bad`, `cause` and `pc`, in the order of GDB's MIPS register set.
+const NUM_REGISTERS: usize = 38;
+
+/// The GDB register number of `pc`.
+const PC_REGISTER: usize = 37;
+
+/// Stop signals reported to the debugger.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// The action the [GdbStub] takes after handling a packet.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    /// Send the reply and wait for the next packet.
+    Reply(String),
+    /// Send the reply and end the session.
+    Detach(String),
+    /// End the session without replying.
+    Kill,
+}
+
+/// The [GdbStub] serves a single GDB remote serial protocol session for an [InstrumentedState].
+///
+/// The guest is presented as a single-threaded MIPS32 process. Breakpoints (`Z0`/`Z1`) and
+/// watchpoints (`Z2`-`Z4`) are backed by the breakpoints and watchpoints of the
+/// [InstrumentedState], and memory is read without firing them.
+pub struct GdbStub<'a, O: Write, E: Write, P: PreimageOracle> {
+    /// The [InstrumentedState] being debugged.
+    ins_state: &'a mut InstrumentedState<O, E, P>,
+    /// The ids of the breakpoints set by the debugger, by address.
+    breakpoints: HashMap<Address, usize>,
+    /// The ids and kinds of the watchpoints set by the debugger, by address and length.
+    watchpoints: HashMap<(Address, u32), (usize, WatchKind)>,
+    /// Whether or not the debugger disabled packet acknowledgments.
+    no_ack: bool,
+}
+
+impl<'a, O, E, P> GdbStub<'a, O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    /// Creates a new [GdbStub] for the given [InstrumentedState].
+    pub fn new(ins_state: &'a mut InstrumentedState<O, E, P>) -> Self {
+        Self {
+            ins_state,
+            breakpoints: HashMap::default(),
+            watchpoints: HashMap::default(),
+            no_ack: false,
+        }
+    }
+
+    /// Serves a debugger connected over the given [TcpStream], until it detaches, kills the
+    /// guest or disconnects.
+    ///
+    /// ### Takes
+    /// - `stream`: The connection to the debugger.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the session ended without an I/O error.
+    pub fn serve(&mut self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        while let Some(packet) = read_packet(&mut reader, &mut writer, self.no_ack)? {
+            crate::traces::debug!(target: "cannon::gdb", "<- {}", packet);
+
+            let mut interrupted = || poll_interrupt(&mut reader);
+            let (reply, done) = match self.handle(&packet, &mut interrupted)? {
+                Action::Reply(reply) => (Some(reply), false),
+                Action::Detach(reply) => (Some(reply), true),
+                Action::Kill => (None, true),
+            };
+
+            if let Some(reply) = reply {
+                crate::traces::debug!(target: "cannon::gdb", "-> {}", reply);
+                write_packet(&mut writer, &reply)?;
+            }
+            if done {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a single packet.
+    ///
+    /// ### Takes
+    /// - `packet`: The payload of the packet.
+    /// - `interrupted`: Returns `true` if the debugger interrupted a running guest.
+    ///
+    /// ### Returns
+    /// - The [Action] to take.
+    fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Result<Action> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.stop_reply(SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => self.resume(args, 1, interrupted)?,
+            "c" => self.resume(args, u64::MAX, interrupted)?,
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "H" | "T" => Some("OK".to_string()),
+            "D" => return Ok(Action::Detach("OK".to_string())),
+            "k" => return Ok(Action::Kill),
+            "v" if packet.starts_with("vKill") => return Ok(Action::Detach("OK".to_string())),
+            "q" | "Q" | "v" => self.query(packet),
+            _ => Some(String::new()),
+        };
+        Ok(Action::Reply(reply.unwrap_or_else(|| "E01".to_string())))
+    }
+
+    /// Handles the general query and `v` packets.
+    fn query(&mut self, packet: &str) -> Option<String> {
+        let reply = if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};QStartNoAckMode+", MAX_PACKET_SIZE)
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        };
+        Some(reply)
+    }
+
+    /// Returns the value of the register with the given GDB register number.
+    fn register(&self, index: usize) -> u32 {
+        let state = &self.ins_state.state;
+        match index {
+            0..=31 => state.registers[index],
+            33 => state.lo,
+            34 => state.hi,
+            PC_REGISTER => state.pc,
+            _ => 0,
+        }
+    }
+
+    /// Sets the value of the register with the given GDB register number. Writes to registers
+    /// that the VM does not have are ignored.
+    fn set_register(&mut self, index: usize, value: u32) {
+        let state = &mut self.ins_state.state;
+        match index {
+            // `$zero` is hardwired to 0.
+            1..=31 => state.registers[index] = value,
+            33 => state.lo = value,
+            34 => state.hi = value,
+            // Rewriting the current pc, as a `G` packet does, keeps a pending branch target.
+            PC_REGISTER if value != state.pc => {
+                state.pc = value;
+                state.next_pc = value.wrapping_add(4);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the `g` packet.
+    fn read_registers(&self) -> Option<String> {
+        let mut reply = String::with_capacity(NUM_REGISTERS * 8);
+        for index in 0..NUM_REGISTERS {
+            let _ = write!(reply, "{:08x}", self.register(index));
+        }
+        Some(reply)
+    }
+
+    /// Handles the `G` packet.
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let values = decode_hex(args)?;
+        for (index, value) in values.chunks_exact(4).take(NUM_REGISTERS).enumerate() {
+            self.set_register(index, u32::from_be_bytes(value.try_into().ok()?));
+        }
+        Some("OK".to_string())
+    }
+
+    /// Handles the `p` packet.
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from_str_radix(args, 16).ok()?;
+        if index < NUM_REGISTERS {
+            Some(format!("{:08x}", self.register(index)))
+        } else {
+            // The floating point and DSP registers are not available.
+            Some("xxxxxxxx".to_string())
+        }
+    }
+
+    /// Handles the `P` packet.
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (index, value) = args.split_once('=')?;
+        let index = usize::from_str_radix(index, 16).ok()?;
+        let value = u32::from_be_bytes(decode_hex(value)?.try_into().ok()?);
+        self.set_register(index, value);
+        Some("OK".to_string())
+    }
+
+    /// Handles the `m` packet. Memory is read without firing watchpoints.
+    fn read_memory(&mut self, args: &str) -> Option<String> {
+        let (address, len) = parse_address_len(args)?;
+        let len = (len as usize).min(MAX_PACKET_SIZE / 2);
+
+        let mut reply = String::with_capacity(len * 2);
+        for offset in 0..len as u32 {
+            let address = address.wrapping_add(offset);
+            let word = self
+                .ins_state
+                .state
+                .memory
+                .peek_memory(address & !0x3)
+                .ok()?;
+            let _ = write!(reply, "{:02x}", word.to_be_bytes()[address as usize & 0x3]);
+        }
+        Some(reply)
+    }
+
+    /// Handles the `M` packet.
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (location, data) = args.split_once(':')?;
+        let (address, len) = parse_address_len(location)?;
+        let data = decode_hex(data)?;
+        if data.len() != len as usize {
+            return None;
+        }
+
+        let memory = &mut self.ins_state.state.memory;
+        for (offset, byte) in data.into_iter().enumerate() {
+            let address = address.wrapping_add(offset as u32);
+            let mut word = memory.peek_memory(address & !0x3).ok()?.to_be_bytes();
+            word[address as usize & 0x3] = byte;
+            memory
+                .set_memory(address & !0x3, u32::from_be_bytes(word))
+                .ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    /// Handles the `s` and `c` packets.
+    ///
+    /// ### Takes
+    /// - `args`: The optional address to resume at.
+    /// - `max_steps`: The maximum number of steps to perform.
+    /// - `interrupted`: Returns `true` if the debugger interrupted the guest.
+    ///
+    /// ### Returns
+    /// - The stop reply.
+    fn resume(
+        &mut self,
+        args: &str,
+        max_steps: u64,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<String>> {
+        if !args.is_empty() {
+            let Ok(pc) = u32::from_str_radix(args, 16) else {
+                return Ok(None);
+            };
+            self.set_register(PC_REGISTER, pc);
+        }
+
+        let end = self.ins_state.state.step.saturating_add(max_steps);
+        loop {
+            let steps = (end - self.ins_state.state.step).min(INTERRUPT_POLL_INTERVAL);
+            let stop = match self.ins_state.run_until_break(steps) {
+                Ok(stop) => stop,
+                Err(e) => return Ok(self.fault_reply(e)),
+            };
+
+            match stop.reason {
+                StopReason::Exited => {
+                    return Ok(Some(format!("W{:02x}", self.ins_state.state.exit_code)))
+                }
+                StopReason::Breakpoint(_) => return Ok(self.stop_reply(SIGTRAP)),
+                StopReason::Watchpoint(hit) => {
+                    let kind = self
+                        .watchpoints
+                        .values()
+                        .find(|(id, _)| *id == hit.id)
+                        .map_or(hit.access, |(_, kind)| *kind);
+                    let name = match kind {
+                        WatchKind::Write => "watch",
+                        WatchKind::Read => "rwatch",
+                        WatchKind::Access => "awatch",
+                    };
+                    return Ok(Some(format!(
+                        "T{:02x}{}:{:08x};thread:1;",
+                        SIGTRAP, name, hit.address
+                    )));
+                }
+                StopReason::StepLimit => {
+                    if stop.step >= end {
+                        return Ok(self.stop_reply(SIGTRAP));
+                    }
+                    // The next call does not stop at a breakpoint it starts on, so breakpoints
+                    // at the end of a chunk are checked here.
+                    if self.breakpoints.contains_key(&stop.pc) {
+                        return Ok(self.stop_reply(SIGTRAP));
+                    }
+                    if interrupted() {
+                        return Ok(self.stop_reply(SIGINT));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Converts an error returned while resuming the guest into a stop reply. Faults of the
+    /// guest are reported as signals, so that the debugger can inspect the faulting state. Host
+    /// errors are reported as `SIGABRT`.
+    fn fault_reply(&self, e: VmError) -> Option<String> {
+        let signal = match &e {
+            VmError::Fault { kind, .. } => match kind {
+                FaultKind::InvalidOpcode(_) | FaultKind::InvalidFunction(_) => SIGILL,
+                FaultKind::DivisionByZero => SIGFPE,
+                _ => SIGSEGV,
+            },
+            _ => SIGABRT,
+        };
+        crate::traces::warn!(target: "cannon::gdb", "Guest stopped: {}", e);
+        self.stop_reply(signal)
+    }
+
+    /// Returns the stop reply for the given signal, or the exit reply if the guest has exited.
+    fn stop_reply(&self, signal: u8) -> Option<String> {
+        let state = &self.ins_state.state;
+        if state.exited {
+            Some(format!("W{:02x}", state.exit_code))
+        } else {
+            Some(format!("T{:02x}thread:1;", signal))
+        }
+    }
+
+    /// Handles the `Z` packet.
+    fn insert_point(&mut self, args: &str) -> Option<String> {
+        let (kind, address, len) = parse_point(args)?;
+        match kind {
+            0 | 1 => {
+                if !self.breakpoints.contains_key(&address) {
+                    let id = self.ins_state.add_breakpoint(address, None);
+                    self.breakpoints.insert(address, id);
+                }
+            }
+            2..=4 => {
+                let kind = match kind {
+                    2 => WatchKind::Write,
+                    3 => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let end = address.checked_add(len)?;
+                let id = self.ins_state.add_watchpoint(address..end, kind);
+                if let Some((old, _)) = self.watchpoints.insert((address, len), (id, kind)) {
+                    self.ins_state.remove_watchpoint(old);
+                }
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".to_string())
+    }
+
+    /// Handles the `z` packet.
+    fn remove_point(&mut self, args: &str) -> Option<String> {
+        let (kind, address, len) = parse_point(args)?;
+        match kind {
+            0 | 1 => {
+                if let Some(id) = self.breakpoints.remove(&address) {
+                    self.ins_state.remove_breakpoint(id);
+                }
+            }
+            2..=4 => {
+                if let Some((id, _)) = self.watchpoints.remove(&(address, len)) {
+                    self.ins_state.remove_watchpoint(id);
+                }
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".to_string())
+    }
+}
+
+/// Parses the `addr,length` arguments of the memory packets.
+fn parse_address_len(args: &str) -> Option<(Address, u32)> {
+    let (address, len) = args.split_once(',')?;
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        u32::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+/// Parses the `type,addr,kind` arguments of the `Z` and `z` packets.
+fn parse_point(args: &str) -> Option<(u8, Address, u32)> {
+    let (kind, location) = args.split_once(',')?;
+    // Conditions and commands evaluated by the target are not supported, and are ignored.
+    let location = location.split(';').next()?;
+    let (address, len) = parse_address_len(location)?;
+    Some((kind.parse().ok()?, address, len))
+}
+
+/// Decodes a hex string.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Reads the next packet from the debugger, acknowledging it unless acknowledgments are
+/// disabled. Interrupts received while the guest is stopped are ignored.
+///
+/// ### Takes
+/// - `reader`: The reading half of the connection.
+/// - `writer`: The writing half of the connection.
+/// - `no_ack`: Whether or not acknowledgments are disabled.
+///
+/// ### Returns
+/// - The payload of the packet, or `None` if the debugger disconnected.
+fn read_packet(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    no_ack: bool,
+) -> io::Result<Option<String>> {
+    loop {
+        let mut skipped = Vec::new();
+        if reader.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+            return Ok(None);
+        }
+
+        let mut payload = Vec::new();
+        reader.read_until(b'#', &mut payload)?;
+        if payload.pop() != Some(b'#') {
+            return Ok(None);
+        }
+        let mut checksum = [0u8; 2];
+        reader.read_exact(&mut checksum)?;
+
+        let valid = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+            == Some(payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)));
+        if !no_ack {
+            writer.write_all(if valid { b"+" } else { b"-" })?;
+            writer.flush()?;
+        }
+        if valid && payload.len() <= MAX_PACKET_SIZE {
+            return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+        }
+    }
+}
+
+/// Writes a packet to the debugger. Acknowledgments of the debugger are consumed by
+/// [read_packet], and lost packets are not retransmitted.
+fn write_packet(writer: &mut impl Write, payload: &str) -> io::Result<()> {
+    let checksum = payload.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(writer, "${}#{:02x}", payload, checksum)?;
+    writer.flush()
+}
+
+/// Checks, without blocking, whether the debugger sent an interrupt or disconnected.
+fn poll_interrupt(reader: &mut BufReader<TcpStream>) -> bool {
+    if reader.buffer().contains(&0x03) {
+        reader.consume(reader.buffer().len());
+        return true;
+    }
+
+    let stream = reader.get_mut();
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0u8; 1];
+    let interrupted = match stream.read(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => byte[0] == 0x03,
+        Err(_) => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, GdbStub};
+    use cannon_mipsevm::test_utils::{program, StaticOracle};
+    use std::io;
+
+    fn reply<O: io::Write, E: io::Write>(
+        stub: &mut GdbStub<'_, O, E, StaticOracle>,
+        packet: &str,
+    ) -> String {
+        match stub.handle(packet, &mut || false).unwrap() {
+            Action::Reply(reply) | Action::Detach(reply) => reply,
+            Action::Kill => panic!("unexpected kill"),
+        }
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        // addiu $t0, $zero, 7
+        let mut ins = program(&[0x24080007]);
+        let mut stub = GdbStub::new(&mut ins);
+
+        assert_eq!(reply(&mut stub, "?"), "T05thread:1;");
+        assert_eq!(reply(&mut stub, "s"), "T05thread:1;");
+        assert_eq!(reply(&mut stub, "p8"), "00000007");
+        assert_eq!(reply(&mut stub, "p25"), "00000004");
+
+        let registers = reply(&mut stub, "g");
+        assert_eq!(registers.len(), 38 * 8);
+        assert_eq!(&registers[8 * 8..9 * 8], "00000007");
+
+        assert_eq!(reply(&mut stub, "P22=deadbeef"), "OK");
+        assert_eq!(reply(&mut stub, "P0=00000001"), "OK");
+        assert_eq!(stub.ins_state.state.hi, 0xdeadbeef);
+        assert_eq!(stub.ins_state.state.registers[0], 0);
+
+        stub.ins_state.state.next_pc = 0x200;
+        assert_eq!(reply(&mut stub, "P25=00000004"), "OK");
+        assert_eq!(stub.ins_state.state.next_pc, 0x200);
+        assert_eq!(reply(&mut stub, "P25=fffffffc"), "OK");
+        assert_eq!(stub.ins_state.state.next_pc, 0);
+        assert_eq!(reply(&mut stub, "P25=00000004"), "OK");
+
+        assert_eq!(reply(&mut stub, "m0,4"), "24080007");
+        assert_eq!(reply(&mut stub, "M102,3:aabbcc"), "OK");
+        assert_eq!(reply(&mut stub, "m100,6"), "0000aabbcc00");
+        assert_eq!(
+            stub.ins_state.state.memory.get_memory(0x100).unwrap(),
+            0xaabb
+        );
+        assert_eq!(reply(&mut stub, "mzz,4"), "E01");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut ins = program(&[
+            0x24080003, // addiu $t0, $zero, 3
+            0x2508FFFF, // addiu $t0, $t0, -1
+            0xAC080100, // sw $t0, 0x100($zero)
+            0x1500FFFD, // bne $t0, $zero, -3
+            0,          // nop
+            0x24021096, // addiu $v0, $zero, 4246 (exit_group)
+            0x24040009, // addiu $a0, $zero, 9
+            0x0000000C, // syscall
+        ]);
+        let mut stub = GdbStub::new(&mut ins);
+
+        assert_eq!(reply(&mut stub, "Z0,c,4"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05thread:1;");
+        assert_eq!(stub.ins_state.state.pc, 0xc);
+        assert_eq!(reply(&mut stub, "c"), "T05thread:1;");
+        assert_eq!(stub.ins_state.state.registers[8], 1);
+        assert_eq!(reply(&mut stub, "z0,c,4"), "OK");
+
+        assert_eq!(reply(&mut stub, "Z2,100,4"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05watch:00000100;thread:1;");
+        assert_eq!(stub.ins_state.state.pc, 0xc);
+        assert_eq!(reply(&mut stub, "z2,100,4"), "OK");
+
+        assert_eq!(reply(&mut stub, "c"), "W09");
+        assert_eq!(reply(&mut stub, "?"), "W09");
+    }
+}
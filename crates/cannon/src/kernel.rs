@@ -1,23 +1,29 @@
 //! This module contains the [Kernel] struct and its associated methods.
 
-use crate::{gz::compress_bytes, types::Proof, ChildWithFds};
+use crate::{gz::compress_bytes, types::Proof, ChildWithFds, GdbStub, Profiler};
 use anyhow::{anyhow, Result};
-use cannon_mipsevm::{InstrumentedState, PreimageOracle, StateWitnessHasher};
+use cannon_mipsevm::{InstrumentedState, PreimageOracle, SymbolTable, VmError};
 use std::{
     fs::File,
     io::{BufWriter, Write},
+    net::{TcpListener, ToSocketAddrs},
 };
 use tokio::{runtime::Runtime, task::JoinHandle};
 
 #[cfg(feature = "tracing")]
 use std::time::Instant;
 
+/// The interval, in steps, at which the preimage server process is checked for liveness.
+const SERVER_POLL_INTERVAL: u64 = 10_000_000;
+
 /// The [Kernel] struct contains the configuration for a Cannon kernel as well as
 /// the [PreimageOracle] and [InstrumentedState] instances that form it.
 #[allow(dead_code)]
 pub struct Kernel<O: Write, E: Write, P: PreimageOracle> {
     /// The instrumented state that the kernel will run.
     ins_state: InstrumentedState<O, E, P>,
+    /// The [SymbolTable] of the guest program, used to symbolize program counters in info logs.
+    symbols: Option<SymbolTable>,
     /// The server's process coupled with the preimage server's IO. We hold on to these so that they
     /// are not dropped until the kernel is dropped, preventing a broken pipe before the kernel is
     /// dropped. The other side of the bidirectional channel is owned by the [InstrumentedState],
@@ -40,6 +46,12 @@ pub struct Kernel<O: Write, E: Write, P: PreimageOracle> {
     stop_at: Option<String>,
     /// The pattern to print information at.
     info_at: Option<String>,
+    /// The [Profiler] that samples the guest, if profiling.
+    profiler: Option<Profiler>,
+    /// The path to write the folded stacks of the [Profiler] to.
+    profile: Option<String>,
+    /// The path to write the JSON [cannon_mipsevm::RunStats] to, or `-` for stdout.
+    stats: Option<String>,
 }
 
 impl<O, E, P> Kernel<O, E, P>
@@ -51,6 +63,7 @@ where
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         ins_state: InstrumentedState<O, E, P>,
+        symbols: Option<SymbolTable>,
         server_proc: Option<ChildWithFds>,
         input: String,
         output: Option<String>,
@@ -60,9 +73,13 @@ where
         snapshot_format: Option<String>,
         stop_at: Option<String>,
         info_at: Option<String>,
+        profiler: Option<Profiler>,
+        profile: Option<String>,
+        stats: Option<String>,
     ) -> Self {
         Self {
             ins_state,
+            symbols,
             server_proc,
             input,
             output,
@@ -72,6 +89,9 @@ where
             snapshot_format,
             stop_at,
             info_at,
+            profiler,
+            profile,
+            stats,
         }
     }
 
@@ -82,6 +102,10 @@ where
             let stop_at = create_matcher(self.stop_at.as_ref())?;
             let proof_at = create_matcher(self.proof_at.as_ref())?;
             let snapshot_at = create_matcher(self.snapshot_at.as_ref())?;
+            let profile_at = self
+                .profiler
+                .as_ref()
+                .map_or(Matcher::Never, |p| Matcher::MultipleOf(p.interval()));
 
             let proof_fmt = self.proof_format.unwrap_or("%d.json.gz".to_string());
             let snapshot_fmt = self.snapshot_format.unwrap_or("%d.json.gz".to_string());
@@ -94,6 +118,10 @@ where
             );
 
             let mut io_tasks: Vec<JoinHandle<Result<()>>> = Vec::default();
+            // Set when the run must be aborted, after the pending i/o tasks have finished.
+            let mut abort = None;
+            // Set when the cost budget is exhausted. The final state is still written.
+            let mut exhausted = None;
 
             while !self.ins_state.state.exited {
                 let step = self.ins_state.state.step;
@@ -101,20 +129,37 @@ where
                 #[cfg(feature = "tracing")]
                 if info_at.matches(step) {
                     let delta = start.elapsed();
+                    let instruction = self.ins_state.state.memory.peek_memory(self.ins_state.state.pc)?;
+                    let symbolize = |address| match &self.symbols {
+                        Some(symbols) => symbols.symbolize(address),
+                        None => address.to_string(),
+                    };
+                    let stack = self.ins_state.call_stack().map_or_else(String::new, |frames| {
+                        let frames = frames.iter().rev().map(|frame| symbolize(frame.call_site));
+                        format!(", stack: [{}]", frames.collect::<Vec<_>>().join(" <- "))
+                    });
                     crate::traces::info!(
                         target: "cannon::kernel",
-                        "[ELAPSED: {}.{:03}s] step: {}, pc: {}, instruction: {:08x}, ips: {}, pages: {}, mem: {}",
+                        "[ELAPSED: {}.{:03}s] step: {}, pc: {}, instruction: {}, ips: {}, pages: {}, mem: {}{}",
                         delta.as_secs(),
                         delta.subsec_millis(),
                         step,
-                        self.ins_state.state.pc,
-                        self.ins_state.state.memory.get_memory(self.ins_state.state.pc)?,
+                        symbolize(self.ins_state.state.pc),
+                        cannon_mipsevm::Instruction::decode(instruction)
+                            .map_or_else(|_| format!(".word 0x{:08x}", instruction), |i| i.to_string()),
                         (step - start_step) as f64 / delta.as_secs_f64(),
                         self.ins_state.state.memory.page_count(),
                         self.ins_state.state.memory.usage(),
+                        stack,
                     );
                 }
 
+                if profile_at.matches(step) {
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.sample(self.ins_state.state.pc, self.ins_state.call_stack());
+                    }
+                }
+
                 if stop_at.matches(step) {
                     crate::traces::info!(target: "cannon::kernel", "Stopping at step {}", step);
                     break;
@@ -122,9 +167,12 @@ where
 
                 if snapshot_at.matches(step) {
                     crate::traces::info!(target: "cannon::kernel", "Writing snapshot at step {}", step);
-                    let ser_state = serde_json::to_vec(&self.ins_state.state).unwrap();
+                    // Cloning the state shares its pages until the run writes to them, so the
+                    // snapshot is serialized off the main thread.
+                    let state = self.ins_state.state.clone();
                     let snap_path = snapshot_fmt.replace("%d", &format!("{}", step));
                     io_tasks.push(tokio::task::spawn(async move {
+                        let ser_state = serde_json::to_vec(&state)?;
                         let gz_state = compress_bytes(&ser_state)?;
                         let mut writer = BufWriter::new(File::create(snap_path)?);
                         writer.write_all(&gz_state)?;
@@ -137,12 +185,23 @@ where
                 if proof_at.matches(step) {
                     crate::traces::info!(target: "cannon::kernel", "Writing proof at step {}", step);
 
-                    let prestate_hash = self.ins_state.state.encode_witness()?.state_hash();
-                    let step_witness = self
-                        .ins_state
-                        .step(true)?
-                        .ok_or(anyhow!("No step witness"))?;
-                    let poststate_hash = self.ins_state.state.encode_witness()?.state_hash();
+                    // Rehash the dirty pages in parallel before the pre-state hash merkleizes
+                    // them on this thread.
+                    #[cfg(feature = "parallel")]
+                    self.ins_state.state.memory.par_merkle_root()?;
+                    let prestate_hash = self.ins_state.state.state_hash()?;
+                    let step_witness = match self.ins_state.step(true) {
+                        Err(e @ VmError::PageBudgetExceeded(_)) => {
+                            abort = Some(e);
+                            break;
+                        }
+                        Err(e @ VmError::CostBudgetExhausted(_)) => {
+                            exhausted = Some(e);
+                            break;
+                        }
+                        res => res?.ok_or(anyhow!("No step witness"))?,
+                    };
+                    let poststate_hash = self.ins_state.state.state_hash()?;
 
                     let proof_path = proof_fmt.replace("%d", &format!("{}", step));
                     io_tasks.push(tokio::task::spawn(async move {
@@ -152,8 +211,8 @@ where
                                 step,
                                 pre: prestate_hash,
                                 post: poststate_hash,
-                                state_data: step_witness.state,
                                 step_input: step_witness.encode_step_input().to_vec(),
+                                state_data: step_witness.state,
                                 proof_data: step_witness.mem_proof,
                                 oracle_input: preimage_input.map(|k| k.to_vec()),
                                 oracle_key: step_witness.preimage_key.map(|k| k.to_vec()),
@@ -171,13 +230,35 @@ where
                         Ok(())
                     }));
                 } else {
-                    self.ins_state.step(false)?;
+                    // No proof is needed until the next step that one of the patterns matches, so
+                    // run the basic block engine up to it. The preimage server is polled at
+                    // multiples of the poll interval, so those steps must be visited too.
+                    let next_poll = (step / SERVER_POLL_INTERVAL + 1) * SERVER_POLL_INTERVAL;
+                    let next = [&stop_at, &proof_at, &snapshot_at, &profile_at]
+                        .iter()
+                        .filter_map(|matcher| matcher.next_match(step + 1))
+                        .fold(next_poll, u64::min);
+                    #[cfg(feature = "tracing")]
+                    let next = info_at.next_match(step + 1).map_or(next, |n| n.min(next));
+
+                    match self.ins_state.run_blocks(next - step) {
+                        Ok(_) => {}
+                        Err(e @ VmError::PageBudgetExceeded(_)) => {
+                            abort = Some(e);
+                            break;
+                        }
+                        Err(e @ VmError::CostBudgetExhausted(_)) => {
+                            exhausted = Some(e);
+                            break;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
                 }
 
                 // Periodically check if the preimage server process has exited. If it has, then
                 // we should exit as well with a failure.
                 // TODO: This may be problematic.
-                if step % 10_000_000 == 0 {
+                if step % SERVER_POLL_INTERVAL == 0 {
                     if let Some(ref mut proc) = self.server_proc {
                         match proc.inner.try_wait() {
                             Ok(Some(status)) => {
@@ -193,6 +274,16 @@ where
                 }
             }
 
+            // The guest exhausted the memory page budget. Its state is not a valid final state,
+            // so finish the pending i/o and bail without writing it.
+            if let Some(e) = abort {
+                crate::traces::error!(target: "cannon::kernel", "Aborting at step {}: {}", self.ins_state.state.step, e);
+                for task in io_tasks {
+                    task.await??;
+                }
+                anyhow::bail!("Aborted at step {}: {}", self.ins_state.state.step, e);
+            }
+
             // Output the final state
             if let Some(output) = &self.output {
                 if !output.is_empty() {
@@ -208,6 +299,35 @@ where
                 println!("{:?}", &self.ins_state.state);
             }
 
+            if let Some(recorder) = self.ins_state.trace_recorder_mut() {
+                recorder.flush()?;
+            }
+
+            if let (Some(profiler), Some(profile)) = (&self.profiler, &self.profile) {
+                crate::traces::info!(target: "cannon::kernel", "Writing {} profile samples to {}", profiler.sample_count(), profile);
+                profiler.write_folded(self.symbols.as_ref(), BufWriter::new(File::create(profile)?))?;
+            }
+
+            if let (Some(run_stats), Some(stats)) = (self.ins_state.run_stats(), &self.stats) {
+                crate::traces::info!(
+                    target: "cannon::kernel",
+                    "Executed {} instructions and {} syscalls, read {} preimage bytes, wrote {} hint bytes",
+                    run_stats.instructions,
+                    run_stats.syscalls.values().sum::<u64>(),
+                    run_stats.preimage_bytes_read,
+                    run_stats.hint_bytes_written,
+                );
+                if stats == "-" {
+                    println!("{}", serde_json::to_string(&run_stats)?);
+                } else {
+                    serde_json::to_writer(BufWriter::new(File::create(stats)?), &run_stats)?;
+                }
+            }
+
+            if let Some(meter) = self.ins_state.cost_meter() {
+                crate::traces::info!(target: "cannon::kernel", "Used cost {} at step {}", meter.used, self.ins_state.state.step);
+            }
+
             crate::traces::info!(target: "cannon::kernel", "Kernel exiting...");
 
             // Wait for all of the i/o tasks to finish.
@@ -215,10 +335,35 @@ where
                 task.await??;
             }
 
+            if let Some(e) = exhausted {
+                crate::traces::error!(target: "cannon::kernel", "Stopped at step {}: {}", self.ins_state.state.step, e);
+                return Err(e.into());
+            }
+
             // File descriptors are closed when the kernel struct is dropped, since it owns all open IO.
             Ok(())
         })
     }
+
+    /// Serves the [InstrumentedState] to a single GDB remote serial protocol session, instead of
+    /// running it. The state is not written back when the session ends.
+    ///
+    /// ### Takes
+    /// - `address`: The address to listen for the debugger on, e.g. `127.0.0.1:1234`.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the session ended successfully.
+    pub fn debug(mut self, address: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(address)?;
+        crate::traces::info!(target: "cannon::kernel", "Waiting for GDB on {}", listener.local_addr()?);
+
+        let (stream, _peer) = listener.accept()?;
+        crate::traces::info!(target: "cannon::kernel", "GDB connected from {}", _peer);
+
+        GdbStub::new(&mut self.ins_state).serve(stream)?;
+        crate::traces::info!(target: "cannon::kernel", "GDB session ended at step {}", self.ins_state.state.step);
+        Ok(())
+    }
 }
 
 enum Matcher {
@@ -238,6 +383,17 @@ impl Matcher {
             Matcher::MultipleOf(steps) => value % steps == 0,
         }
     }
+
+    /// Returns the first value at or after `value` that the [Matcher] matches, if any.
+    #[inline(always)]
+    fn next_match(&self, value: u64) -> Option<u64> {
+        match self {
+            Matcher::Never => None,
+            Matcher::Always => Some(value),
+            Matcher::Equal(step) => (*step >= value).then_some(*step),
+            Matcher::MultipleOf(steps) => value.div_ceil(*steps).checked_mul(*steps),
+        }
+    }
 }
 
 fn create_matcher(pattern: Option<&String>) -> Result<Matcher> {
@@ -268,3 +424,19 @@ fn create_matcher(pattern: Option<&String>) -> Result<Matcher> {
         },
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Matcher;
+    use proptest::proptest;
+
+    proptest! {
+        #[test]
+        fn test_next_match(value in 0u64..1_000_000, n in 1u64..1_000) {
+            for matcher in [Matcher::Never, Matcher::Always, Matcher::Equal(n), Matcher::MultipleOf(n)] {
+                let expected = (value..value + 2 * n + 1).find(|v| matcher.matches(*v));
+                assert_eq!(matcher.next_match(value), expected);
+            }
+        }
+    }
+}
@@ -6,12 +6,21 @@ pub use builder::KernelBuilder;
 pub mod gz;
 pub use gz::{compress_bytes, decompress_bytes};
 
+mod gdb;
+pub use gdb::GdbStub;
+
 mod kernel;
 pub use kernel::Kernel;
 
+mod profiler;
+pub use profiler::Profiler;
+
 mod proc_oracle;
 pub use proc_oracle::ProcessPreimageOracle;
 
+mod trace;
+pub use trace::TraceReader;
+
 mod types;
 pub use types::{ChildWithFds, Proof};
 
@@ -0,0 +1,139 @@
+//! This module contains the [Profiler], a sampling profiler for the guest program.
+
+use cannon_mipsevm::{Address, Frame, SymbolTable};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+/// The [Profiler] samples the guest's program counter, and its call stack if it is tracked, at a
+/// fixed step interval. The samples are written as folded stacks, the input format of
+/// `flamegraph.pl` and `inferno-flamegraph`.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// The number of steps between samples.
+    interval: u64,
+    /// The number of times every sampled stack was seen, outermost address first.
+    samples: HashMap<Vec<Address>, u64>,
+}
+
+impl Profiler {
+    /// Creates a new [Profiler].
+    ///
+    /// ### Takes
+    /// - `interval`: The number of steps between samples. Must be nonzero.
+    ///
+    /// ### Returns
+    /// - The new [Profiler].
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval: interval.max(1),
+            samples: HashMap::default(),
+        }
+    }
+
+    /// Returns the number of steps between samples.
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Returns the total number of samples taken.
+    pub fn sample_count(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    /// Takes a sample.
+    ///
+    /// ### Takes
+    /// - `pc`: The guest's program counter.
+    /// - `frames`: The guest's call stack, innermost last, if it is tracked.
+    pub fn sample(&mut self, pc: Address, frames: Option<&[Frame]>) {
+        let mut stack = frames.map_or_else(Vec::new, |frames| {
+            frames.iter().map(|frame| frame.call_site).collect()
+        });
+        stack.push(pc);
+        *self.samples.entry(stack).or_default() += 1;
+    }
+
+    /// Writes the samples as folded stacks, one `outer;...;inner count` line per distinct
+    /// stack. Addresses are resolved to the function containing them, or printed in hex if the
+    /// [SymbolTable] is not given or has no function containing them.
+    ///
+    /// ### Takes
+    /// - `symbols`: The [SymbolTable] of the guest program, if any.
+    /// - `writer`: The writer to write the folded stacks to.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the folded stacks were written successfully.
+    pub fn write_folded(
+        &self,
+        symbols: Option<&SymbolTable>,
+        mut writer: impl Write,
+    ) -> io::Result<()> {
+        let resolve = |address: &Address| {
+            symbols
+                .and_then(|symbols| symbols.lookup(*address))
+                .map_or_else(|| format!("0x{:08x}", address), |s| s.name.clone())
+        };
+
+        // Stacks that resolve to the same functions are merged.
+        let mut folded = BTreeMap::<String, u64>::new();
+        for (stack, count) in &self.samples {
+            let stack = stack.iter().map(resolve).collect::<Vec<_>>().join(";");
+            *folded.entry(stack).or_default() += count;
+        }
+        for (stack, count) in folded {
+            writeln!(writer, "{} {}", stack, count)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Profiler;
+    use cannon_mipsevm::{Frame, Symbol, SymbolTable};
+
+    #[test]
+    fn folded_stacks() {
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "main".to_string(),
+                start: 0x100,
+                size: 0x100,
+            },
+            Symbol {
+                name: "hash".to_string(),
+                start: 0x200,
+                size: 0x100,
+            },
+        ]);
+        let frame = Frame {
+            call_site: 0x110,
+            target: 0x200,
+            return_address: 0x118,
+        };
+
+        let mut profiler = Profiler::new(10);
+        profiler.sample(0x120, Some(&[]));
+        profiler.sample(0x204, Some(&[frame]));
+        profiler.sample(0x208, Some(&[frame]));
+        profiler.sample(0x400, Some(&[frame]));
+        assert_eq!(profiler.sample_count(), 4);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(Some(&symbols), &mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main 1\nmain;0x00000400 1\nmain;hash 2\n"
+        );
+
+        let mut folded = Vec::new();
+        profiler.write_folded(None, &mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "0x00000110;0x00000204 1\n0x00000110;0x00000208 1\n0x00000110;0x00000400 1\n\
+             0x00000120 1\n"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! This module contains the [TraceReader], a streaming reader for the binary execution traces
+//! written by the [cannon_mipsevm::TraceRecorder].
+
+use anyhow::{anyhow, bail, Result};
+use cannon_mipsevm::{SyscallRecord, TraceRecord, TRACE_MAGIC, TRACE_VERSION};
+use std::io::{BufRead, BufReader, Read};
+
+/// The [TraceReader] reads the [TraceRecord]s of a trace one at a time, without loading the whole
+/// trace into memory.
+pub struct TraceReader<R: Read> {
+    /// The buffered trace input.
+    reader: BufReader<R>,
+    /// The version of the trace format.
+    version: u16,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Creates a new [TraceReader], reading and validating the trace header.
+    ///
+    /// ### Takes
+    /// - `reader`: The reader to read the trace from.
+    ///
+    /// ### Returns
+    /// - The new [TraceReader], or an error if the header is invalid or of an unsupported
+    ///   version.
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != TRACE_MAGIC {
+            bail!("Invalid trace magic: {:02x?}", magic);
+        }
+        let version = u16::from_be_bytes(read_array(&mut reader)?);
+        if version != TRACE_VERSION {
+            bail!("Unsupported trace version {}", version);
+        }
+
+        Ok(Self { reader, version })
+    }
+
+    /// Returns the version of the trace format.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Reads the next [TraceRecord].
+    ///
+    /// ### Returns
+    /// - `Ok(Some(record))`: The next [TraceRecord].
+    /// - `Ok(None)`: The end of the trace was reached.
+    /// - `Err(_)`: The trace could not be read, or ends within a record.
+    pub fn next_record(&mut self) -> Result<Option<TraceRecord>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        self.read_record()
+            .map(Some)
+            .map_err(|e| anyhow!("Truncated or corrupt trace record: {}", e))
+    }
+
+    /// Reads a [TraceRecord] that is known to start at the current position.
+    fn read_record(&mut self) -> Result<TraceRecord> {
+        let reader = &mut self.reader;
+        let step = u64::from_be_bytes(read_array(reader)?);
+        let pc = u32::from_be_bytes(read_array(reader)?);
+        let instruction = u32::from_be_bytes(read_array(reader)?);
+        let [flags, register_count] = read_array(reader)?;
+        let memory_count = u32::from_be_bytes(read_array(reader)?);
+
+        let register_writes = (0..register_count)
+            .map(|_| {
+                let [register] = read_array(reader)?;
+                Ok((register, u32::from_be_bytes(read_array(reader)?)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let memory_writes = (0..memory_count)
+            .map(|_| {
+                Ok((
+                    u32::from_be_bytes(read_array(reader)?),
+                    u32::from_be_bytes(read_array(reader)?),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let syscall = if flags & 1 != 0 {
+            Some(SyscallRecord {
+                number: u32::from_be_bytes(read_array(reader)?),
+                v0: u32::from_be_bytes(read_array(reader)?),
+                v1: u32::from_be_bytes(read_array(reader)?),
+            })
+        } else {
+            None
+        };
+
+        Ok(TraceRecord {
+            step,
+            pc,
+            instruction,
+            register_writes,
+            memory_writes,
+            syscall,
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Reads a fixed size array from the given reader.
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::TraceReader;
+    use cannon_mipsevm::{
+        test_utils::{load_program, SharedBuffer, StaticOracle},
+        InstrumentedState, TraceRecord, TraceRecorder, TRACE_MAGIC, TRACE_REGISTER_HI,
+        TRACE_REGISTER_LO, TRACE_VERSION,
+    };
+    use std::io;
+
+    #[test]
+    fn read_trace() {
+        let new_vm = || {
+            let elf_bytes = include_bytes!("../../../example/bin/hello.elf");
+            InstrumentedState::new(
+                load_program(elf_bytes),
+                StaticOracle::new(Vec::default()),
+                io::sink(),
+                io::sink(),
+            )
+        };
+
+        let buffer = SharedBuffer::default();
+        let mut traced = new_vm().with_trace_recorder(TraceRecorder::new(buffer.clone()).unwrap());
+        traced.run(u64::MAX).unwrap();
+        let steps = traced.state.step;
+        drop(traced);
+
+        // Replaying the recorded register and memory writes reproduces the execution.
+        let trace = buffer.0.lock().unwrap().clone();
+        let mut reference = new_vm();
+        let mut reader = TraceReader::new(trace.as_slice()).unwrap();
+        let mut syscalls = 0;
+        for (step, record) in (&mut reader).enumerate() {
+            let record = record.unwrap();
+            assert_eq!(record.step, step as u64);
+            assert_eq!(record.pc, reference.state.pc);
+
+            let mut registers = reference.state.registers;
+            let (mut lo, mut hi) = (reference.state.lo, reference.state.hi);
+            for (register, value) in &record.register_writes {
+                match *register {
+                    TRACE_REGISTER_LO => lo = *value,
+                    TRACE_REGISTER_HI => hi = *value,
+                    register => registers[register as usize] = *value,
+                }
+            }
+            syscalls += record.syscall.is_some() as u64;
+
+            reference.step(false).unwrap();
+            assert_eq!(registers, reference.state.registers);
+            assert_eq!((lo, hi), (reference.state.lo, reference.state.hi));
+            for (address, value) in &record.memory_writes {
+                assert_eq!(reference.state.memory.get_memory(*address).unwrap(), *value);
+            }
+        }
+        assert_eq!(reference.state.step, steps);
+        assert!(reference.state.exited);
+        assert!(syscalls > 0);
+
+        // Truncated records and invalid headers are errors.
+        let mut truncated = TraceReader::new(&trace[..trace.len() - 1]).unwrap();
+        assert!(truncated.by_ref().last().unwrap().is_err());
+        assert!(TraceReader::new(&b"CNTR\x00\x02"[..]).is_err());
+        assert!(TraceReader::new(&b"JSON\x00\x01"[..]).is_err());
+
+        // Syscall handlers may write more words than fit in a byte.
+        let record = TraceRecord {
+            step: 1,
+            memory_writes: (0..300).map(|i| (i * 4, i)).collect(),
+            ..Default::default()
+        };
+        let mut encoded = [&TRACE_MAGIC[..], &TRACE_VERSION.to_be_bytes()].concat();
+        record.encode(&mut encoded).unwrap();
+        let mut reader = TraceReader::new(encoded.as_slice()).unwrap();
+        assert_eq!(reader.next_record().unwrap(), Some(record));
+        assert!(reader.next_record().unwrap().is_none());
+    }
+}
@@ -1,6 +1,5 @@
 //! This module contains the types for the `cannon` interface.
 
-use cannon_mipsevm::StateWitness;
 use preimage_oracle::ReadWritePair;
 use serde::{Deserialize, Serialize};
 use std::process::Child;
@@ -12,8 +11,8 @@ pub struct Proof {
     pub step: u64,
     pub pre: [u8; 32],
     pub post: [u8; 32],
-    #[serde(with = "cannon_mipsevm::ser::state_witness_hex")]
-    pub state_data: StateWitness,
+    #[serde(with = "cannon_mipsevm::ser::vec_u8_hex")]
+    pub state_data: Vec<u8>,
     pub proof_data: Vec<u8>,
     pub step_input: Vec<u8>,
     pub oracle_key: Option<Vec<u8>>,
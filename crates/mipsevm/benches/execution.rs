@@ -30,6 +30,19 @@ fn bench_exec(
     })
 }
 
+#[inline(always)]
+fn bench_run(elf_bytes: &[u8], oracle: impl PreimageOracle, b: &mut Bencher) {
+    let mut state = load_elf(elf_bytes).unwrap();
+    patch_go(elf_bytes, &mut state).unwrap();
+    patch_stack(&mut state).unwrap();
+
+    let out = BufWriter::new(Vec::default());
+    let err = BufWriter::new(Vec::default());
+    let mut ins = InstrumentedState::new(state, oracle, out, err);
+
+    b.iter(|| ins.run(u64::MAX).unwrap())
+}
+
 fn execution(c: &mut Criterion) {
     let mut g = c.benchmark_group("execution");
     g.sample_size(10);
@@ -44,6 +57,11 @@ fn execution(c: &mut Criterion) {
         bench_exec(elf_bytes, StaticOracle::default(), true, b);
     });
 
+    g.bench_function("[Run] Execution (hello.elf)", |b| {
+        let elf_bytes = include_bytes!("../../../example/bin/hello.elf");
+        bench_run(elf_bytes, StaticOracle::default(), b);
+    });
+
     g.bench_function("[No Witness] Execution (claim.elf)", |b| {
         let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
         bench_exec(elf_bytes, ClaimTestOracle::default(), false, b);
@@ -53,6 +71,11 @@ fn execution(c: &mut Criterion) {
         let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
         bench_exec(elf_bytes, ClaimTestOracle::default(), true, b);
     });
+
+    g.bench_function("[Run] Execution (claim.elf)", |b| {
+        let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
+        bench_run(elf_bytes, ClaimTestOracle::default(), b);
+    });
 }
 
 criterion_group! {
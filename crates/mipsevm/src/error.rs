@@ -0,0 +1,187 @@
+//! This module contains the [VmError] type returned by the MIPS emulator.
+
+use crate::Address;
+use std::fmt::{self, Display};
+
+/// A [VmError] is returned by [crate::InstrumentedState::step] when an instruction step could not
+/// be performed.
+///
+/// A [VmError::Fault] is caused by the guest program itself, and would also fail to execute in
+/// `MIPS.sol`. All other variants are failures of the host, and say nothing about the validity of
+/// the guest program's execution.
+#[derive(Debug)]
+pub enum VmError {
+    /// The guest program faulted.
+    Fault {
+        /// The step at which the faulting instruction was executed.
+        step: u64,
+        /// The program counter of the faulting instruction.
+        pc: Address,
+        /// The faulting instruction word.
+        instruction: u32,
+        /// The kind of fault that occurred.
+        kind: FaultKind,
+    },
+    /// The preimage oracle failed to accept a hint or to serve a preimage.
+    Oracle(anyhow::Error),
+    /// A host I/O operation failed.
+    Io(std::io::Error),
+    /// The [crate::Memory] failed to perform an operation, e.g. while generating a merkle proof.
+    Memory(anyhow::Error),
+    /// A [crate::SyscallHandler] failed to handle a syscall.
+    Syscall(anyhow::Error),
+    /// The [crate::Memory] page budget was exhausted.
+    PageBudgetExceeded(PageBudgetExceeded),
+    /// The [crate::CostMeter] budget was exhausted. The [crate::State] is left as it was after
+    /// the last executed instruction, so execution can be resumed with a larger budget.
+    CostBudgetExhausted(CostBudgetExhausted),
+}
+
+/// A [PageBudgetExceeded] error is returned by [crate::Memory::alloc_page] when allocating a page
+/// would exceed the [crate::Memory]'s page budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageBudgetExceeded {
+    /// The maximum number of pages that may be allocated.
+    pub budget: usize,
+}
+
+/// A [CostBudgetExhausted] error is returned by [crate::InstrumentedState::step] when the
+/// [crate::CostMeter]'s budget is used up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostBudgetExhausted {
+    /// The budget of the [crate::CostMeter].
+    pub budget: u64,
+    /// The cost used when execution stopped.
+    pub used: u64,
+}
+
+/// The kind of a [VmError::Fault] raised by the guest program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// The instruction's opcode is not supported.
+    InvalidOpcode(u32),
+    /// The instruction's function code is not supported.
+    InvalidFunction(u32),
+    /// Memory was accessed at an address that is not aligned to 4 bytes.
+    UnalignedAccess(Address),
+    /// A branch instruction was found in a branch delay slot.
+    BranchInDelaySlot,
+    /// A jump instruction was found in a branch delay slot.
+    JumpInDelaySlot,
+    /// A register index out of range was written to.
+    InvalidRegister(u32),
+    /// The instruction accessed more than one memory address, which cannot be proven in a
+    /// single step.
+    UnexpectedMemoryAccess {
+        /// The address of the second access.
+        address: Address,
+        /// The address of the access that is already buffered for the step's proof.
+        buffered: Address,
+    },
+    /// A `div` or `divu` instruction divided by zero.
+    DivisionByZero,
+    /// The preimage was read at an offset beyond its length.
+    PreimageOffsetOutOfBounds(u32),
+    /// An unknown syscall was made while strict syscalls are enabled.
+    UnknownSyscall(u32),
+}
+
+impl VmError {
+    /// Returns `true` if the error is a [VmError::Fault] raised by the guest program.
+    pub fn is_fault(&self) -> bool {
+        matches!(self, Self::Fault { .. })
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fault {
+                step,
+                pc,
+                instruction,
+                kind,
+            } => write!(
+                f,
+                "VM fault at step {} (pc: {:08x}, instruction: {:08x}): {}",
+                step, pc, instruction, kind
+            ),
+            Self::Oracle(e) => write!(f, "Preimage oracle error: {}", e),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::Memory(e) => write!(f, "Memory error: {}", e),
+            Self::Syscall(e) => write!(f, "Syscall handler error: {}", e),
+            Self::PageBudgetExceeded(e) => write!(f, "{}", e),
+            Self::CostBudgetExhausted(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOpcode(opcode) => write!(f, "Invalid opcode {:x}", opcode),
+            Self::InvalidFunction(fun) => write!(f, "Invalid function code {:x}", fun),
+            Self::UnalignedAccess(address) => write!(f, "Unaligned memory access: {:x}", address),
+            Self::BranchInDelaySlot => write!(f, "Unexpected branch in delay slot"),
+            Self::JumpInDelaySlot => write!(f, "Unexpected jump in delay slot"),
+            Self::InvalidRegister(index) => write!(f, "Invalid register index {}", index),
+            Self::UnexpectedMemoryAccess { address, buffered } => write!(
+                f,
+                "Unexpected different memory access at {:x}, already have access at {:x} buffered",
+                address, buffered
+            ),
+            Self::DivisionByZero => write!(f, "Division by zero"),
+            Self::PreimageOffsetOutOfBounds(offset) => {
+                write!(f, "Preimage read at out of bounds offset {}", offset)
+            }
+            Self::UnknownSyscall(syscall) => write!(f, "Unknown syscall {}", syscall),
+        }
+    }
+}
+
+impl Display for PageBudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Memory page budget of {} pages exceeded", self.budget)
+    }
+}
+
+impl std::error::Error for PageBudgetExceeded {}
+
+impl Display for CostBudgetExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cost budget of {} exhausted, used {}",
+            self.budget, self.used
+        )
+    }
+}
+
+impl std::error::Error for CostBudgetExhausted {}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Fault { .. } | Self::PageBudgetExceeded(_) | Self::CostBudgetExhausted(_) => None,
+            Self::Oracle(e) | Self::Memory(e) | Self::Syscall(e) => Some(e.as_ref()),
+            Self::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for VmError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<anyhow::Error> for VmError {
+    /// Errors returned by the [crate::Memory] are [anyhow::Error]s. Oracle errors must be mapped
+    /// to [VmError::Oracle] explicitly.
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<PageBudgetExceeded>() {
+            Ok(e) => Self::PageBudgetExceeded(e),
+            Err(e) => Self::Memory(e),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! This module contains the [InstructionCache], a per-page cache of decoded instructions that
+//! lets the MIPS emulator skip the page lookup and field extraction of each instruction fetch.
+
+use crate::{
+    mips::sign_extend,
+    page::{PAGE_ADDRESS_MASK, PAGE_SIZE},
+    Address, Page, PageIndex,
+};
+use rustc_hash::FxHashMap;
+use std::{
+    fmt::{self, Debug},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of instructions within a [Page].
+pub(crate) const PAGE_SIZE_INSTRUCTIONS: usize = PAGE_SIZE / 4;
+
+/// A [Page] worth of [DecodedInstruction]s.
+pub(crate) type DecodedPage = [DecodedInstruction; PAGE_SIZE_INSTRUCTIONS];
+
+/// The id of the next [InstructionCache] to be created.
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A [DecodedInstruction] is an instruction word with the fields that the MIPS emulator needs to
+/// dispatch it already extracted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DecodedInstruction {
+    /// The raw instruction word.
+    pub(crate) word: u32,
+    /// The immediate operand of I-type instructions. Zero extended for `andi`, `ori` and `xori`,
+    /// and sign extended otherwise.
+    pub(crate) imm: u32,
+    /// The opcode of the instruction.
+    pub(crate) opcode: u8,
+    /// The function code of R-type instructions.
+    pub(crate) fun: u8,
+    /// The index of the `rs` register.
+    pub(crate) rs: u8,
+    /// The index of the `rt` register.
+    pub(crate) rt: u8,
+    /// The index of the `rd` register.
+    pub(crate) rd: u8,
+}
+
+impl DecodedInstruction {
+    /// Decodes an instruction word. Decoding never fails; invalid instructions fault when they
+    /// are executed.
+    ///
+    /// ### Takes
+    /// - `word`: The instruction word.
+    ///
+    /// ### Returns
+    /// - The [DecodedInstruction].
+    #[inline(always)]
+    pub(crate) fn new(word: u32) -> Self {
+        let opcode = word >> 26;
+        let imm = if (0x0c..=0x0e).contains(&opcode) {
+            word & 0xFFFF
+        } else {
+            sign_extend(word & 0xFFFF, 16)
+        };
+
+        Self {
+            word,
+            imm,
+            opcode: opcode as u8,
+            fun: (word & 0x3F) as u8,
+            rs: ((word >> 21) & 0x1F) as u8,
+            rt: ((word >> 16) & 0x1F) as u8,
+            rd: ((word >> 11) & 0x1F) as u8,
+        }
+    }
+}
+
+/// The [InstructionCache] holds the [DecodedPage]s of the pages that instructions were fetched
+/// from. It is owned by the [crate::Memory], which keeps it coherent with writes.
+///
+/// The cache is not part of the machine state: clones start out empty, and it is ignored when
+/// comparing [crate::Memory].
+pub(crate) struct InstructionCache {
+    /// The most recently used page, checked before the map.
+    last: Option<(PageIndex, Box<DecodedPage>)>,
+    /// All other decoded pages.
+    pages: FxHashMap<PageIndex, Box<DecodedPage>>,
+    /// Unique to each cache, including clones, so that code derived from the cache of one
+    /// [crate::Memory] is never mistaken for code derived from another.
+    pub(crate) id: u64,
+    /// Incremented whenever a decoded instruction changes or a decoded page is evicted, so that
+    /// code derived from the cache can tell when it is stale.
+    pub(crate) generation: u64,
+}
+
+impl Default for InstructionCache {
+    fn default() -> Self {
+        Self {
+            last: None,
+            pages: FxHashMap::default(),
+            id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+        }
+    }
+}
+
+impl InstructionCache {
+    /// Returns the number of decoded pages in the cache.
+    pub(crate) fn len(&self) -> usize {
+        self.pages.len() + self.last.is_some() as usize
+    }
+
+    /// Looks up the [DecodedInstruction] at the given [Address].
+    ///
+    /// ### Takes
+    /// - `address`: The aligned [Address] of the instruction.
+    ///
+    /// ### Returns
+    /// - The [DecodedInstruction], or `None` if its page is not decoded.
+    #[inline(always)]
+    pub(crate) fn get(&mut self, address: Address) -> Option<DecodedInstruction> {
+        let page_index = address as PageIndex >> crate::page::PAGE_ADDRESS_SIZE;
+        let index = (address as usize & PAGE_ADDRESS_MASK) >> 2;
+
+        if let Some((last_index, page)) = &self.last {
+            if *last_index == page_index {
+                return Some(page[index]);
+            }
+        }
+
+        let page = self.pages.remove(&page_index)?;
+        let instruction = page[index];
+        self.promote(page_index, page);
+        Some(instruction)
+    }
+
+    /// Decodes a [Page] and inserts it into the cache.
+    ///
+    /// ### Takes
+    /// - `page_index`: The index of the page.
+    /// - `data`: The data of the page.
+    ///
+    /// ### Returns
+    /// - The [DecodedPage].
+    pub(crate) fn insert(&mut self, page_index: PageIndex, data: &Page) -> &DecodedPage {
+        let mut page = Box::new([DecodedInstruction::default(); PAGE_SIZE_INSTRUCTIONS]);
+        for (instruction, word) in page.iter_mut().zip(data.chunks_exact(4)) {
+            *instruction = DecodedInstruction::new(u32::from_be_bytes(
+                word.try_into().expect("chunk is 4 bytes"),
+            ));
+        }
+        self.promote(page_index, page);
+        &self.last.as_ref().expect("page was just promoted").1
+    }
+
+    /// Re-decodes a single word of a cached page after it has been written.
+    ///
+    /// ### Takes
+    /// - `address`: The aligned [Address] of the word.
+    /// - `word`: The new value of the word.
+    #[inline(always)]
+    pub(crate) fn update(&mut self, address: Address, word: u32) {
+        let page_index = address as PageIndex >> crate::page::PAGE_ADDRESS_SIZE;
+        let index = (address as usize & PAGE_ADDRESS_MASK) >> 2;
+
+        let page = match &mut self.last {
+            Some((last_index, page)) if *last_index == page_index => Some(page),
+            _ => self.pages.get_mut(&page_index),
+        };
+        if let Some(page) = page {
+            page[index] = DecodedInstruction::new(word);
+            self.generation += 1;
+        }
+    }
+
+    /// Evicts a page from the cache.
+    ///
+    /// ### Takes
+    /// - `page_index`: The index of the page to evict.
+    pub(crate) fn invalidate(&mut self, page_index: PageIndex) {
+        if matches!(&self.last, Some((last_index, _)) if *last_index == page_index) {
+            self.last = None;
+            self.generation += 1;
+        } else if self.pages.remove(&page_index).is_some() {
+            self.generation += 1;
+        }
+    }
+
+    /// Makes the given page the most recently used page.
+    fn promote(&mut self, page_index: PageIndex, page: Box<DecodedPage>) {
+        if let Some((last_index, last)) = self.last.replace((page_index, page)) {
+            self.pages.insert(last_index, last);
+        }
+    }
+}
+
+impl Clone for InstructionCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl PartialEq for InstructionCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for InstructionCache {}
+
+impl Debug for InstructionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstructionCache")
+            .field("pages", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DecodedInstruction, InstructionCache};
+    use crate::Memory;
+
+    #[test]
+    fn decode() {
+        // addiu $sp, $sp, -32
+        let insn = DecodedInstruction::new(0x27BDFFE0);
+        assert_eq!((insn.opcode, insn.rs, insn.rt), (0x09, 29, 29));
+        assert_eq!(insn.imm, 0xFFFFFFE0);
+
+        // ori $t0, $t0, 0x8000
+        let insn = DecodedInstruction::new(0x35088000);
+        assert_eq!(insn.imm, 0x8000);
+
+        // addu $v0, $a0, $a1
+        let insn = DecodedInstruction::new(0x00851021);
+        assert_eq!((insn.rs, insn.rt, insn.rd, insn.fun), (4, 5, 2, 0x21));
+    }
+
+    #[test]
+    fn cache_coherence() {
+        let mut memory = Memory::default();
+        memory.set_memory(0x1000, 0x24090001).unwrap();
+        memory.set_memory(0x2000, 0x24090002).unwrap();
+
+        assert_eq!(memory.fetch_instruction(0x1000).unwrap().word, 0x24090001);
+        assert_eq!(memory.fetch_instruction(0x2000).unwrap().word, 0x24090002);
+        // Unmapped pages read as zero and are not cached.
+        assert_eq!(memory.fetch_instruction(0x3000).unwrap().word, 0);
+        assert_eq!(memory.instruction_cache.len(), 2);
+
+        // Writes to cached pages are visible to subsequent fetches, whether or not the page is
+        // the most recently used one.
+        memory.set_memory(0x1000, 0x24090003).unwrap();
+        memory.set_memory(0x2000, 0x24090004).unwrap();
+        assert_eq!(memory.fetch_instruction(0x1000).unwrap().word, 0x24090003);
+        assert_eq!(memory.fetch_instruction(0x2000).unwrap().word, 0x24090004);
+
+        memory
+            .set_memory_range(0x1000, [0x24, 0x09, 0x00, 0x05].as_slice())
+            .unwrap();
+        assert_eq!(memory.fetch_instruction(0x1000).unwrap().word, 0x24090005);
+
+        // The cache is not part of the memory's identity.
+        let mut clone = memory.clone();
+        assert_eq!(clone.instruction_cache.len(), 0);
+        assert_eq!(clone, memory);
+
+        // Clones copy shared pages on write, so writes through a clone leave the decoded
+        // instructions of the original intact.
+        clone.set_memory(0x1000, 0x24090006).unwrap();
+        assert_eq!(memory.fetch_instruction(0x1000).unwrap().word, 0x24090005);
+        assert_eq!(clone.fetch_instruction(0x1000).unwrap().word, 0x24090006);
+
+        let mut cache = InstructionCache::default();
+        cache.insert(1, &[0; crate::page::PAGE_SIZE]);
+        cache.invalidate(1);
+        assert_eq!(cache.len(), 0);
+    }
+}
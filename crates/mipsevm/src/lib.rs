@@ -5,27 +5,69 @@
 pub(crate) mod traces;
 
 mod memory;
-pub use self::memory::Memory;
+pub use self::memory::{
+    verify_merkle_proof, verify_merkle_proofs, verify_multi_proof, Memory, MEMORY_PROOF_SIZE,
+};
 
 mod page;
 pub use self::page::CachedPage;
 
+mod icache;
+
+mod meter;
+pub use meter::{CostMeter, CostTable, InstructionClass};
+
+mod recorder;
+pub use recorder::{
+    SyscallRecord, TraceRecord, TraceRecorder, TRACE_MAGIC, TRACE_REGISTER_HI, TRACE_REGISTER_LO,
+    TRACE_VERSION,
+};
+
+mod store;
+pub use store::{FilePageStore, PageStore};
+
+mod stats;
+pub use stats::{PageSample, RunStats, PAGE_SAMPLE_INTERVAL};
+
+mod symbols;
+pub use symbols::{CallStack, Frame, Symbol, SymbolTable, MAX_CALL_DEPTH};
+
 mod state;
 pub use self::state::State;
 
+mod threads;
+pub use self::threads::{
+    ThreadState, Threads, FUTEX_EMPTY_ADDR, FUTEX_NO_TIMEOUT, FUTEX_TIMEOUT_STEPS, SCHED_QUANTUM,
+    STEPS_PER_SECOND, VALID_CLONE_FLAGS,
+};
+
 mod traits;
-pub use self::traits::{PreimageOracle, StateWitnessHasher};
+pub use self::traits::{PreimageOracle, StateWitnessHasher, SyscallHandler};
+
+mod error;
+pub use error::{CostBudgetExhausted, FaultKind, PageBudgetExceeded, VmError};
 
 mod witness;
-pub use witness::{StepWitness, STATE_WITNESS_SIZE};
+pub use witness::{
+    StepWitness, MT_STATE_WITNESS_SIZE, STATE_WITNESS_SIZE, THREAD_PROOF_SIZE, THREAD_WITNESS_SIZE,
+};
+
+mod verifier;
+pub use verifier::verify_step;
 
 mod utils;
 
 mod types;
-pub use types::{Address, Fd, Gindex, Page, PageIndex, StateWitness, VMStatus};
+pub use types::{
+    Address, Fd, Gindex, MtStateWitness, Page, PageIndex, StateWitness, ThreadWitness, VMStatus,
+};
 
 mod mips;
-pub use mips::InstrumentedState;
+pub use mips::{
+    Breakpoint, Comparison, ITypeOp, Instruction, InstrumentedState, JTypeOp, RTypeOp,
+    RegisterCondition, StepDelta, Stop, StopReason, SyscallContext, ThreadsDelta, WatchKind,
+    Watchpoint, WatchpointHit, REGISTER_NAMES,
+};
 
 mod patch;
 pub use patch::{load_elf, patch_go, patch_stack, MultiReader};
@@ -1,55 +1,136 @@
 //! The memory module contains the [Memory] data structure and its functionality for the emulator.
 
 use crate::{
-    page::{self},
+    icache::{DecodedInstruction, InstructionCache},
+    mips::{WatchKind, Watchpoints},
+    page::{self, CachedPage},
+    store::{PageStore, Paging, Slot, StoredPage},
     types::SharedCachedPage,
     utils::keccak_concat_hashes,
-    Address, Gindex, Page, PageIndex,
+    Address, Gindex, Page, PageBudgetExceeded, PageIndex,
 };
 use anyhow::Result;
 use rustc_hash::FxHashMap;
 use serde::{Deserialize, Serialize};
-use std::{io::Read, rc::Rc};
+use std::{io::Read, sync::Arc};
+
+/// The size of a merkle proof of a word in the [Memory]: the 32 byte leaf containing the word,
+/// followed by its 27 sibling hashes from the bottom of the tree up.
+pub const MEMORY_PROOF_SIZE: usize = 28 * 32;
 
 /// The [Memory] struct represents the MIPS emulator's memory.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Memory {
     /// Map of generalized index -> the merkle root of each index. None if invalidated.
     pub nodes: FxHashMap<Gindex, Option<[u8; 32]>>,
-    /// Map of page indices to [CachedPage]s.
+    /// Map of page indices to [CachedPage]s. Pages are shared between clones of the [Memory]
+    /// until they are written to.
     pub pages: FxHashMap<PageIndex, SharedCachedPage>,
-    /// We store two caches upfront; we often read instructions from one page and reserve another
-    /// for scratch memory. This prevents map lookups for each instruction.
-    pub last_page: [(PageIndex, Option<SharedCachedPage>); 2],
+    /// The maximum number of pages that may be allocated, if any. Allocating a page beyond the
+    /// budget fails with a [PageBudgetExceeded] error. The budget is not serialized.
+    pub page_budget: Option<usize>,
+    /// The decoded instructions of the pages that instructions were fetched from. Kept coherent
+    /// by the write methods of the [Memory]; pages written to directly through `pages` must be
+    /// invalidated with [Memory::invalidate].
+    pub(crate) instruction_cache: InstructionCache,
+    /// The watchpoints checked by [Memory::get_memory] and [Memory::set_memory]. Watchpoints are
+    /// not serialized.
+    pub(crate) watchpoints: Watchpoints,
+    /// The address, previous value and new value of the words written by [Memory::set_memory]
+    /// since the log was last cleared, if writes are logged. Used to record the memory writes of
+    /// traced instructions, and to undo them.
+    pub(crate) write_log: Option<Vec<(Address, u32, u32)>>,
+    /// The [Paging] state, if cold pages are evicted to a [PageStore]. Evicted pages are read
+    /// back when they are accessed.
+    pub(crate) paging: Option<Paging>,
 }
 
-impl Default for Memory {
-    fn default() -> Self {
-        Self {
-            nodes: FxHashMap::default(),
-            pages: FxHashMap::default(),
-            last_page: [(!0u64, None), (!0u64, None)],
-        }
+impl Memory {
+    /// Forks the [Memory]. The fork shares every page with the original, and a page is only
+    /// copied once either of them writes to it. The memory is merkleized before it is forked, so
+    /// that computing the merkle root of either side does not copy the shared pages.
+    ///
+    /// ### Returns
+    /// - `Ok(fork)`: The forked [Memory], with the same merkle root as the original.
+    /// - `Err(_)`: The [Memory] could not be merkleized.
+    pub fn fork(&mut self) -> Result<Self> {
+        self.merkle_root()?;
+        Ok(self.clone())
     }
-}
 
-impl Memory {
-    /// Returns the number of allocated pages in memory.
+    /// Returns the number of allocated pages in memory, including the pages evicted to the
+    /// [PageStore].
     pub fn page_count(&self) -> usize {
         self.pages.len()
+            + self
+                .paging
+                .as_ref()
+                .map_or(0, |paging| paging.evicted.len())
     }
 
-    /// Performs an operation on all pages in the memory.
+    /// Evicts cold pages to a [PageStore], keeping at most `resident_pages` pages in memory.
+    /// The pages that were least recently accessed are evicted first, as approximated by a
+    /// clock, and the merkle root of an evicted page stays in memory, so that the merkle root of
+    /// the [Memory] can be computed without reading it back. Clones and forks of the [Memory]
+    /// share the store.
     ///
     /// ### Takes
-    /// - `f`: A function that takes a [PageIndex] and a shared reference to a [CachedPage].
-    pub fn for_each_page(&mut self, mut f: impl FnMut(PageIndex, SharedCachedPage)) {
+    /// - `store`: The [PageStore] to evict pages to.
+    /// - `resident_pages`: The maximum number of pages kept in memory. Must be nonzero.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the pages over the limit were evicted successfully. Fails if
+    ///   the [Memory] already has a page store.
+    pub fn set_page_store(
+        &mut self,
+        store: Arc<dyn PageStore>,
+        resident_pages: usize,
+    ) -> Result<()> {
+        if self.paging.is_some() {
+            anyhow::bail!("The memory already has a page store");
+        }
+        self.paging = Some(Paging::new(
+            store,
+            resident_pages,
+            self.pages.keys().copied(),
+        ));
+        self.evict_pages(None)
+    }
+
+    /// Returns the number of allocated pages that are shared with a clone or fork of the
+    /// [Memory].
+    pub fn shared_page_count(&self) -> usize {
+        self.pages
+            .values()
+            .filter(|page| Arc::strong_count(page) > 1)
+            .count()
+    }
+
+    /// Performs an operation on all pages in the memory. Evicted pages are read from the
+    /// [PageStore] without being made resident again.
+    ///
+    /// ### Takes
+    /// - `f`: A function that takes a [PageIndex] and a reference to a [CachedPage].
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the evicted pages were read successfully.
+    pub fn for_each_page(&self, mut f: impl FnMut(PageIndex, &CachedPage)) -> Result<()> {
         self.pages.iter().for_each(|(key, page)| {
-            f(*key, Rc::clone(page));
+            f(*key, page);
         });
+        if let Some(paging) = &self.paging {
+            let mut page = CachedPage::default();
+            for (key, stored) in &paging.evicted {
+                paging.store.read_page(stored.slot.key, &mut page.data)?;
+                page.invalidate_full();
+                f(*key, &page);
+            }
+        }
+        Ok(())
     }
 
-    /// Invalidate a given memory address
+    /// Invalidate a given memory address. This also evicts the decoded instructions of the page
+    /// containing the address.
     ///
     /// ### Takes
     /// - `address`: The address to invalidate.
@@ -61,59 +142,162 @@ impl Memory {
             panic!("Unaligned memory access: {:x}", address);
         }
 
+        self.instruction_cache
+            .invalidate(address as u64 >> page::PAGE_ADDRESS_SIZE);
+        self.invalidate_nodes(address)
+    }
+
+    /// Invalidate the merkle nodes covering a given memory address.
+    ///
+    /// ### Takes
+    /// - `address`: The aligned address to invalidate.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the operation was successful.
+    fn invalidate_nodes(&mut self, address: Address) -> Result<()> {
         // Find the page and invalidate the address within it.
-        match self.page_lookup(address as u64 >> page::PAGE_ADDRESS_SIZE) {
-            Some(page) => {
-                let mut page = page.borrow_mut();
-                let prev_valid = !page.valid[1];
+        match self
+            .pages
+            .get_mut(&(address as u64 >> page::PAGE_ADDRESS_SIZE))
+        {
+            Some(page) => invalidate_page(&mut self.nodes, Arc::make_mut(page), address),
+            // Nothing to invalidate
+            None => Ok(()),
+        }
+    }
 
-                // Invalidate the address within the page.
-                page.invalidate(address & page::PAGE_ADDRESS_MASK as u32)?;
+    /// Lookup a page in the [Memory].
+    ///
+    /// ### Takes
+    /// - `page_index`: The page index to look up.
+    ///
+    /// ### Returns
+    /// - A reference to the [CachedPage] if it exists, or an error if it could not be read back
+    ///   from the [PageStore].
+    #[inline(always)]
+    pub fn page_lookup(&mut self, page_index: PageIndex) -> Result<Option<&CachedPage>> {
+        self.fault_in(page_index)?;
+        Ok(self.pages.get(&page_index).map(Arc::as_ref))
+    }
 
-                // If the page was already invalid before, then nodes to the memory
-                // root will also still be invalid.
-                if prev_valid {
-                    return Ok(());
-                }
-            }
-            None => {
-                // Nothing to invalidate
-                return Ok(());
-            }
+    /// Lookup a page in the [Memory] for writing. A page that is shared with a clone of the
+    /// [Memory] is copied first, so that the write is not visible in the clone.
+    ///
+    /// ### Takes
+    /// - `page_index`: The page index to look up.
+    ///
+    /// ### Returns
+    /// - A mutable reference to the [CachedPage] if it exists, or an error if it could not be
+    ///   read back from the [PageStore].
+    #[inline(always)]
+    pub fn page_lookup_mut(&mut self, page_index: PageIndex) -> Result<Option<&mut CachedPage>> {
+        self.fault_in(page_index)?;
+        Ok(self.pages.get_mut(&page_index).map(Arc::make_mut))
+    }
+
+    /// Marks a resident page as accessed, or reads it back from the [PageStore] if it was
+    /// evicted.
+    ///
+    /// ### Takes
+    /// - `page_index`: The index of the page.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the page is resident or unallocated.
+    #[inline(always)]
+    fn fault_in(&mut self, page_index: PageIndex) -> Result<()> {
+        let Some(paging) = &mut self.paging else {
+            return Ok(());
+        };
+        if self.pages.contains_key(&page_index) {
+            paging.referenced.insert(page_index);
+            return Ok(());
         }
+        self.load_page(page_index)
+    }
+
+    /// Reads an evicted page back from the [PageStore], and evicts a cold resident page if the
+    /// limit of resident pages is exceeded.
+    ///
+    /// ### Takes
+    /// - `page_index`: The index of the page.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the page was read back successfully. Fails if the page does
+    ///   not match the merkle root it was evicted with.
+    fn load_page(&mut self, page_index: PageIndex) -> Result<()> {
+        let Some(paging) = &mut self.paging else {
+            return Ok(());
+        };
+        let Some(stored) = paging.evicted.get(&page_index).cloned() else {
+            return Ok(());
+        };
 
-        // Find the generalized index of the first page covering the address
-        let mut g_index = ((1u64 << 32) | address as u64) >> page::PAGE_ADDRESS_SIZE;
-        // Invalidate all nodes in the branch
-        while g_index > 0 {
-            self.nodes.insert(g_index, None);
-            g_index >>= 1;
+        let mut page = CachedPage::default();
+        paging.store.read_page(stored.slot.key, &mut page.data)?;
+        page.invalidate_full();
+        if page.merkle_root()? != stored.root {
+            anyhow::bail!("Page {} read from the page store is corrupt", page_index);
         }
 
-        Ok(())
+        paging.evicted.remove(&page_index);
+        paging.loaded.insert(page_index, stored);
+        paging.queue.push_back(page_index);
+        self.pages.insert(page_index, Arc::new(page));
+        self.evict_pages(Some(page_index))
     }
 
-    /// Lookup a page in the [Memory]. This function will consult the cache before checking the
-    /// maps, and will cache the page if it is not already cached.
+    /// Evicts resident pages to the [PageStore] until the limit of resident pages is met. The
+    /// clock passes over the pages in the queue, and evicts the first one that was not accessed
+    /// since it was last passed. Pages that were read back and not changed since are not written
+    /// again.
     ///
     /// ### Takes
-    /// - `page_index`: The page index to look up.
+    /// - `protected`: The index of a page that must stay resident, if any.
     ///
     /// ### Returns
-    /// - A reference to the [CachedPage] if it exists.
-    pub fn page_lookup(&mut self, page_index: PageIndex) -> Option<SharedCachedPage> {
-        // Check caches before maps
-        if let Some((_, Some(page))) = self.last_page.iter().find(|(key, _)| *key == page_index) {
-            Some(Rc::clone(page))
-        } else if let Some(page) = self.pages.get(&page_index) {
-            // Cache the page
-            self.last_page[1] = self.last_page[0].clone();
-            self.last_page[0] = (page_index, Some(page.clone()));
-
-            Some(Rc::clone(page))
-        } else {
-            None
+    /// - A [Result] indicating if the pages were evicted successfully.
+    fn evict_pages(&mut self, protected: Option<PageIndex>) -> Result<()> {
+        let Some(paging) = &mut self.paging else {
+            return Ok(());
+        };
+
+        let mut skipped = false;
+        while self.pages.len() > paging.resident_pages {
+            let Some(page_index) = paging.queue.pop_front() else {
+                break;
+            };
+            if Some(page_index) == protected {
+                skipped = true;
+                continue;
+            }
+            if paging.referenced.remove(&page_index) {
+                paging.queue.push_back(page_index);
+                continue;
+            }
+            // The queue may hold pages that were evicted since they were queued.
+            let Some(page) = self.pages.get_mut(&page_index) else {
+                continue;
+            };
+
+            let root = if page.valid[1] {
+                page.cache[1]
+            } else {
+                Arc::make_mut(page).merkle_root()?
+            };
+            // A changed page frees its previous slot before it is written again, so that the slot
+            // can be reused.
+            let slot = match paging.loaded.remove(&page_index) {
+                Some(stored) if stored.root == root => stored.slot,
+                _ => Slot::write(&paging.store, &page.data)?,
+            };
+
+            self.pages.remove(&page_index);
+            paging.evicted.insert(page_index, StoredPage { slot, root });
+        }
+        if skipped {
+            paging.queue.extend(protected);
         }
+        Ok(())
     }
 
     pub fn merkleize_subtree(&mut self, g_index: Gindex) -> Result<[u8; 32]> {
@@ -126,12 +310,22 @@ impl Memory {
         if bits > page::PAGE_KEY_SIZE as u32 {
             let depth_into_page = bits - 1 - page::PAGE_KEY_SIZE as u32;
             let page_index = (g_index >> depth_into_page) & page::PAGE_KEY_MASK as u64;
-            return self.pages.get(&page_index).map_or(
+            // The root of an evicted page is known without reading it back.
+            if depth_into_page == 0 {
+                if let Some(stored) = self
+                    .paging
+                    .as_ref()
+                    .and_then(|paging| paging.evicted.get(&page_index))
+                {
+                    return Ok(stored.root);
+                }
+            }
+            return self.page_lookup_mut(page_index)?.map_or(
                 Ok(page::ZERO_HASHES[28 - bits as usize]),
                 |page| {
                     let page_g_index =
                         (1 << depth_into_page) | (g_index & ((1 << depth_into_page) - 1));
-                    page.borrow_mut().merkleize_subtree(page_g_index)
+                    page.merkleize_subtree(page_g_index)
                 },
             );
         }
@@ -163,6 +357,29 @@ impl Memory {
         self.merkleize_subtree(1)
     }
 
+    /// Compute the merkle root of the [Memory], rehashing the invalidated pages in parallel on
+    /// the global [rayon] thread pool before folding the nodes above them. The root is identical
+    /// to the one computed by [Memory::merkle_root], and the caches of the pages and nodes are
+    /// updated the same way.
+    ///
+    /// ### Returns
+    /// - The 32 byte merkle root hash of the [Memory].
+    #[cfg(feature = "parallel")]
+    pub fn par_merkle_root(&mut self) -> Result<[u8; 32]> {
+        use rayon::prelude::*;
+
+        // A page whose root is invalid has at least one invalidated node.
+        self.pages
+            .values_mut()
+            .filter(|page| !page.valid[1])
+            .map(Arc::make_mut)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .try_for_each(|page| page.merkle_root().map(|_| ()))?;
+
+        self.merkle_root()
+    }
+
     /// Compute the merkle proof for the given address in the [Memory].
     ///
     /// ### Takes
@@ -219,6 +436,48 @@ impl Memory {
         Ok(proof)
     }
 
+    /// Compute a single merkle proof for several addresses in the [Memory]. Siblings that are
+    /// shared between the branches of the addresses, or that can be computed from the proven
+    /// leaves, are only included once or not at all.
+    ///
+    /// The proof is the 32 byte leaves containing the addresses, in ascending order, followed by
+    /// the sibling hashes that are not known to the verifier, from the bottom of the tree up and
+    /// in ascending order within a level. A proof of a single address is identical to the proof
+    /// of [Memory::merkle_proof].
+    ///
+    /// ### Takes
+    /// - `addresses`: The 4 byte aligned addresses to prove, in any order.
+    ///
+    /// ### Returns
+    /// - `Ok(proof)`: The merkle proof of the addresses, to be verified with
+    ///   [verify_multi_proof].
+    /// - `Err(_)`: An address is unaligned.
+    pub fn multi_proof(&mut self, addresses: &[Address]) -> Result<Vec<u8>> {
+        let mut known = leaf_indices(addresses)?;
+        let mut proof = Vec::with_capacity(known.len() * 32);
+        for g_index in &known {
+            proof.extend(self.merkleize_subtree(*g_index)?);
+        }
+
+        for _ in 0..32 - 5 {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let g_index = known[i];
+                if g_index & 1 == 0 && known.get(i + 1) == Some(&(g_index | 1)) {
+                    i += 2;
+                } else {
+                    proof.extend(self.merkleize_subtree(g_index ^ 1)?);
+                    i += 1;
+                }
+                parents.push(g_index >> 1);
+            }
+            known = parents;
+        }
+
+        Ok(proof)
+    }
+
     /// Set a 32 bit value in the [Memory] at a given address.
     /// This will invalidate the page at the given address, or allocate a new page if it does not exist.
     ///
@@ -235,28 +494,41 @@ impl Memory {
             anyhow::bail!("Unaligned memory access: {:x}", address);
         }
 
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(address, WatchKind::Write);
+        }
+
         let page_index = address as PageIndex >> page::PAGE_ADDRESS_SIZE as u64;
         let page_address = address as usize & page::PAGE_ADDRESS_MASK;
+        self.fault_in(page_index)?;
 
         // Attempt to look up the page.
         // - If it does exist, invalidate it before changing it.
         // - If it does not exist, allocate it.
-        let page = self
-            .page_lookup(page_index)
-            .map(|page| {
+        let page = match self.pages.get_mut(&page_index) {
+            Some(page) => {
                 // If the page exists, invalidate it - the value will change.
-                self.invalidate(address)?;
-                Ok::<_, anyhow::Error>(page)
-            })
-            .unwrap_or_else(|| {
-                let page = self.alloc_page(page_index)?;
-                let _ = page.borrow_mut().invalidate(page_address as Address);
-                Ok(page)
-            })?;
+                let page = Arc::make_mut(page);
+                invalidate_page(&mut self.nodes, page, address)?;
+                page
+            }
+            None => {
+                self.alloc_page(page_index)?;
+                let page =
+                    Arc::make_mut(self.pages.get_mut(&page_index).expect("page was allocated"));
+                let _ = page.invalidate(page_address as Address);
+                page
+            }
+        };
+
+        if let Some(log) = &mut self.write_log {
+            let previous = &page.data[page_address..page_address + 4];
+            log.push((address, u32::from_be_bytes(previous.try_into()?), value));
+        }
 
         // Copy the 32 bit value into the page
-        page.borrow_mut().data[page_address..page_address + 4]
-            .copy_from_slice(&value.to_be_bytes());
+        page.data[page_address..page_address + 4].copy_from_slice(&value.to_be_bytes());
+        self.instruction_cache.update(address, value);
 
         Ok(())
     }
@@ -270,39 +542,106 @@ impl Memory {
     /// - The 32 bit value at the given address.
     #[inline(always)]
     pub fn get_memory(&mut self, address: Address) -> Result<u32> {
+        let value = self.peek_memory(address)?;
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(address, WatchKind::Read);
+        }
+        Ok(value)
+    }
+
+    /// Retrieve a 32 bit value from the [Memory] at a given address, without firing read
+    /// watchpoints. Used by the emulator for reads that are not reads of the guest program, such
+    /// as loading the word a partial store is merged into.
+    ///
+    /// ### Takes
+    /// - `address`: The [Address] to retrieve the value from.
+    ///
+    /// ### Returns
+    /// - The 32 bit value at the given address.
+    #[inline(always)]
+    pub fn peek_memory(&mut self, address: Address) -> Result<u32> {
         // Address must be aligned to 4 bytes
         if address & 0x3 != 0 {
             anyhow::bail!("Unaligned memory access: {:x}", address);
         }
 
-        match self.page_lookup(address as u64 >> page::PAGE_ADDRESS_SIZE as u64) {
+        match self.page_lookup(address as u64 >> page::PAGE_ADDRESS_SIZE as u64)? {
             Some(page) => {
                 let page_address = address as usize & page::PAGE_ADDRESS_MASK;
                 Ok(u32::from_be_bytes(
-                    page.borrow().data[page_address..page_address + 4].try_into()?,
+                    page.data[page_address..page_address + 4].try_into()?,
                 ))
             }
             None => Ok(0),
         }
     }
 
+    /// Fetch the [DecodedInstruction] at a given address, decoding its page if it has not been
+    /// fetched from before.
+    ///
+    /// ### Takes
+    /// - `address`: The [Address] of the instruction.
+    ///
+    /// ### Returns
+    /// - The [DecodedInstruction] at the given address.
+    #[inline(always)]
+    pub(crate) fn fetch_instruction(&mut self, address: Address) -> Result<DecodedInstruction> {
+        // Address must be aligned to 4 bytes
+        if address & 0x3 != 0 {
+            anyhow::bail!("Unaligned memory access: {:x}", address);
+        }
+
+        if let Some(instruction) = self.instruction_cache.get(address) {
+            return Ok(instruction);
+        }
+
+        let page_index = address as PageIndex >> page::PAGE_ADDRESS_SIZE as u64;
+        self.fault_in(page_index)?;
+        match self.pages.get(&page_index) {
+            Some(page) => {
+                let index = (address as usize & page::PAGE_ADDRESS_MASK) >> 2;
+                Ok(self.instruction_cache.insert(page_index, &page.data)[index])
+            }
+            // Unmapped memory reads as zero. It is not cached, so that the page is decoded once
+            // it is allocated.
+            None => Ok(DecodedInstruction::new(0)),
+        }
+    }
+
     /// Allocate a new page in the [Memory] at a given page index.
     ///
     /// ### Takes
     /// - `page_index`: The page index to allocate the page at.
     ///
     /// ### Returns
-    /// - A reference to the allocated [CachedPage].
-    pub fn alloc_page(&mut self, page_index: PageIndex) -> Result<SharedCachedPage> {
-        let page = SharedCachedPage::default();
-        self.pages.insert(page_index, page.clone());
+    /// - A reference to the allocated [CachedPage], or a [PageBudgetExceeded] error if the page
+    ///   budget is exhausted.
+    pub fn alloc_page(&mut self, page_index: PageIndex) -> Result<&mut CachedPage> {
+        if let Some(budget) = self.page_budget {
+            if self.page_count() >= budget && !self.pages.contains_key(&page_index) {
+                return Err(PageBudgetExceeded { budget }.into());
+            }
+        }
+        if let Some(paging) = &mut self.paging {
+            paging.evicted.remove(&page_index);
+            paging.loaded.remove(&page_index);
+            if !self.pages.contains_key(&page_index) {
+                paging.queue.push_back(page_index);
+            }
+        }
 
+        self.instruction_cache.invalidate(page_index);
         let mut key = (1 << page::PAGE_KEY_SIZE) | page_index;
         while key > 0 {
             self.nodes.insert(key, None);
             key >>= 1;
         }
-        Ok(page)
+
+        self.pages.insert(page_index, SharedCachedPage::default());
+        self.evict_pages(Some(page_index))?;
+        Ok(self
+            .page_lookup_mut(page_index)?
+            .expect("page was allocated"))
     }
 
     /// Set a range of memory in the [Memory] at a given address.
@@ -320,13 +659,17 @@ impl Memory {
             let page_index = address as PageIndex >> page::PAGE_ADDRESS_SIZE as u64;
             let page_address = address as usize & page::PAGE_ADDRESS_MASK;
 
+            self.fault_in(page_index)?;
+            if !self.pages.contains_key(&page_index) {
+                self.alloc_page(page_index)?;
+            }
+            self.instruction_cache.invalidate(page_index);
             let page = self
-                .page_lookup(page_index)
-                .map(Ok)
-                .unwrap_or_else(|| self.alloc_page(page_index))?;
-            page.borrow_mut().invalidate_full();
+                .page_lookup_mut(page_index)?
+                .expect("page was allocated");
+            page.invalidate_full();
 
-            match data.read(&mut page.borrow_mut().data[page_address..]) {
+            match data.read(&mut page.data[page_address..]) {
                 Ok(n) => {
                     if n == 0 {
                         return Ok(());
@@ -344,7 +687,7 @@ impl Memory {
     /// - A human-readable string describing the size of the [Memory] in B, KiB,
     ///   MiB, GiB, TiB, PiB, or EiB.
     pub fn usage(&self) -> String {
-        let total = (self.pages.len() * page::PAGE_SIZE) as u64;
+        let total = (self.page_count() * page::PAGE_SIZE) as u64;
         const UNIT: u64 = 1024;
         if total < UNIT {
             return format!("{} B", total);
@@ -365,6 +708,226 @@ impl Memory {
     }
 }
 
+/// Invalidate an address within a page, and the merkle nodes above the page.
+///
+/// ### Takes
+/// - `nodes`: The merkle nodes above the pages of the [Memory].
+/// - `page`: The [CachedPage] containing the address.
+/// - `address`: The aligned address to invalidate.
+///
+/// ### Returns
+/// - A [Result] indicating if the operation was successful.
+#[inline(always)]
+fn invalidate_page(
+    nodes: &mut FxHashMap<Gindex, Option<[u8; 32]>>,
+    page: &mut CachedPage,
+    address: Address,
+) -> Result<()> {
+    let prev_valid = !page.valid[1];
+
+    // Invalidate the address within the page.
+    page.invalidate(address & page::PAGE_ADDRESS_MASK as u32)?;
+
+    // If the page was already invalid before, then nodes to the memory
+    // root will also still be invalid.
+    if prev_valid {
+        return Ok(());
+    }
+
+    // Find the generalized index of the first page covering the address
+    let mut g_index = ((1u64 << 32) | address as u64) >> page::PAGE_ADDRESS_SIZE;
+    // Invalidate all nodes in the branch
+    while g_index > 0 {
+        nodes.insert(g_index, None);
+        g_index >>= 1;
+    }
+
+    Ok(())
+}
+
+/// Verifies a merkle proof produced by [Memory::merkle_proof] against a memory root, without
+/// access to the [Memory].
+///
+/// ### Takes
+/// - `root`: The merkle root of the [Memory].
+/// - `address`: The 4 byte aligned address of the proven word.
+/// - `proof`: The [MEMORY_PROOF_SIZE] byte merkle proof of the address.
+///
+/// ### Returns
+/// - `Ok(word)`: The proven word at the address.
+/// - `Err(_)`: The address is unaligned, or the proof is malformed or does not match the root.
+pub fn verify_merkle_proof(root: [u8; 32], address: Address, proof: &[u8]) -> Result<u32> {
+    if address & 0x3 != 0 {
+        anyhow::bail!("Unaligned memory access: {:x}", address);
+    }
+    if proof.len() != MEMORY_PROOF_SIZE {
+        anyhow::bail!(
+            "Invalid memory proof size {}, expected {}",
+            proof.len(),
+            MEMORY_PROOF_SIZE
+        );
+    }
+    if proof_root(address, proof) != root {
+        anyhow::bail!("Invalid memory proof for address 0x{:08x}", address);
+    }
+
+    let offset = (address & 0x1C) as usize;
+    Ok(u32::from_be_bytes(
+        proof[offset..offset + 4]
+            .try_into()
+            .expect("word is 4 bytes"),
+    ))
+}
+
+/// Verifies the merkle proofs of several words against the same memory root with
+/// [verify_merkle_proof].
+///
+/// ### Takes
+/// - `root`: The merkle root of the [Memory].
+/// - `proofs`: The address and merkle proof of every proven word.
+///
+/// ### Returns
+/// - `Ok(words)`: The proven words, in the order of the proofs.
+/// - `Err(_)`: Any of the proofs is invalid.
+pub fn verify_merkle_proofs(root: [u8; 32], proofs: &[(Address, &[u8])]) -> Result<Vec<u32>> {
+    proofs
+        .iter()
+        .map(|(address, proof)| verify_merkle_proof(root, *address, proof))
+        .collect()
+}
+
+/// Verifies a merkle proof of several addresses produced by [Memory::multi_proof] against a
+/// memory root, without access to the [Memory].
+///
+/// ### Takes
+/// - `root`: The merkle root of the [Memory].
+/// - `addresses`: The 4 byte aligned addresses the proof was produced for, in any order.
+/// - `proof`: The merkle proof of the addresses.
+///
+/// ### Returns
+/// - `Ok(words)`: The proven words, in the order of the addresses.
+/// - `Err(_)`: An address is unaligned, or the proof is malformed or does not match the root.
+pub fn verify_multi_proof(root: [u8; 32], addresses: &[Address], proof: &[u8]) -> Result<Vec<u32>> {
+    let leaves = leaf_indices(addresses)?;
+    let mut chunks = proof.chunks_exact(32);
+    if !chunks.remainder().is_empty() {
+        anyhow::bail!("Invalid memory proof size {}", proof.len());
+    }
+    let mut next = || -> Result<[u8; 32]> {
+        let chunk = chunks
+            .next()
+            .ok_or(anyhow::anyhow!("Memory proof is too short"))?;
+        Ok(chunk.try_into().expect("chunk is 32 bytes"))
+    };
+
+    let mut known = leaves
+        .iter()
+        .map(|g_index| Ok((*g_index, next()?)))
+        .collect::<Result<Vec<_>>>()?;
+    let data = known.iter().map(|(_, leaf)| *leaf).collect::<Vec<_>>();
+
+    for _ in 0..32 - 5 {
+        let mut parents = Vec::with_capacity(known.len());
+        let mut i = 0;
+        while i < known.len() {
+            let (g_index, node) = known[i];
+            let (left, right) = match known.get(i + 1) {
+                Some((sibling, right)) if g_index & 1 == 0 && *sibling == g_index | 1 => {
+                    i += 2;
+                    (node, *right)
+                }
+                _ => {
+                    i += 1;
+                    let sibling = next()?;
+                    if g_index & 1 == 0 {
+                        (node, sibling)
+                    } else {
+                        (sibling, node)
+                    }
+                }
+            };
+            parents.push((g_index >> 1, *keccak_concat_hashes(left, right)));
+        }
+        known = parents;
+    }
+
+    if next().is_ok() {
+        anyhow::bail!("Memory proof is too long");
+    }
+    if known[0].1 != root {
+        anyhow::bail!("Invalid memory proof for {} addresses", addresses.len());
+    }
+
+    Ok(addresses
+        .iter()
+        .map(|address| {
+            let leaf = &data[leaves
+                .binary_search(&leaf_index(*address))
+                .expect("address is proven")];
+            let offset = (address & 0x1C) as usize;
+            u32::from_be_bytes(
+                leaf[offset..offset + 4]
+                    .try_into()
+                    .expect("word is 4 bytes"),
+            )
+        })
+        .collect())
+}
+
+/// Returns the generalized index of the 32 byte leaf containing an address.
+#[inline(always)]
+fn leaf_index(address: Address) -> Gindex {
+    (1 << (32 - 5)) | (address as Gindex >> 5)
+}
+
+/// Returns the sorted and deduplicated generalized indices of the leaves containing the given
+/// addresses.
+///
+/// ### Takes
+/// - `addresses`: The 4 byte aligned addresses.
+///
+/// ### Returns
+/// - `Ok(leaves)`: The generalized indices of the leaves.
+/// - `Err(_)`: An address is unaligned, or no address is given.
+fn leaf_indices(addresses: &[Address]) -> Result<Vec<Gindex>> {
+    if addresses.is_empty() {
+        anyhow::bail!("No addresses to prove");
+    }
+    let mut leaves = addresses
+        .iter()
+        .map(|address| {
+            if address & 0x3 != 0 {
+                anyhow::bail!("Unaligned memory access: {:x}", address);
+            }
+            Ok(leaf_index(*address))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    leaves.sort_unstable();
+    leaves.dedup();
+    Ok(leaves)
+}
+
+/// Computes the merkle root that a memory proof commits to.
+///
+/// ### Takes
+/// - `address`: The address the proof is for.
+/// - `proof`: The [MEMORY_PROOF_SIZE] byte merkle proof of the address.
+///
+/// ### Returns
+/// - The merkle root.
+pub(crate) fn proof_root(address: Address, proof: &[u8]) -> [u8; 32] {
+    let mut node: [u8; 32] = proof[..32].try_into().expect("leaf is 32 bytes");
+    for (i, sibling) in proof[32..MEMORY_PROOF_SIZE].chunks_exact(32).enumerate() {
+        let sibling = sibling.try_into().expect("sibling is 32 bytes");
+        node = if (address >> (i + 5)) & 1 == 1 {
+            *keccak_concat_hashes(sibling, node)
+        } else {
+            *keccak_concat_hashes(node, sibling)
+        };
+    }
+    node
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct PageEntry {
     index: PageIndex,
@@ -386,14 +949,14 @@ impl Serialize for Memory {
     where
         S: serde::Serializer,
     {
-        let mut page_entries: Vec<PageEntry> = self
-            .pages
-            .iter()
-            .map(|(&k, p)| PageEntry {
+        let mut page_entries: Vec<PageEntry> = Vec::with_capacity(self.page_count());
+        self.for_each_page(|k, p| {
+            page_entries.push(PageEntry {
                 index: k,
-                data: p.borrow().data,
+                data: p.data,
             })
-            .collect();
+        })
+        .map_err(serde::ser::Error::custom)?;
 
         page_entries.sort_by(|a, b| a.index.cmp(&b.index));
         page_entries.serialize(serializer)
@@ -419,7 +982,6 @@ impl<'de> Deserialize<'de> for Memory {
             let page = memory.alloc_page(p.index).map_err(|_| {
                 serde::de::Error::custom("Failed to allocate page in deserialization")
             })?;
-            let mut page = page.borrow_mut();
             page.data = p.data;
             page.invalidate_full();
         }
@@ -460,9 +1022,13 @@ impl<'a> Read for MemoryReader<'a> {
             end = end_address as usize & page::PAGE_ADDRESS_MASK;
         }
         let n = end - start;
-        match self.memory.page_lookup(page_index) {
+        match self
+            .memory
+            .page_lookup(page_index)
+            .map_err(std::io::Error::other)?
+        {
             Some(page) => {
-                std::io::copy(&mut page.borrow().data[start..end].as_ref(), &mut buf)?;
+                std::io::copy(&mut page.data[start..end].as_ref(), &mut buf)?;
             }
             None => {
                 std::io::copy(&mut vec![0; n].as_slice(), &mut buf)?;
@@ -502,18 +1068,84 @@ mod test {
             let root = memory.merkle_root().unwrap();
             let proof = memory.merkle_proof(0x80004).unwrap();
             assert_eq!([0x00, 0x00, 0x00, 0x2a], proof[4..8]);
-            let mut node = proof[..32].try_into().unwrap();
-            let mut path = 0x80004 >> 5;
-            (32..proof.len()).step_by(32).for_each(|i| {
-                let sib: [u8; 32] = proof[i..i + 32].try_into().unwrap();
-                if path & 1 != 0 {
-                    node = *keccak_concat_hashes(sib, node);
-                } else {
-                    node = *keccak_concat_hashes(node, sib);
-                }
-                path >>= 1;
-            });
-            assert_eq!(root, node, "proof must verify");
+            assert_eq!(
+                verify_merkle_proof(root, 0x80004, &proof).unwrap(),
+                42,
+                "proof must verify"
+            );
+        }
+        #[test]
+        fn verify() {
+            let mut memory = Memory::default();
+            memory.set_memory(0x10000, 0xaabbccdd).unwrap();
+            memory.set_memory(0x1001C, 7).unwrap();
+            memory.set_memory(0x13370000, 123).unwrap();
+            let root = memory.merkle_root().unwrap();
+
+            let addresses = [0x10000, 0x1001C, 0x13370000, 0x80000000];
+            let proofs = addresses.map(|address| memory.merkle_proof(address).unwrap());
+            let words = verify_merkle_proofs(
+                root,
+                &addresses
+                    .iter()
+                    .zip(&proofs)
+                    .map(|(address, proof)| (*address, proof.as_slice()))
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+            assert_eq!(words, vec![0xaabbccdd, 7, 123, 0]);
+
+            // The same proof covers every word in its leaf, but no other address.
+            assert_eq!(verify_merkle_proof(root, 0x10004, &proofs[0]).unwrap(), 0);
+            assert!(verify_merkle_proof(root, 0x10020, &proofs[0]).is_err());
+            assert!(verify_merkle_proof(root, 0x10002, &proofs[0]).is_err());
+            assert!(verify_merkle_proof(root, 0x10000, &proofs[0][1..]).is_err());
+            for offset in [0, 32, MEMORY_PROOF_SIZE - 1] {
+                let mut proof = proofs[0];
+                proof[offset] ^= 1;
+                assert!(verify_merkle_proof(root, 0x10000, &proof).is_err());
+                assert!(
+                    verify_merkle_proofs(root, &[(0x1001C, &proofs[1]), (0x10000, &proof)])
+                        .is_err()
+                );
+            }
+        }
+
+        #[test]
+        fn multi_proof() {
+            let mut memory = Memory::default();
+            memory.set_memory(0x10000, 0xaabbccdd).unwrap();
+            memory.set_memory(0x1001C, 7).unwrap();
+            memory.set_memory(0x10040, 8).unwrap();
+            memory.set_memory(0x13370000, 123).unwrap();
+            let root = memory.merkle_root().unwrap();
+
+            // A single address is proven like with `merkle_proof`.
+            let proof = memory.multi_proof(&[0x13370000]).unwrap();
+            assert_eq!(proof, memory.merkle_proof(0x13370000).unwrap());
+
+            // Shared siblings are only included once.
+            let addresses = [0x13370000, 0x10040, 0x10000, 0x1001C, 0x10000, 0x80000000];
+            let proof = memory.multi_proof(&addresses).unwrap();
+            assert!(proof.len() < 4 * MEMORY_PROOF_SIZE);
+            assert_eq!(
+                verify_multi_proof(root, &addresses, &proof).unwrap(),
+                vec![123, 8, 0xaabbccdd, 7, 0xaabbccdd, 0]
+            );
+
+            assert!(verify_multi_proof(root, &addresses[1..], &proof).is_err());
+            assert!(verify_multi_proof(root, &[0x10000, 0x10004], &proof).is_err());
+            assert!(verify_multi_proof(root, &[], &[]).is_err());
+            assert!(memory.multi_proof(&[0x10002]).is_err());
+            assert!(verify_multi_proof(root, &addresses, &proof[32..]).is_err());
+            assert!(
+                verify_multi_proof(root, &addresses, &[&proof[..], &[0; 32]].concat()).is_err()
+            );
+            for offset in [0, 4 * 32, proof.len() - 1] {
+                let mut proof = proof.clone();
+                proof[offset] ^= 1;
+                assert!(verify_multi_proof(root, &addresses, &proof).is_err());
+            }
         }
     }
 
@@ -633,6 +1265,33 @@ mod test {
                 "Zero again"
             );
         }
+
+        #[cfg(feature = "parallel")]
+        #[test]
+        fn parallel() {
+            use rand::Rng;
+
+            let mut rng = rand::thread_rng();
+            let mut memory = Memory::default();
+            let mut reference = Memory::default();
+            for _ in 0..3 {
+                for _ in 0..500 {
+                    let address = rng.gen_range(0..0x40000u32) << 2;
+                    let value = rng.gen::<u32>();
+                    memory.set_memory(address, value).unwrap();
+                    reference.set_memory(address, value).unwrap();
+                }
+                assert_eq!(
+                    memory.par_merkle_root().unwrap(),
+                    reference.merkle_root().unwrap()
+                );
+                assert_eq!(
+                    memory.merkle_proof(0x1000).unwrap(),
+                    reference.merkle_proof(0x1000).unwrap()
+                );
+            }
+            assert_eq!(memory, reference);
+        }
     }
 
     mod read_write {
@@ -705,6 +1364,99 @@ mod test {
             assert!(memory.set_memory(15, 0x11223344).is_err());
             assert_eq!(0xaabbccdd, memory.get_memory(12).unwrap());
         }
+
+        #[test]
+        fn page_budget() {
+            let mut memory = Memory {
+                page_budget: Some(2),
+                ..Default::default()
+            };
+            memory.set_memory(0x1000, 1).unwrap();
+            memory.set_memory(0x2000, 2).unwrap();
+            // Writing to allocated pages does not count against the budget.
+            memory.set_memory(0x1004, 3).unwrap();
+
+            let err = memory.set_memory(0x3000, 4).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<PageBudgetExceeded>(),
+                Some(&PageBudgetExceeded { budget: 2 })
+            );
+            assert_eq!(memory.page_count(), 2);
+            assert_eq!(memory.get_memory(0x3000).unwrap(), 0);
+        }
+    }
+
+    mod copy_on_write {
+        use super::*;
+        use crate::{test_utils::StaticOracle, InstrumentedState, State};
+
+        #[test]
+        fn clone() {
+            let mut memory = Memory::default();
+            memory.set_memory(0x1000, 1).unwrap();
+            memory.set_memory(0x2000, 2).unwrap();
+            let root = memory.merkle_root().unwrap();
+
+            // The clone shares its pages until either side writes to them.
+            let mut clone = memory.clone();
+            assert!(Arc::ptr_eq(&memory.pages[&1], &clone.pages[&1]));
+            clone.set_memory(0x1000, 3).unwrap();
+            assert!(!Arc::ptr_eq(&memory.pages[&1], &clone.pages[&1]));
+            assert!(Arc::ptr_eq(&memory.pages[&2], &clone.pages[&2]));
+
+            assert_eq!(memory.get_memory(0x1000).unwrap(), 1);
+            assert_eq!(clone.get_memory(0x1000).unwrap(), 3);
+            assert_eq!(memory.merkle_root().unwrap(), root);
+            assert_ne!(clone.merkle_root().unwrap(), root);
+        }
+
+        #[test]
+        fn fork() {
+            let mut memory = Memory::default();
+            for i in 0..16 {
+                memory.set_memory(i * 0x1000, i + 1).unwrap();
+            }
+            memory.merkle_root().unwrap();
+            memory.set_memory(0x3000, 42).unwrap();
+
+            // Forking merkleizes the dirty page first, so no page is copied by hashing it.
+            let mut fork = memory.fork().unwrap();
+            assert_eq!(fork.shared_page_count(), 16);
+            let root = fork.merkle_root().unwrap();
+            assert_eq!(memory.merkle_root().unwrap(), root);
+            assert_eq!(memory.shared_page_count(), 16);
+
+            // A write copies only the written page.
+            fork.set_memory(0x5000, 7).unwrap();
+            assert_eq!(fork.shared_page_count(), 15);
+            assert_eq!(memory.shared_page_count(), 15);
+            assert_ne!(fork.merkle_root().unwrap(), root);
+            assert_eq!(memory.merkle_root().unwrap(), root);
+            assert_eq!(memory.get_memory(0x5000).unwrap(), 6);
+            assert_eq!(fork.get_memory(0x5000).unwrap(), 7);
+
+            drop(fork);
+            assert_eq!(memory.shared_page_count(), 0);
+        }
+
+        #[test]
+        fn send_sync() {
+            fn assert_send_sync<T: Send + Sync>() {}
+            fn assert_send<T: Send>() {}
+            assert_send_sync::<Memory>();
+            assert_send_sync::<State>();
+            assert_send::<InstrumentedState<Vec<u8>, Vec<u8>, StaticOracle>>();
+
+            let mut memory = Memory::default();
+            memory.set_memory(0x1000, 1).unwrap();
+            let root = memory.merkle_root().unwrap();
+            let moved = memory.clone();
+            let handle = std::thread::spawn(move || {
+                let mut moved = moved;
+                moved.merkle_root().unwrap()
+            });
+            assert_eq!(handle.join().unwrap(), root);
+        }
     }
 
     mod serialize {
@@ -737,14 +1489,15 @@ mod test {
                         Just(dummy_page.clone()),
                         0..10,
                     ),
-                    // Generating random values for last_page
-                    (any::<PageIndex>(), Just(Some(dummy_page.clone()))),
-                    (any::<PageIndex>(), Just(Some(dummy_page.clone()))),
                 )
-                    .prop_map(|(nodes, pages, lp_a, lp_b)| Memory {
+                    .prop_map(|(nodes, pages)| Memory {
                         nodes: nodes.into_iter().collect::<FxHashMap<_, _>>(),
                         pages: pages.into_iter().collect::<FxHashMap<_, _>>(),
-                        last_page: [lp_a, lp_b],
+                        page_budget: None,
+                        instruction_cache: Default::default(),
+                        watchpoints: Default::default(),
+                        write_log: None,
+                        paging: None,
                     })
                     .boxed()
             }
@@ -760,7 +1513,7 @@ mod test {
                 assert_eq!(merkle_root_pre, merkle_root_post);
                 for (i, page) in memory.pages.iter() {
                     let deserialized_page = deserialized_mem.pages.get(i).unwrap();
-                    assert_eq!(page.borrow().data, deserialized_page.borrow().data);
+                    assert_eq!(page.data, deserialized_page.data);
                 }
             }
         }
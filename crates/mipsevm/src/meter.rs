@@ -0,0 +1,243 @@
+//! This module contains the [CostMeter], which charges a configurable cost for every instruction
+//! the MIPS emulator executes and stops the emulator once a budget is exhausted.
+
+use crate::{icache::DecodedInstruction, types::Syscall, CostBudgetExhausted, Fd};
+use serde::{Deserialize, Serialize};
+
+/// The class of an executed instruction, used to look up its cost in a [CostTable].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionClass {
+    /// Arithmetic, logic, shift, move and `hi`/`lo` instructions.
+    Alu,
+    /// Loads and stores.
+    Memory,
+    /// Branches and jumps.
+    Branch,
+    /// Syscalls, other than preimage reads.
+    Syscall,
+    /// `read` syscalls on the preimage oracle.
+    PreimageRead,
+}
+
+impl InstructionClass {
+    /// Classifies an instruction before it is executed.
+    ///
+    /// ### Takes
+    /// - `decoded`: The [DecodedInstruction] to classify.
+    /// - `registers`: The registers of the current thread, used to tell preimage reads apart from
+    ///   other syscalls.
+    ///
+    /// ### Returns
+    /// - The [InstructionClass] of the instruction.
+    #[inline(always)]
+    pub(crate) fn of(decoded: DecodedInstruction, registers: &[u32; 32]) -> Self {
+        match (decoded.opcode, decoded.fun) {
+            (0, 0x0c) => {
+                if registers[2] == Syscall::Read as u32 && registers[4] == Fd::PreimageRead as u32 {
+                    Self::PreimageRead
+                } else {
+                    Self::Syscall
+                }
+            }
+            (0, 0x08 | 0x09) | (0x01..=0x07, _) => Self::Branch,
+            (0x20.., _) => Self::Memory,
+            _ => Self::Alu,
+        }
+    }
+}
+
+/// The [CostTable] holds the cost of each [InstructionClass]. By default, every instruction costs
+/// 1, so that the cost is the number of executed instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CostTable {
+    /// The cost of an [InstructionClass::Alu] instruction.
+    pub alu: u64,
+    /// The cost of an [InstructionClass::Memory] instruction.
+    pub memory: u64,
+    /// The cost of an [InstructionClass::Branch] instruction.
+    pub branch: u64,
+    /// The cost of an [InstructionClass::Syscall] instruction.
+    pub syscall: u64,
+    /// The cost of an [InstructionClass::PreimageRead] instruction.
+    pub preimage_read: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            memory: 1,
+            branch: 1,
+            syscall: 1,
+            preimage_read: 1,
+        }
+    }
+}
+
+impl CostTable {
+    /// Returns the cost of an [InstructionClass].
+    #[inline(always)]
+    pub fn cost(&self, class: InstructionClass) -> u64 {
+        match class {
+            InstructionClass::Alu => self.alu,
+            InstructionClass::Memory => self.memory,
+            InstructionClass::Branch => self.branch,
+            InstructionClass::Syscall => self.syscall,
+            InstructionClass::PreimageRead => self.preimage_read,
+        }
+    }
+}
+
+/// The [CostMeter] accumulates the cost of the instructions executed by the MIPS emulator.
+///
+/// Steps that only switch threads in multithreaded mode do not execute an instruction and are
+/// free.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CostMeter {
+    /// The [CostTable] instructions are charged from.
+    pub table: CostTable,
+    /// The cost after which the emulator stops, if any.
+    pub budget: Option<u64>,
+    /// The cost of the instructions executed so far.
+    pub used: u64,
+}
+
+impl CostMeter {
+    /// Creates a new [CostMeter] without a budget.
+    ///
+    /// ### Takes
+    /// - `table`: The [CostTable] to charge instructions from.
+    ///
+    /// ### Returns
+    /// - The new [CostMeter].
+    pub fn new(table: CostTable) -> Self {
+        Self {
+            table,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the budget of the [CostMeter].
+    ///
+    /// ### Takes
+    /// - `budget`: The cost after which the emulator stops, or `None` to never stop.
+    ///
+    /// ### Returns
+    /// - The [CostMeter] with the budget set.
+    pub fn with_budget(mut self, budget: Option<u64>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Returns the remaining budget, if the [CostMeter] has one.
+    pub fn remaining(&self) -> Option<u64> {
+        self.budget.map(|budget| budget.saturating_sub(self.used))
+    }
+
+    /// Charges the cost of an executed instruction.
+    ///
+    /// ### Takes
+    /// - `class`: The [InstructionClass] of the executed instruction.
+    #[inline(always)]
+    pub fn charge(&mut self, class: InstructionClass) {
+        self.used = self.used.saturating_add(self.table.cost(class));
+    }
+
+    /// Checks whether another instruction may be executed. The instruction that reaches the
+    /// budget is still executed, so the used cost may exceed the budget by at most its cost.
+    ///
+    /// ### Returns
+    /// - `Err(_)`: The budget is exhausted.
+    #[inline(always)]
+    pub fn check(&self) -> Result<(), CostBudgetExhausted> {
+        match self.budget {
+            Some(budget) if self.used >= budget => Err(CostBudgetExhausted {
+                budget,
+                used: self.used,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CostMeter, CostTable, InstructionClass};
+    use crate::{
+        icache::DecodedInstruction,
+        test_utils::{load_program, StaticOracle},
+        CostBudgetExhausted, InstrumentedState, VmError,
+    };
+
+    #[test]
+    fn classify() {
+        let mut registers = [0u32; 32];
+        let class = |word: u32, registers: &[u32; 32]| {
+            InstructionClass::of(DecodedInstruction::new(word), registers)
+        };
+
+        // addu $v0, $a0, $a1
+        assert_eq!(class(0x00851021, &registers), InstructionClass::Alu);
+        // lui $t0, 0x2409
+        assert_eq!(class(0x3C082409, &registers), InstructionClass::Alu);
+        // sw $t0, 16($zero)
+        assert_eq!(class(0xAC080010, &registers), InstructionClass::Memory);
+        // lw $ra, 28($sp)
+        assert_eq!(class(0x8FBF001C, &registers), InstructionClass::Memory);
+        // bne $t0, $zero, -2
+        assert_eq!(class(0x1500FFFE, &registers), InstructionClass::Branch);
+        // jr $ra
+        assert_eq!(class(0x03E00008, &registers), InstructionClass::Branch);
+        // syscall
+        assert_eq!(class(0x0000000C, &registers), InstructionClass::Syscall);
+        registers[2] = 4003;
+        registers[4] = 5;
+        assert_eq!(
+            class(0x0000000C, &registers),
+            InstructionClass::PreimageRead
+        );
+    }
+
+    #[test]
+    fn budget() {
+        let new_vm = |meter: CostMeter| {
+            let elf_bytes = include_bytes!("../../../example/bin/hello.elf");
+            InstrumentedState::new(
+                load_program(elf_bytes),
+                StaticOracle::new(b"hello world".to_vec()),
+                Vec::default(),
+                Vec::default(),
+            )
+            .with_cost_meter(meter)
+        };
+
+        // With the default table, the cost is the number of executed instructions.
+        let mut ins = new_vm(CostMeter::default());
+        ins.run(u64::MAX).unwrap();
+        assert!(ins.state.exited);
+        assert_eq!(ins.cost_meter().unwrap().used, ins.state.step);
+
+        let table = CostTable {
+            memory: 3,
+            syscall: 1_000,
+            ..Default::default()
+        };
+        let mut ins = new_vm(CostMeter::new(table).with_budget(Some(50_000)));
+        match ins.run(u64::MAX) {
+            Err(VmError::CostBudgetExhausted(CostBudgetExhausted { budget, used })) => {
+                assert_eq!(budget, 50_000);
+                assert!((50_000..51_000).contains(&used));
+            }
+            res => panic!("expected exhausted budget, got {:?}", res),
+        }
+        assert!(!ins.state.exited);
+        assert!(ins.state.step < 50_000);
+
+        // Raising the budget resumes execution.
+        let step = ins.state.step;
+        ins.cost_meter.as_mut().unwrap().budget = None;
+        ins.step(false).unwrap();
+        assert_eq!(ins.state.step, step + 1);
+    }
+}
@@ -0,0 +1,427 @@
+//! This module contains the basic block engine of the MIPS emulator. When no proof is requested,
+//! straight-line runs of instructions are translated into [Block]s of threaded code, which are
+//! executed without fetching, decoding and dispatching each instruction.
+
+use crate::{
+    icache::DecodedInstruction,
+    mips::sign_extend,
+    page::{PAGE_ADDRESS_MASK, PAGE_ADDRESS_SIZE},
+    Address, InstrumentedState, PageIndex, PreimageOracle, VmError,
+};
+use rustc_hash::FxHashMap;
+use std::{io::Write, sync::Arc};
+
+/// The maximum number of instructions within a [Block].
+const MAX_BLOCK_SIZE: usize = 256;
+
+/// The handler of a single instruction within a [Block]. Handlers behave exactly like
+/// [InstrumentedState::execute_decoded] for the instructions they are selected for.
+type Handler<O, E, P> =
+    fn(&mut InstrumentedState<O, E, P>, DecodedInstruction) -> Result<(), VmError>;
+
+/// An instruction within a [Block], paired with its [Handler].
+type Op<O, E, P> = (Handler<O, E, P>, DecodedInstruction);
+
+/// A [Block] is a straight-line run of instructions within a single page, ending after the delay
+/// slot of a branch or jump, before a syscall, or at the end of the page.
+pub(crate) struct Block<O: Write, E: Write, P: PreimageOracle> {
+    /// The instructions of the block, paired with their handlers.
+    ops: Box<[Op<O, E, P>]>,
+}
+
+/// The [BlockCache] holds the translated [Block]s, keyed by their start address.
+pub(crate) struct BlockCache<O: Write, E: Write, P: PreimageOracle> {
+    /// The translated blocks.
+    blocks: FxHashMap<Address, Arc<Block<O, E, P>>>,
+    /// The id and generation of the [crate::Memory]'s instruction cache the blocks were
+    /// translated from.
+    cache: (u64, u64),
+}
+
+impl<O, E, P> Default for BlockCache<O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    fn default() -> Self {
+        Self {
+            blocks: FxHashMap::default(),
+            cache: (0, 0),
+        }
+    }
+}
+
+impl<O, E, P> InstrumentedState<O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    /// Run the MIPS emulator without generating witnesses, until it exits or `max_steps` steps
+    /// have been performed, executing straight-line code as translated basic blocks. The final
+    /// state is identical to performing the same steps with `step(false)`.
+    ///
+    /// In multithreaded mode, the scheduler may switch threads at every step, and with a
+    /// [crate::CostMeter] every instruction must be charged, and with reverse execution or a
+    /// [crate::TraceRecorder] every step must be recorded, and with call stack tracking or
+    /// [crate::RunStats] every instruction must be seen, so this falls back to
+    /// [InstrumentedState::run].
+    ///
+    /// ### Takes
+    /// - `max_steps`: The maximum number of steps to perform.
+    ///
+    /// ### Returns
+    /// - Ok(steps): The number of steps performed.
+    /// - Err(_): A [VmError] occurred while processing an instruction step in the MIPS emulator.
+    pub fn run_blocks(&mut self, max_steps: u64) -> Result<u64, VmError> {
+        if self.state.is_multithreaded()
+            || self.cost_meter.is_some()
+            || self.history.is_some()
+            || self.trace_recorder.is_some()
+            || self.call_stack.is_some()
+            || self.stats.is_some()
+        {
+            return self.run(max_steps);
+        }
+
+        self.mem_proof_enabled = false;
+        self.last_preimage_offset = !0u32;
+
+        let start = self.state.step;
+        let end = start.saturating_add(max_steps);
+        while !self.state.exited && self.state.step < end {
+            let pc = self.state.pc;
+
+            // Blocks are only entered outside of delay slots, with an aligned program counter.
+            if pc & 0x3 != 0 || self.state.next_pc != pc + 4 {
+                self.inner_step()?;
+                continue;
+            }
+
+            // The state may have been replaced, along with its memory, since the last run.
+            let cache = &self.state.memory.instruction_cache;
+            let generation = cache.generation;
+            if self.blocks.cache != (cache.id, generation) {
+                self.blocks.blocks.clear();
+                self.blocks.cache = (cache.id, generation);
+            }
+
+            let block = match self.blocks.blocks.get(&pc) {
+                Some(block) => Arc::clone(block),
+                None => self.translate(pc)?,
+            };
+            if block.ops.is_empty() || ((end - self.state.step) as usize) < block.ops.len() {
+                self.inner_step()?;
+                continue;
+            }
+
+            for (handler, decoded) in block.ops.iter() {
+                self.state.step += 1;
+                handler(self, *decoded)?;
+
+                // A write to a code page may have changed the rest of the block.
+                if self.state.memory.instruction_cache.generation != generation {
+                    break;
+                }
+            }
+        }
+        Ok(self.state.step - start)
+    }
+
+    /// Translates the [Block] starting at the given address and caches it.
+    ///
+    /// ### Takes
+    /// - `pc`: The aligned start address of the block.
+    ///
+    /// ### Returns
+    /// - The translated [Block]. The block is empty if its first instruction must be stepped.
+    fn translate(&mut self, pc: Address) -> Result<Arc<Block<O, E, P>>, VmError> {
+        let mut ops: Vec<Op<O, E, P>> = Vec::new();
+
+        // Unmapped pages are not decoded, so writes to them would not invalidate the block.
+        if self
+            .state
+            .memory
+            .page_lookup(pc as PageIndex >> PAGE_ADDRESS_SIZE)?
+            .is_some()
+        {
+            let mut address = pc;
+            while ops.len() < MAX_BLOCK_SIZE {
+                let decoded = self.state.memory.fetch_instruction(address)?;
+                if is_syscall(decoded) {
+                    break;
+                }
+                ops.push((Self::handler(decoded), decoded));
+
+                address = address.wrapping_add(4);
+                let page_end = address as usize & PAGE_ADDRESS_MASK == 0;
+                if is_control(decoded) {
+                    // Include the delay slot if it can be executed as part of the block.
+                    if !page_end {
+                        let slot = self.state.memory.fetch_instruction(address)?;
+                        if !is_syscall(slot) && !is_control(slot) {
+                            ops.push((Self::handler(slot), slot));
+                        }
+                    }
+                    break;
+                }
+                if page_end {
+                    break;
+                }
+            }
+        }
+
+        let block = Arc::new(Block {
+            ops: ops.into_boxed_slice(),
+        });
+        self.blocks.blocks.insert(pc, Arc::clone(&block));
+        Ok(block)
+    }
+
+    /// Selects the [Handler] for a [DecodedInstruction]. Frequent instructions get a specialized
+    /// handler; all others are executed by [InstrumentedState::execute_decoded].
+    fn handler(decoded: DecodedInstruction) -> Handler<O, E, P> {
+        match (decoded.opcode, decoded.fun) {
+            (0, 0x00 | 0x02 | 0x20..=0x27 | 0x2a | 0x2b) => Self::alu_r,
+            (0x08..=0x0f, _) => Self::alu_i,
+            (0x20 | 0x23 | 0x24, _) => Self::load,
+            (0x28 | 0x2b, _) => Self::store,
+            (0x04 | 0x05, _) => Self::branch_eq,
+            (0x02 | 0x03, _) => Self::jump,
+            (0, 0x08) => Self::jump_register,
+            _ => Self::execute_decoded,
+        }
+    }
+
+    /// Writes back a value to a register and advances the program counter.
+    #[inline(always)]
+    fn retire(&mut self, reg: u8, val: u32) {
+        if reg != 0 {
+            self.state.registers[reg as usize] = val;
+        }
+        self.state.pc = self.state.next_pc;
+        self.state.next_pc += 4;
+    }
+
+    /// Handles the R-type ALU instructions `sll`, `srl`, `add(u)`, `sub(u)`, `and`, `or`, `xor`,
+    /// `nor`, `slt` and `sltu`.
+    fn alu_r(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let rs = self.state.registers[decoded.rs as usize];
+        let rt = self.state.registers[decoded.rt as usize];
+        let val = match decoded.fun {
+            0x00 => rt << ((decoded.word >> 6) & 0x1F),
+            0x02 => rt >> ((decoded.word >> 6) & 0x1F),
+            0x20 | 0x21 => rs + rt,
+            0x22 | 0x23 => rs - rt,
+            0x24 => rs & rt,
+            0x25 => rs | rt,
+            0x26 => rs ^ rt,
+            0x27 => !(rs | rt),
+            0x2a => ((rs as i32) < (rt as i32)) as u32,
+            _ => (rs < rt) as u32,
+        };
+        self.retire(decoded.rd, val);
+        Ok(())
+    }
+
+    /// Handles the I-type ALU instructions `addi(u)`, `slti(u)`, `andi`, `ori`, `xori` and `lui`.
+    fn alu_i(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let rs = self.state.registers[decoded.rs as usize];
+        let val = match decoded.opcode {
+            0x08 | 0x09 => rs + decoded.imm,
+            0x0a => ((rs as i32) < (decoded.imm as i32)) as u32,
+            0x0b => (rs < decoded.imm) as u32,
+            0x0c => rs & decoded.imm,
+            0x0d => rs | decoded.imm,
+            0x0e => rs ^ decoded.imm,
+            _ => decoded.imm << 16,
+        };
+        self.retire(decoded.rt, val);
+        Ok(())
+    }
+
+    /// Handles the `lb`, `lw` and `lbu` instructions.
+    fn load(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let address = self.state.registers[decoded.rs as usize] + decoded.imm;
+        let mem = self.state.memory.get_memory(address & 0xFFFFFFFC)?;
+        let val = match decoded.opcode {
+            0x20 => sign_extend((mem >> (24 - ((address & 0x3) << 3))) & 0xFF, 8),
+            0x23 => mem,
+            _ => (mem >> (24 - ((address & 0x3) << 3))) & 0xFF,
+        };
+        self.retire(decoded.rt, val);
+        Ok(())
+    }
+
+    /// Handles the `sb` and `sw` instructions.
+    fn store(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let address = self.state.registers[decoded.rs as usize] + decoded.imm;
+        let rt = self.state.registers[decoded.rt as usize];
+        let val = if decoded.opcode == 0x2b {
+            rt
+        } else {
+            let mem = self.state.memory.peek_memory(address & 0xFFFFFFFC)?;
+            let sl = 24 - ((address & 0x3) << 3);
+            (mem & (0xFFFFFFFF ^ (0xFF << sl))) | ((rt & 0xFF) << sl)
+        };
+        self.state.memory.set_memory(address & 0xFFFFFFFC, val)?;
+        self.retire(0, 0);
+        Ok(())
+    }
+
+    /// Handles the `beq` and `bne` instructions. Blocks never place a branch in a delay slot.
+    fn branch_eq(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let rs = self.state.registers[decoded.rs as usize];
+        let rt = self.state.registers[decoded.rt as usize];
+        let prev_pc = self.state.pc;
+        self.state.pc = self.state.next_pc;
+        if (rs == rt) == (decoded.opcode == 0x04) {
+            self.state.next_pc = prev_pc + 4 + (decoded.imm << 2);
+        } else {
+            self.state.next_pc += 4;
+        }
+        Ok(())
+    }
+
+    /// Handles the `j` and `jal` instructions. Blocks never place a jump in a delay slot.
+    fn jump(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let prev_pc = self.state.pc;
+        let target = self.state.next_pc & 0xF0000000 | ((decoded.word & 0x03FFFFFF) << 2);
+        self.state.pc = self.state.next_pc;
+        self.state.next_pc = target;
+        if decoded.opcode == 0x03 {
+            self.state.registers[31] = prev_pc + 8;
+        }
+        Ok(())
+    }
+
+    /// Handles the `jr` instruction. Blocks never place a jump in a delay slot.
+    fn jump_register(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        self.state.pc = self.state.next_pc;
+        self.state.next_pc = self.state.registers[decoded.rs as usize];
+        Ok(())
+    }
+}
+
+/// Returns `true` if the instruction is a `syscall`, which is always stepped.
+#[inline(always)]
+fn is_syscall(decoded: DecodedInstruction) -> bool {
+    decoded.opcode == 0 && decoded.fun == 0x0c
+}
+
+/// Returns `true` if the instruction is a branch or a jump, which ends a [Block] after its delay
+/// slot.
+#[inline(always)]
+fn is_control(decoded: DecodedInstruction) -> bool {
+    (1..=7).contains(&decoded.opcode) || (decoded.opcode == 0 && matches!(decoded.fun, 8 | 9))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_utils::{load_program, program, StaticOracle},
+        FaultKind, InstrumentedState,
+    };
+
+    #[test]
+    fn run_blocks() {
+        let state = load_program(include_bytes!("../../../../example/bin/hello.elf"));
+        let new_vm = || {
+            InstrumentedState::new(
+                state.clone(),
+                StaticOracle::new(b"hello world".to_vec()),
+                Vec::default(),
+                Vec::default(),
+            )
+        };
+        let mut stepped = new_vm();
+        let mut blocks = new_vm();
+
+        // Uneven chunks stop within blocks.
+        for chunk in [1, 7, 1_000, 33_333] {
+            for _ in 0..chunk {
+                stepped.step(false).unwrap();
+            }
+            assert_eq!(blocks.run_blocks(chunk).unwrap(), chunk);
+            assert_eq!(blocks.state.pc, stepped.state.pc);
+            assert_eq!(
+                blocks.state.encode_witness().unwrap(),
+                stepped.state.encode_witness().unwrap()
+            );
+        }
+
+        while !stepped.state.exited {
+            stepped.step(false).unwrap();
+        }
+        blocks.run_blocks(u64::MAX).unwrap();
+        assert!(blocks.state.exited);
+        assert_eq!(blocks.std_out(), stepped.std_out());
+        assert_eq!(
+            blocks.state.encode_witness().unwrap(),
+            stepped.state.encode_witness().unwrap()
+        );
+    }
+
+    #[test]
+    fn self_modifying() {
+        let mut ins = program(&[
+            0x3C082409, // lui $t0, 0x2409
+            0x35080002, // ori $t0, $t0, 2
+            0xAC080010, // sw $t0, 16($zero)
+            0,          // nop
+            0x24090001, // addiu $t1, $zero, 1
+            0x08000010, // j 0x40
+            0,          // nop
+        ]);
+        assert_eq!(ins.run_blocks(7).unwrap(), 7);
+        assert_eq!(ins.state.registers[9], 2);
+        assert_eq!(ins.state.pc, 0x40);
+    }
+
+    #[test]
+    fn replaced_state() {
+        let mut ins = program(&[
+            0x24090001, // addiu $t1, $zero, 1
+            0x08000010, // j 0x40
+            0,          // nop
+        ]);
+        assert_eq!(ins.run_blocks(3).unwrap(), 3);
+        assert_eq!(ins.state.registers[9], 1);
+
+        // Blocks translated from the memory of the previous state are not reused.
+        ins.state = program(&[
+            0x24090002, // addiu $t1, $zero, 2
+            0x08000010, // j 0x40
+            0,          // nop
+        ])
+        .state;
+        assert_eq!(ins.run_blocks(3).unwrap(), 3);
+        assert_eq!(ins.state.registers[9], 2);
+    }
+
+    #[test]
+    fn delay_slot() {
+        let mut ins = program(&[
+            0x24080003, // addiu $t0, $zero, 3
+            0x2508FFFF, // addiu $t0, $t0, -1
+            0x1500FFFE, // bne $t0, $zero, -2
+            0x25290001, // addiu $t1, $t1, 1
+            0x08000040, // j 0x100
+            0x08000080, // j 0x200
+        ]);
+        assert_eq!(ins.run_blocks(11).unwrap(), 11);
+        assert_eq!(ins.state.registers[9], 3);
+        assert_eq!(ins.state.pc, 0x14);
+        assert_eq!(ins.state.next_pc, 0x100);
+
+        // A jump in the delay slot faults at the same step as it does when stepping.
+        match ins.run_blocks(1) {
+            Err(crate::VmError::Fault { step, kind, .. }) => {
+                assert_eq!(step, 11);
+                assert_eq!(kind, FaultKind::JumpInDelaySlot);
+            }
+            res => panic!("expected fault, got {:?}", res),
+        }
+    }
+}
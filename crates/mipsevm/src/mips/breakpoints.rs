@@ -0,0 +1,417 @@
+//! This module contains the breakpoints and watchpoints of the [InstrumentedState], and the
+//! [InstrumentedState::run_until_break] helper that stops on them.
+
+use crate::{Address, InstrumentedState, PreimageOracle, VmError};
+use std::{io::Write, ops::Range};
+
+/// A comparison of a register value against a constant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A [RegisterCondition] compares the value of a register against a constant. Values are
+/// compared as unsigned integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterCondition {
+    /// The index of the register.
+    pub register: usize,
+    /// The [Comparison] to apply.
+    pub comparison: Comparison,
+    /// The constant to compare the register value against.
+    pub value: u32,
+}
+
+impl RegisterCondition {
+    /// Creates a new [RegisterCondition].
+    pub fn new(register: usize, comparison: Comparison, value: u32) -> Self {
+        Self {
+            register,
+            comparison,
+            value,
+        }
+    }
+
+    /// Returns `true` if the condition holds for the given registers.
+    pub fn holds(&self, registers: &[u32; 32]) -> bool {
+        let register = registers[self.register & 0x1F];
+        match self.comparison {
+            Comparison::Eq => register == self.value,
+            Comparison::Ne => register != self.value,
+            Comparison::Lt => register < self.value,
+            Comparison::Le => register <= self.value,
+            Comparison::Gt => register > self.value,
+            Comparison::Ge => register >= self.value,
+        }
+    }
+}
+
+/// A [Breakpoint] stops execution before the instruction at its program counter is executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// The program counter to stop at.
+    pub pc: Address,
+    /// The condition that must hold for the breakpoint to fire, if any.
+    pub condition: Option<RegisterCondition>,
+}
+
+/// The kind of memory access a [Watchpoint] fires on, or that fired it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes. Never reported as the access of a [WatchpointHit].
+    Access,
+}
+
+impl WatchKind {
+    /// Returns `true` if a watchpoint of this kind fires on the given access.
+    #[inline(always)]
+    fn matches(self, access: WatchKind) -> bool {
+        self == WatchKind::Access || self == access
+    }
+}
+
+/// A [Watchpoint] stops execution after an instruction accesses an address within its range.
+/// Memory is accessed a word at a time, so the watchpoint fires for any access to a word that
+/// overlaps the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// The first watched address.
+    pub start: Address,
+    /// The end of the watched range, exclusive.
+    pub end: Address,
+    /// The kind of access that fires the watchpoint.
+    pub kind: WatchKind,
+}
+
+/// A [WatchpointHit] describes the access that fired a [Watchpoint].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchpointHit {
+    /// The id of the watchpoint.
+    pub id: usize,
+    /// The address of the accessed word.
+    pub address: Address,
+    /// The access, either [WatchKind::Read] or [WatchKind::Write].
+    pub access: WatchKind,
+}
+
+/// The reason [InstrumentedState::run_until_break] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The [Breakpoint] with the given id fired.
+    Breakpoint(usize),
+    /// A [Watchpoint] fired.
+    Watchpoint(WatchpointHit),
+    /// The guest program exited.
+    Exited,
+    /// The maximum number of steps was performed.
+    StepLimit,
+}
+
+/// A [Stop] describes where [InstrumentedState::run_until_break] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stop {
+    /// The reason execution stopped.
+    pub reason: StopReason,
+    /// The step the state is at.
+    pub step: u64,
+    /// The program counter the state is at.
+    pub pc: Address,
+}
+
+/// The [Breakpoints] registered on an [InstrumentedState].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Breakpoints {
+    /// The registered breakpoints, with their ids.
+    points: Vec<(usize, Breakpoint)>,
+    /// The id of the next breakpoint.
+    next_id: usize,
+}
+
+impl Breakpoints {
+    /// Returns the id of the first breakpoint that fires at the given program counter.
+    #[inline(always)]
+    fn hit(&self, pc: Address, registers: &[u32; 32]) -> Option<usize> {
+        self.points
+            .iter()
+            .find(|(_, bp)| bp.pc == pc && bp.condition.is_none_or(|c| c.holds(registers)))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// The [Watchpoints] registered on a [crate::Memory], and the first hit since they were last
+/// checked.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Watchpoints {
+    /// The registered watchpoints, with their ids.
+    points: Vec<(usize, Watchpoint)>,
+    /// The id of the next watchpoint.
+    next_id: usize,
+    /// The first hit since the last check.
+    pub(crate) hit: Option<WatchpointHit>,
+}
+
+impl Watchpoints {
+    /// Returns `true` if no watchpoints are registered.
+    #[inline(always)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Records a hit if an access to the word at the given [Address] fires a watchpoint.
+    ///
+    /// ### Takes
+    /// - `address`: The aligned address of the accessed word.
+    /// - `access`: The access, either [WatchKind::Read] or [WatchKind::Write].
+    pub(crate) fn check(&mut self, address: Address, access: WatchKind) {
+        if self.hit.is_some() {
+            return;
+        }
+
+        let word = address as u64..address as u64 + 4;
+        self.hit = self
+            .points
+            .iter()
+            .find(|(_, wp)| {
+                wp.kind.matches(access)
+                    && word.start < wp.end as u64
+                    && (wp.start as u64) < word.end
+            })
+            .map(|(id, _)| WatchpointHit {
+                id: *id,
+                address,
+                access,
+            });
+    }
+}
+
+impl<O, E, P> InstrumentedState<O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    /// Registers a [Breakpoint] at the given program counter.
+    ///
+    /// ### Takes
+    /// - `pc`: The program counter to stop at.
+    /// - `condition`: The [RegisterCondition] that must hold for the breakpoint to fire, if any.
+    ///
+    /// ### Returns
+    /// - The id of the breakpoint.
+    pub fn add_breakpoint(&mut self, pc: Address, condition: Option<RegisterCondition>) -> usize {
+        let id = self.breakpoints.next_id;
+        self.breakpoints.next_id += 1;
+        self.breakpoints
+            .points
+            .push((id, Breakpoint { pc, condition }));
+        id
+    }
+
+    /// Removes the [Breakpoint] with the given id.
+    ///
+    /// ### Returns
+    /// - `true` if the breakpoint existed.
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.points.len();
+        self.breakpoints.points.retain(|(bp_id, _)| *bp_id != id);
+        self.breakpoints.points.len() != len
+    }
+
+    /// Registers a [Watchpoint] on the given address range.
+    ///
+    /// ### Takes
+    /// - `range`: The watched address range.
+    /// - `kind`: The kind of access that fires the watchpoint.
+    ///
+    /// ### Returns
+    /// - The id of the watchpoint.
+    pub fn add_watchpoint(&mut self, range: Range<Address>, kind: WatchKind) -> usize {
+        let watchpoints = &mut self.state.memory.watchpoints;
+        let id = watchpoints.next_id;
+        watchpoints.next_id += 1;
+        watchpoints.points.push((
+            id,
+            Watchpoint {
+                start: range.start,
+                end: range.end,
+                kind,
+            },
+        ));
+        id
+    }
+
+    /// Removes the [Watchpoint] with the given id.
+    ///
+    /// ### Returns
+    /// - `true` if the watchpoint existed.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let points = &mut self.state.memory.watchpoints.points;
+        let len = points.len();
+        points.retain(|(wp_id, _)| *wp_id != id);
+        points.len() != len
+    }
+
+    /// Run the MIPS emulator without generating witnesses, until a [Breakpoint] or [Watchpoint]
+    /// fires, the guest exits, or `max_steps` steps have been performed.
+    ///
+    /// Breakpoints stop before the instruction at their program counter is executed. A run that
+    /// starts at a breakpoint executes that instruction before it can stop on it again.
+    /// Watchpoints stop after the instruction that accessed the watched memory.
+    ///
+    /// ### Takes
+    /// - `max_steps`: The maximum number of steps to perform.
+    ///
+    /// ### Returns
+    /// - Ok(stop): The [Stop] describing where and why execution stopped.
+    /// - Err(_): A [VmError] occurred while processing an instruction step in the MIPS emulator.
+    pub fn run_until_break(&mut self, max_steps: u64) -> Result<Stop, VmError> {
+        self.mem_proof_enabled = false;
+        self.last_preimage_offset = !0u32;
+        self.state.memory.watchpoints.hit = None;
+
+        let end = self.state.step.saturating_add(max_steps);
+        let mut first = true;
+        let reason = loop {
+            if self.state.exited {
+                break StopReason::Exited;
+            }
+            if !first {
+                if let Some(id) = self.breakpoints.hit(self.state.pc, &self.state.registers) {
+                    break StopReason::Breakpoint(id);
+                }
+            }
+            if self.state.step >= end {
+                break StopReason::StepLimit;
+            }
+            first = false;
+
+            self.inner_step()?;
+            self.state.save_thread_context();
+
+            if let Some(hit) = self.state.memory.watchpoints.hit.take() {
+                break StopReason::Watchpoint(hit);
+            }
+        };
+
+        Ok(Stop {
+            reason,
+            step: self.state.step,
+            pc: self.state.pc,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Comparison, RegisterCondition, StopReason, WatchKind, WatchpointHit};
+    use crate::{
+        test_utils::{program, StaticOracle},
+        InstrumentedState,
+    };
+    use std::io;
+
+    /// Counts `$t0` down from 3, storing it to `0x100` and loading `0x200` on every iteration.
+    fn countdown() -> InstrumentedState<io::Sink, io::Sink, StaticOracle> {
+        program(&[
+            0x24080003, // addiu $t0, $zero, 3
+            0x2508FFFF, // addiu $t0, $t0, -1
+            0xAC080100, // sw $t0, 0x100($zero)
+            0x8C090200, // lw $t1, 0x200($zero)
+            0x1500FFFC, // bne $t0, $zero, -4
+            0,          // nop
+        ])
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut ins = countdown();
+        let id = ins.add_breakpoint(0x10, None);
+
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(stop.reason, StopReason::Breakpoint(id));
+        assert_eq!((stop.step, stop.pc), (4, 0x10));
+
+        // Continuing from a breakpoint does not stop on it again right away.
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(stop.reason, StopReason::Breakpoint(id));
+        assert_eq!((stop.step, stop.pc), (9, 0x10));
+
+        assert!(ins.remove_breakpoint(id));
+        assert!(!ins.remove_breakpoint(id));
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(stop.reason, StopReason::StepLimit);
+        assert_eq!(stop.step, 109);
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let mut ins = countdown();
+        ins.add_breakpoint(0x10, Some(RegisterCondition::new(8, Comparison::Eq, 5)));
+        let id = ins.add_breakpoint(0x10, Some(RegisterCondition::new(8, Comparison::Le, 1)));
+
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(stop.reason, StopReason::Breakpoint(id));
+        assert_eq!(ins.state.registers[8], 1);
+        assert_eq!(stop.step, 9);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut ins = countdown();
+        let write = ins.add_watchpoint(0x100..0x101, WatchKind::Write);
+        let read = ins.add_watchpoint(0x1FC..0x204, WatchKind::Read);
+
+        // The store stops after it has been executed.
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(
+            stop.reason,
+            StopReason::Watchpoint(WatchpointHit {
+                id: write,
+                address: 0x100,
+                access: WatchKind::Write
+            })
+        );
+        assert_eq!((stop.step, stop.pc), (3, 0xC));
+        assert_eq!(ins.state.memory.get_memory(0x100).unwrap(), 2);
+
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(
+            stop.reason,
+            StopReason::Watchpoint(WatchpointHit {
+                id: read,
+                address: 0x200,
+                access: WatchKind::Read
+            })
+        );
+        assert_eq!(stop.step, 4);
+
+        // Stores do not fire read watchpoints, even though they load the word they write to.
+        assert!(ins.remove_watchpoint(write));
+        assert!(ins.remove_watchpoint(read));
+        let id = ins.add_watchpoint(0x100..0x104, WatchKind::Read);
+        let stop = ins.run_until_break(100).unwrap();
+        assert_eq!(stop.reason, StopReason::StepLimit);
+        assert_eq!(stop.step, 104);
+
+        // Access watchpoints fire on both reads and writes.
+        assert!(ins.remove_watchpoint(id));
+        let id = ins.add_watchpoint(0x200..0x204, WatchKind::Access);
+        ins.state.memory.set_memory(0x200, 7).unwrap();
+        assert_eq!(ins.state.memory.get_memory(0x200).unwrap(), 7);
+        assert_eq!(
+            ins.state.memory.watchpoints.hit,
+            Some(WatchpointHit {
+                id,
+                address: 0x200,
+                access: WatchKind::Write
+            })
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! This module contains the [StepDelta], which records the parts of the [crate::State] that a
+//! single instruction step changed, so that the step can be undone.
+
+use crate::{Address, Frame, InstrumentedState, PreimageOracle, ThreadState, VmError};
+use std::io::Write;
+
+/// A [StepDelta] holds the values that a single step overwrote. Applying it with
+/// [InstrumentedState::undo] restores the [crate::State] to what it was before the step.
+///
+/// Only the [crate::State] and the tracked call stack are restored. Output that was written,
+/// hints that were sent, stdin that was consumed and the cost that was charged are not. With
+/// reverse execution enabled, undone steps are executed again rather than replayed, and stdin
+/// reads are served from the history.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StepDelta {
+    /// The step before the step was performed.
+    pub step: u64,
+    /// The previous program counter.
+    pub pc: Address,
+    /// The previous next program counter.
+    pub next_pc: Address,
+    /// The previous `lo` register.
+    pub lo: u32,
+    /// The previous `hi` register.
+    pub hi: u32,
+    /// The previous heap pointer.
+    pub heap: u32,
+    /// The previous exited status.
+    pub exited: bool,
+    /// The previous exit code.
+    pub exit_code: u8,
+    /// The previous preimage key.
+    pub preimage_key: [u8; 32],
+    /// The previous preimage offset.
+    pub preimage_offset: u32,
+    /// The index and previous value of the registers that changed.
+    pub registers: Vec<(u8, u32)>,
+    /// The address and previous value of the memory words that were written, in the order they
+    /// were written.
+    pub memory: Vec<(Address, u32)>,
+    /// The previous last hint, if it changed.
+    pub last_hint: Option<Vec<u8>>,
+    /// The parts of the thread stacks and scheduler state that the step overwrote, in
+    /// multithreaded mode.
+    pub threads: Option<ThreadsDelta>,
+    /// The ID of the thread that stepped and its previous call stack, if the call stack is
+    /// tracked and changed.
+    pub call_stack: Option<(u32, Vec<Frame>)>,
+    /// The number of stdin reads performed before the step, if reverse execution is enabled.
+    stdin_reads: Option<usize>,
+}
+
+/// A [ThreadsDelta] holds the parts of the [crate::Threads] that a single step overwrote. A step
+/// only changes the top of each thread stack, by updating, pushing, popping or preempting the
+/// current thread.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ThreadsDelta {
+    /// The previous length and top thread of the left thread stack, if it changed.
+    pub left_thread_stack: Option<(usize, Option<ThreadState>)>,
+    /// The previous length and top thread of the right thread stack, if it changed.
+    pub right_thread_stack: Option<(usize, Option<ThreadState>)>,
+    /// The previous traversal direction.
+    pub traverse_right: bool,
+    /// The previous identifier of the next thread.
+    pub next_thread_id: u32,
+    /// The previous number of steps since the last context switch.
+    pub steps_since_last_context_switch: u64,
+    /// The previous wakeup address.
+    pub wakeup: Address,
+}
+
+impl ThreadsDelta {
+    /// Returns the length and top thread of a thread stack.
+    fn top(stack: &[ThreadState]) -> (usize, Option<ThreadState>) {
+        (stack.len(), stack.last().cloned())
+    }
+
+    /// Forgets the previous top of a thread stack if the step did not change it.
+    fn retain_changed(top: &mut Option<(usize, Option<ThreadState>)>, stack: &[ThreadState]) {
+        if matches!(top, Some((len, thread)) if stack.len() == *len && stack.last() == thread.as_ref())
+        {
+            *top = None;
+        }
+    }
+
+    /// Restores the previous top of a thread stack, if the step changed it.
+    fn restore(top: Option<(usize, Option<ThreadState>)>, stack: &mut Vec<ThreadState>) {
+        if let Some((len, thread)) = top {
+            stack.truncate(len.saturating_sub(1));
+            stack.extend(thread);
+        }
+    }
+}
+
+impl<O, E, P> InstrumentedState<O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    /// Step the MIPS emulator forward one instruction without generating a witness, recording
+    /// the values the step overwrote.
+    ///
+    /// ### Returns
+    /// - Ok(delta): The [StepDelta] that undoes the step.
+    /// - Err(_): A [VmError] occurred while processing the instruction step in the MIPS emulator.
+    pub fn step_delta(&mut self) -> Result<StepDelta, VmError> {
+        let state = &self.state;
+        let mut delta = StepDelta {
+            step: state.step,
+            pc: state.pc,
+            next_pc: state.next_pc,
+            lo: state.lo,
+            hi: state.hi,
+            heap: state.heap,
+            exited: state.exited,
+            exit_code: state.exit_code,
+            preimage_key: state.preimage_key,
+            preimage_offset: state.preimage_offset,
+            threads: state.threads.as_ref().map(|threads| ThreadsDelta {
+                left_thread_stack: Some(ThreadsDelta::top(&threads.left_thread_stack)),
+                right_thread_stack: Some(ThreadsDelta::top(&threads.right_thread_stack)),
+                traverse_right: threads.traverse_right,
+                next_thread_id: threads.next_thread_id,
+                steps_since_last_context_switch: threads.steps_since_last_context_switch,
+                wakeup: threads.wakeup,
+            }),
+            stdin_reads: self.history.as_ref().map(|history| history.stdin_cursor()),
+            ..Default::default()
+        };
+        let registers = state.registers;
+        let last_hint = state.last_hint.clone();
+        let thread_id = state.threads.as_ref().and_then(|t| t.current());
+        let thread_id = thread_id.map_or(0, |t| t.thread_id);
+        let frames = self
+            .call_stack
+            .as_ref()
+            .map(|stack| stack.frames(thread_id).to_vec());
+
+        // Log the memory writes of the step, keeping the log of a trace recorder, if any.
+        let log = self.state.memory.write_log.replace(Vec::default());
+        let result = self.step(false);
+        let writes = std::mem::replace(&mut self.state.memory.write_log, log);
+        result?;
+
+        delta.registers = (0..32)
+            .filter(|i| registers[*i] != self.state.registers[*i])
+            .map(|i| (i as u8, registers[i]))
+            .collect();
+        delta.memory = writes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(address, previous, _)| (address, previous))
+            .collect();
+        if last_hint != self.state.last_hint {
+            delta.last_hint = Some(last_hint);
+        }
+        if let (Some(previous), Some(threads)) = (&mut delta.threads, &self.state.threads) {
+            ThreadsDelta::retain_changed(
+                &mut previous.left_thread_stack,
+                &threads.left_thread_stack,
+            );
+            ThreadsDelta::retain_changed(
+                &mut previous.right_thread_stack,
+                &threads.right_thread_stack,
+            );
+        }
+        if let (Some(stack), Some(frames)) = (&self.call_stack, frames) {
+            if stack.frames(thread_id) != frames.as_slice() {
+                delta.call_stack = Some((thread_id, frames));
+            }
+        }
+        Ok(delta)
+    }
+
+    /// Undoes a step, restoring the values recorded in its [StepDelta]. Deltas must be undone in
+    /// the reverse order of the steps that returned them.
+    ///
+    /// ### Takes
+    /// - `delta`: The [StepDelta] of the most recent step.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the step was undone successfully.
+    pub fn undo(&mut self, delta: StepDelta) -> Result<(), VmError> {
+        for (address, previous) in delta.memory.into_iter().rev() {
+            self.state.memory.set_memory(address, previous)?;
+        }
+        for (register, previous) in delta.registers {
+            self.state.registers[register as usize] = previous;
+        }
+        if let Some(last_hint) = delta.last_hint {
+            self.state.last_hint = last_hint;
+        }
+        if let (Some(stack), Some((thread_id, frames))) = (&mut self.call_stack, delta.call_stack) {
+            stack.set_frames(thread_id, frames);
+        }
+
+        let state = &mut self.state;
+        state.step = delta.step;
+        state.pc = delta.pc;
+        state.next_pc = delta.next_pc;
+        state.lo = delta.lo;
+        state.hi = delta.hi;
+        state.heap = delta.heap;
+        state.exited = delta.exited;
+        state.exit_code = delta.exit_code;
+        state.preimage_key = delta.preimage_key;
+        state.preimage_offset = delta.preimage_offset;
+        if let (Some(previous), Some(threads)) = (delta.threads, &mut state.threads) {
+            ThreadsDelta::restore(previous.left_thread_stack, &mut threads.left_thread_stack);
+            ThreadsDelta::restore(previous.right_thread_stack, &mut threads.right_thread_stack);
+            threads.traverse_right = previous.traverse_right;
+            threads.next_thread_id = previous.next_thread_id;
+            threads.steps_since_last_context_switch = previous.steps_since_last_context_switch;
+            threads.wakeup = previous.wakeup;
+        }
+
+        // The step is executed again, rather than replayed, when it is performed next.
+        if let (Some(history), Some(stdin_reads)) = (&mut self.history, delta.stdin_reads) {
+            history.rewind(delta.step, stdin_reads);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        load_elf, patch,
+        test_utils::{load_program, program, ClaimTestOracle, StaticOracle},
+        InstrumentedState, State,
+    };
+
+    fn new_vm() -> InstrumentedState<Vec<u8>, Vec<u8>, ClaimTestOracle> {
+        let elf_bytes = include_bytes!("../../../../example/bin/claim.elf");
+        InstrumentedState::new(
+            load_program(elf_bytes),
+            ClaimTestOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        )
+        .with_call_stack(true)
+    }
+
+    /// Returns the registers of a [State] and its state hash.
+    fn snapshot(state: &mut State) -> ([u32; 32], u32, [u8; 32]) {
+        (state.registers, state.pc, state.state_hash().unwrap())
+    }
+
+    #[test]
+    fn undo() {
+        let mut ins = new_vm();
+
+        let mut deltas = Vec::new();
+        while !ins.state.exited {
+            deltas.push(ins.step_delta().unwrap());
+        }
+        assert_eq!(deltas.len() as u64, ins.state.step);
+        assert!(deltas.iter().any(|delta| delta.preimage_key != [0; 32]));
+        assert!(deltas.iter().any(|delta| delta.call_stack.is_some()));
+
+        // Undo the steps and compare against a reference VM at a few points along the way.
+        let end = ins.state.step;
+        for target in [end, end - 1, end / 2, 0] {
+            while ins.state.step > target {
+                ins.undo(deltas.pop().unwrap()).unwrap();
+            }
+            let mut reference = new_vm();
+            reference.run(target).unwrap();
+            assert_eq!(snapshot(&mut ins.state), snapshot(&mut reference.state));
+            assert_eq!(ins.call_stack(), reference.call_stack());
+        }
+    }
+
+    #[test]
+    fn undo_threaded() {
+        let new_vm = || {
+            let elf_bytes = include_bytes!("../../../../example/bin/hello.elf");
+            let mut state = load_elf(elf_bytes).unwrap();
+            patch::patch_stack(&mut state).unwrap();
+            state.enable_threading();
+            InstrumentedState::new(
+                state,
+                StaticOracle::default(),
+                Vec::default(),
+                Vec::default(),
+            )
+        };
+        let mut ins = new_vm();
+
+        let mut deltas = Vec::new();
+        while !ins.state.exited {
+            deltas.push(ins.step_delta().unwrap());
+        }
+        assert!(ins.state.threads.as_ref().unwrap().next_thread_id > 1);
+
+        let end = ins.state.step;
+        for target in [end / 2, 0] {
+            while ins.state.step > target {
+                ins.undo(deltas.pop().unwrap()).unwrap();
+            }
+            let mut reference = new_vm();
+            reference.run(target).unwrap();
+            assert_eq!(snapshot(&mut ins.state), snapshot(&mut reference.state));
+            assert_eq!(ins.state.threads, reference.state.threads);
+        }
+    }
+
+    #[test]
+    fn undo_history() {
+        let state = program(&[
+            0x24020FA3, // addiu $v0, $zero, 4003 (read)
+            0x24040000, // addiu $a0, $zero, 0
+            0x24050100, // addiu $a1, $zero, 0x100
+            0x24060004, // addiu $a2, $zero, 4
+            0x0000000C, // syscall
+            0x24020FA4, // addiu $v0, $zero, 4004 (write)
+            0x24040001, // addiu $a0, $zero, 1
+            0x0000000C, // syscall
+        ])
+        .state;
+        let mut ins = InstrumentedState::new(
+            state,
+            StaticOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        )
+        .with_stdin(&b"abcdefgh"[..])
+        .with_checkpoints(100);
+
+        let deltas = (0..8)
+            .map(|_| ins.step_delta().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ins.std_out(), b"abcd");
+        for delta in deltas.into_iter().rev() {
+            ins.undo(delta).unwrap();
+        }
+        assert_eq!(ins.state.memory.get_memory(0x100).unwrap(), 0);
+
+        // The undone steps are executed again rather than replayed, and read the same stdin.
+        ins.run(8).unwrap();
+        assert_eq!(ins.std_out(), b"abcdabcd");
+    }
+
+    #[test]
+    fn delta() {
+        let mut ins = program(&[
+            0xAC080100, // sw $t0, 0x100($zero)
+        ]);
+        ins.state.memory.set_memory(0x100, 0xAABBCCDD).unwrap();
+        ins.state.registers[8] = 7;
+        let delta = ins.step_delta().unwrap();
+        assert_eq!(delta.memory, vec![(0x100, 0xAABBCCDD)]);
+        assert!(delta.registers.is_empty());
+        assert_eq!((delta.step, delta.pc, delta.next_pc), (0, 0, 4));
+
+        ins.undo(delta).unwrap();
+        assert_eq!(ins.state.memory.get_memory(0x100).unwrap(), 0xAABBCCDD);
+        assert_eq!((ins.state.step, ins.state.pc, ins.state.next_pc), (0, 0, 4));
+    }
+}
@@ -0,0 +1,490 @@
+//! This module contains the [History] of an [InstrumentedState], which enables reverse
+//! execution through periodic [State] checkpoints and deterministic replay.
+
+use crate::{CallStack, InstrumentedState, PreimageOracle, State, VmError};
+use rustc_hash::FxHashMap;
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    mem,
+};
+
+/// A [Checkpoint] is a copy of the [State] at a given step, which shares the memory pages that
+/// have not been written to since.
+#[derive(Debug)]
+struct Checkpoint {
+    /// The [State] at the checkpoint.
+    state: State,
+    /// The number of stdin reads performed before the checkpoint.
+    stdin_reads: usize,
+    /// The [CallStack] at the checkpoint, if it is tracked.
+    call_stack: Option<CallStack>,
+}
+
+/// The [History] of an [InstrumentedState] holds its checkpoints, along with the inputs that
+/// were consumed since the first of them, so that any step after the first checkpoint can be
+/// reconstructed by replaying from the nearest checkpoint before it.
+///
+/// Steps that were already executed once are replayed: their preimages and stdin reads are
+/// served from the history, and their hints and writes to stdout and stderr are dropped.
+#[derive(Debug)]
+pub(crate) struct History {
+    /// The number of steps between checkpoints.
+    interval: u64,
+    /// The checkpoints, by step.
+    checkpoints: BTreeMap<u64, Checkpoint>,
+    /// The preimages served by the [PreimageOracle], by key.
+    preimages: FxHashMap<[u8; 32], Vec<u8>>,
+    /// The data returned by each stdin read, in order.
+    stdin_reads: Vec<Vec<u8>>,
+    /// The number of stdin reads performed by the current [State].
+    stdin_cursor: usize,
+    /// The step after the furthest step that was executed.
+    frontier: u64,
+    /// Whether or not the current step is being replayed.
+    pub(crate) replaying: bool,
+}
+
+impl History {
+    /// Records the start of a step, taking a checkpoint if the step is executed for the first
+    /// time and falls on the checkpoint interval.
+    ///
+    /// ### Takes
+    /// - `state`: The [State] before the step is executed.
+    /// - `call_stack`: The [CallStack] before the step is executed, if it is tracked.
+    #[inline(always)]
+    pub(crate) fn record(&mut self, state: &State, call_stack: Option<&CallStack>) {
+        self.replaying = self.is_replay(state);
+        if !self.replaying {
+            self.frontier = state.step + 1;
+            if state.step % self.interval == 0 {
+                self.checkpoint(state, call_stack);
+            }
+        }
+    }
+
+    /// Returns `true` if the next step of the given [State] was already executed once, and is
+    /// replayed.
+    #[inline(always)]
+    pub(crate) fn is_replay(&self, state: &State) -> bool {
+        state.step < self.frontier
+    }
+
+    /// Returns the number of stdin reads performed by the current [State].
+    pub(crate) fn stdin_cursor(&self) -> usize {
+        self.stdin_cursor
+    }
+
+    /// Rewinds the history to an undone step, so that the step and the steps after it are
+    /// executed again rather than replayed.
+    ///
+    /// ### Takes
+    /// - `step`: The step that was undone.
+    /// - `stdin_reads`: The number of stdin reads performed before the step.
+    pub(crate) fn rewind(&mut self, step: u64, stdin_reads: usize) {
+        self.frontier = self.frontier.min(step);
+        self.stdin_cursor = stdin_reads;
+    }
+
+    /// Takes a checkpoint of the given [State] and [CallStack], unless one exists at its step.
+    fn checkpoint(&mut self, state: &State, call_stack: Option<&CallStack>) {
+        let stdin_reads = self.stdin_cursor;
+        self.checkpoints
+            .entry(state.step)
+            .or_insert_with(|| Checkpoint {
+                state: state.clone(),
+                stdin_reads,
+                call_stack: call_stack.cloned(),
+            });
+    }
+
+    /// Returns the preimage for the given key, fetching it from the [PreimageOracle] only if it
+    /// has not been served before.
+    ///
+    /// ### Takes
+    /// - `key`: The key of the preimage.
+    /// - `oracle`: The [PreimageOracle] to fetch unknown preimages from.
+    ///
+    /// ### Returns
+    /// - The preimage.
+    pub(crate) fn preimage<P: PreimageOracle>(
+        &mut self,
+        key: [u8; 32],
+        oracle: &mut P,
+    ) -> Result<Vec<u8>, VmError> {
+        if let Some(preimage) = self.preimages.get(&key) {
+            return Ok(preimage.clone());
+        }
+        let preimage = oracle.get(key).map_err(VmError::Oracle)?;
+        self.preimages.insert(key, preimage.clone());
+        Ok(preimage)
+    }
+
+    /// Reads from stdin, serving reads that were already performed once from the history.
+    ///
+    /// ### Takes
+    /// - `std_in`: The stdin source to read from.
+    /// - `buf`: The buffer to read into.
+    ///
+    /// ### Returns
+    /// - The number of bytes read.
+    pub(crate) fn read_stdin(
+        &mut self,
+        std_in: &mut dyn Read,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let n = match self.stdin_reads.get(self.stdin_cursor) {
+            Some(data) => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                n
+            }
+            None => {
+                let n = std_in.read(buf)?;
+                self.stdin_reads.push(buf[..n].to_vec());
+                n
+            }
+        };
+        self.stdin_cursor += 1;
+        Ok(n)
+    }
+}
+
+impl<O, E, P> InstrumentedState<O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    /// Enables reverse execution, taking a checkpoint of the current [State] and then one every
+    /// `interval` steps. Checkpoints share unmodified memory pages with the [State], so the
+    /// memory cost of each checkpoint grows with the pages written since it was taken. Shorter
+    /// intervals make stepping back faster at the cost of memory.
+    ///
+    /// Replay assumes that execution is deterministic: the [State] must not be modified between
+    /// steps, and a [crate::SyscallHandler], if any, must not have side effects.
+    ///
+    /// ### Takes
+    /// - `interval`: The number of steps between checkpoints.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with reverse execution enabled.
+    pub fn with_checkpoints(mut self, interval: u64) -> Self {
+        let mut history = History {
+            interval: interval.max(1),
+            checkpoints: BTreeMap::default(),
+            preimages: FxHashMap::default(),
+            stdin_reads: Vec::default(),
+            stdin_cursor: 0,
+            frontier: self.state.step,
+            replaying: false,
+        };
+        history.checkpoint(&self.state, self.call_stack.as_ref());
+        self.history = Some(history);
+        self
+    }
+
+    /// Returns the steps at which checkpoints were taken.
+    pub fn checkpoints(&self) -> Vec<u64> {
+        self.history
+            .as_ref()
+            .map(|history| history.checkpoints.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Steps back `n` steps, or to the first checkpoint if it is fewer than `n` steps back.
+    ///
+    /// ### Takes
+    /// - `n`: The number of steps to step back.
+    ///
+    /// ### Returns
+    /// - Ok(steps): The number of steps stepped back. Always 0 if reverse execution is not
+    ///   enabled.
+    /// - Err(_): A [VmError] occurred while replaying from the checkpoint.
+    pub fn step_back(&mut self, n: u64) -> Result<u64, VmError> {
+        let Some(first) = self
+            .history
+            .as_ref()
+            .and_then(|history| history.checkpoints.keys().next().copied())
+        else {
+            return Ok(0);
+        };
+
+        let target = self.state.step.saturating_sub(n).max(first);
+        let steps = self.state.step.saturating_sub(target);
+        if steps > 0 {
+            self.rewind_to(target)?;
+        }
+        Ok(steps)
+    }
+
+    /// Runs backwards to the most recent step before the current one at which `predicate`
+    /// returns `true`. The predicate is checked against the [State] before each step, as it is by
+    /// [InstrumentedState::run_until].
+    ///
+    /// ### Takes
+    /// - `predicate`: Returns `true` for the [State] to stop at.
+    ///
+    /// ### Returns
+    /// - Ok(Some(step)): The step the [State] was rewound to.
+    /// - Ok(None): The predicate does not hold at any step since the first checkpoint, or reverse
+    ///   execution is not enabled. The [State] is left at the current step.
+    /// - Err(_): A [VmError] occurred while replaying from a checkpoint.
+    pub fn run_back_to(
+        &mut self,
+        mut predicate: impl FnMut(&State) -> bool,
+    ) -> Result<Option<u64>, VmError> {
+        let current = self.state.step;
+        let Some(history) = &self.history else {
+            return Ok(None);
+        };
+        let starts = history
+            .checkpoints
+            .range(..current)
+            .rev()
+            .map(|(step, _)| *step)
+            .collect::<Vec<_>>();
+
+        // Search the segments between checkpoints from the most recent one backwards, replaying
+        // each segment once to find the last step in it that matches.
+        let mut end = current;
+        for start in starts {
+            self.rewind_to(start)?;
+            let mut found = None;
+            self.run_until(|state| {
+                if state.step >= end {
+                    return true;
+                }
+                if predicate(state) {
+                    found = Some(state.step);
+                }
+                false
+            })?;
+
+            if let Some(step) = found {
+                self.rewind_to(step)?;
+                return Ok(Some(step));
+            }
+            end = start;
+        }
+
+        if self.state.step != current {
+            self.rewind_to(current)?;
+        }
+        Ok(None)
+    }
+
+    /// Restores the nearest checkpoint at or before `target`, and replays to `target`.
+    fn rewind_to(&mut self, target: u64) -> Result<(), VmError> {
+        let history = self.history.as_mut().expect("reverse execution is enabled");
+        let (_, checkpoint) = history
+            .checkpoints
+            .range(..=target)
+            .next_back()
+            .expect("targets are never before the first checkpoint");
+        history.stdin_cursor = checkpoint.stdin_reads;
+
+        // Watchpoints are not part of the execution, and are kept across the restore.
+        let watchpoints = mem::take(&mut self.state.memory.watchpoints);
+        self.state = checkpoint.state.clone();
+        self.state.memory.watchpoints = watchpoints;
+        // The call stack is restored with the state, and replay tracks the calls after it. A
+        // checkpoint taken before tracking was enabled restores an empty call stack.
+        if let Some(call_stack) = &mut self.call_stack {
+            *call_stack = checkpoint.call_stack.clone().unwrap_or_default();
+        }
+
+        self.run(target - self.state.step)?;
+        self.state.memory.watchpoints.hit = None;
+        Ok(())
+    }
+
+    /// Returns `true` if the current step is being replayed, and its side effects outside of the
+    /// [State] must be suppressed.
+    #[inline(always)]
+    pub(crate) fn replaying(&self) -> bool {
+        self.history
+            .as_ref()
+            .is_some_and(|history| history.replaying)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_utils::{load_program, program, ClaimTestOracle, SharedBuffer},
+        CostMeter, CostTable, InstrumentedState, PreimageOracle, State, TraceRecorder,
+    };
+    use anyhow::Result;
+    use preimage_oracle::Hint;
+
+    /// A [PreimageOracle] that counts the requests it serves.
+    #[derive(Default)]
+    struct CountingOracle {
+        inner: ClaimTestOracle,
+        requests: usize,
+    }
+
+    impl PreimageOracle for CountingOracle {
+        fn hint(&mut self, value: impl Hint) -> Result<()> {
+            self.requests += 1;
+            self.inner.hint(value)
+        }
+
+        fn get(&mut self, key: [u8; 32]) -> Result<Vec<u8>> {
+            self.requests += 1;
+            self.inner.get(key)
+        }
+    }
+
+    fn new_vm(elf_bytes: &[u8]) -> InstrumentedState<Vec<u8>, Vec<u8>, CountingOracle> {
+        InstrumentedState::new(
+            load_program(elf_bytes),
+            CountingOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        )
+    }
+
+    /// Returns the data of the allocated pages of a [State], ordered by page index.
+    fn pages(state: &State) -> Vec<(u64, Vec<u8>)> {
+        let mut pages = Vec::new();
+        state
+            .memory
+            .for_each_page(|index, page| pages.push((index, page.data.to_vec())))
+            .unwrap();
+        pages.sort_by_key(|(index, _)| *index);
+        pages
+    }
+
+    #[test]
+    fn step_back() {
+        let elf_bytes = include_bytes!("../../../../example/bin/claim.elf");
+        let mut reference = new_vm(elf_bytes).with_call_stack(true);
+        let mut ins = new_vm(elf_bytes)
+            .with_call_stack(true)
+            .with_checkpoints(50_000);
+
+        ins.run(u64::MAX).unwrap();
+        assert!(ins.state.exited);
+        let end = ins.state.step;
+        let requests = ins.preimage_oracle.requests;
+        let std_out = ins.std_out().to_vec();
+        assert_eq!(ins.checkpoints().len() as u64, end.div_ceil(50_000));
+
+        for target in [end - 1, end / 2, 12_345, 0] {
+            let steps = ins.state.step - target;
+            assert_eq!(ins.step_back(steps).unwrap(), steps);
+            assert_eq!(ins.state.step, target);
+
+            reference.run(target - reference.state.step).unwrap();
+            assert_eq!(ins.state.pc, reference.state.pc);
+            assert_eq!(ins.state.registers, reference.state.registers);
+            assert_eq!(pages(&ins.state), pages(&reference.state));
+            assert_eq!(ins.call_stack(), reference.call_stack());
+            reference = new_vm(elf_bytes).with_call_stack(true);
+        }
+        assert_eq!(ins.step_back(1).unwrap(), 0);
+
+        // Replaying to the end neither queries the oracle nor repeats the output.
+        ins.run(u64::MAX).unwrap();
+        assert_eq!(ins.state.step, end);
+        assert_eq!(ins.preimage_oracle.requests, requests);
+        assert_eq!(ins.std_out(), std_out.as_slice());
+    }
+
+    #[test]
+    fn replay_hooks() {
+        let elf_bytes = include_bytes!("../../../../example/bin/hello.elf");
+        let traced_vm = |trace: SharedBuffer| {
+            new_vm(elf_bytes)
+                .with_cost_meter(CostMeter::new(CostTable::default()))
+                .with_trace_recorder(TraceRecorder::new(trace).unwrap())
+                .with_call_stack(true)
+                .with_stats(true)
+        };
+        let reference_trace = SharedBuffer::default();
+        let mut reference = traced_vm(reference_trace.clone());
+        reference.run(u64::MAX).unwrap();
+
+        let trace = SharedBuffer::default();
+        let mut ins = traced_vm(trace.clone()).with_checkpoints(10_000);
+        ins.run(25_000).unwrap();
+        assert_eq!(ins.step_back(20_000).unwrap(), 20_000);
+        ins.run(u64::MAX).unwrap();
+
+        // Replayed steps are not traced, charged or counted a second time.
+        reference.trace_recorder_mut().unwrap().flush().unwrap();
+        ins.trace_recorder_mut().unwrap().flush().unwrap();
+        assert_eq!(*trace.0.lock().unwrap(), *reference_trace.0.lock().unwrap());
+        assert_eq!(ins.cost_meter(), reference.cost_meter());
+        assert_eq!(ins.call_stack(), reference.call_stack());
+        assert_eq!(ins.run_stats(), reference.run_stats());
+    }
+
+    #[test]
+    fn run_back_to() {
+        let elf_bytes = include_bytes!("../../../../example/bin/hello.elf");
+        let mut reference = new_vm(elf_bytes);
+        reference.run(30_000).unwrap();
+        let pc = reference.state.pc;
+
+        let mut visits = Vec::new();
+        let mut ins = new_vm(elf_bytes).with_checkpoints(10_000);
+        ins.run_until(|state| {
+            if state.pc == pc {
+                visits.push(state.step);
+            }
+            state.exited
+        })
+        .unwrap();
+        let end = ins.state.step;
+
+        let last = *visits.last().unwrap();
+        assert_eq!(ins.run_back_to(|state| state.pc == pc).unwrap(), Some(last));
+        assert_eq!(ins.state.step, last);
+        assert_eq!(ins.state.pc, pc);
+        if let [.., previous, _] = visits.as_slice() {
+            assert_eq!(
+                ins.run_back_to(|state| state.pc == pc).unwrap(),
+                Some(*previous)
+            );
+        }
+
+        // A predicate that never matched leaves the state where it was.
+        let step = ins.state.step;
+        assert_eq!(ins.run_back_to(|state| state.pc == 1).unwrap(), None);
+        assert_eq!(ins.state.step, step);
+
+        ins.run(u64::MAX).unwrap();
+        assert_eq!(ins.state.step, end);
+    }
+
+    #[test]
+    fn stdin_replay() {
+        let mut ins = program(&[
+            0x24020FA3, // addiu $v0, $zero, 4003 (read)
+            0x24040000, // addiu $a0, $zero, 0
+            0x24050100, // addiu $a1, $zero, 0x100
+            0x24060004, // addiu $a2, $zero, 4
+            0x0000000C, // syscall
+            0x24020FA3, // addiu $v0, $zero, 4003 (read)
+            0x24040000, // addiu $a0, $zero, 0
+            0x24050104, // addiu $a1, $zero, 0x104
+            0x24060004, // addiu $a2, $zero, 4
+            0x0000000C, // syscall
+        ])
+        .with_stdin(&b"abcdefgh"[..])
+        .with_checkpoints(100);
+
+        ins.run(10).unwrap();
+        assert_eq!(ins.state.memory.get_memory(0x104).unwrap(), 0x65666768);
+
+        // Stdin is exhausted, so the replayed reads are served from the history.
+        assert_eq!(ins.step_back(10).unwrap(), 10);
+        assert_eq!(ins.state.memory.get_memory(0x100).unwrap(), 0);
+        ins.run(10).unwrap();
+        assert_eq!(ins.state.memory.get_memory(0x100).unwrap(), 0x61626364);
+        assert_eq!(ins.state.memory.get_memory(0x104).unwrap(), 0x65666768);
+    }
+}
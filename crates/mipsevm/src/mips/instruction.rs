@@ -0,0 +1,424 @@
+//! This module contains the [Instruction] type, a typed decoding of the MIPS32 instructions
+//! supported by the MIPS VM, as well as its disassembly.
+
+use anyhow::Result;
+use std::fmt::{self, Display};
+
+/// The conventional names of the 32 general purpose MIPS registers, indexed by register number.
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+    "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp", "fp",
+    "ra",
+];
+
+/// The opcode of `SPECIAL` R-type instructions.
+const OPCODE_SPECIAL: u32 = 0x00;
+/// The opcode of `REGIMM` I-type branch instructions.
+const OPCODE_REGIMM: u32 = 0x01;
+/// The opcode of `SPECIAL2` R-type instructions.
+const OPCODE_SPECIAL2: u32 = 0x1C;
+
+/// Generates an operation enum along with its mnemonics and a mapping to and from its encoding.
+macro_rules! instruction_ops {
+    (
+        $(#[$meta:meta])*
+        $name:ident: $code_ty:ty {
+            $($variant:ident => $code:expr, $mnemonic:literal),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $(
+                #[doc = concat!("`", $mnemonic, "`")]
+                $variant
+            ),+
+        }
+
+        impl $name {
+            /// Returns the assembly mnemonic of the operation.
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $mnemonic),+
+                }
+            }
+
+            /// Returns the encoding of the operation.
+            pub fn encoding(&self) -> $code_ty {
+                match self {
+                    $(Self::$variant => $code),+
+                }
+            }
+
+            /// Looks up the operation with the given encoding.
+            pub fn from_encoding(code: $code_ty) -> Option<Self> {
+                [$(Self::$variant),+].into_iter().find(|op| op.encoding() == code)
+            }
+        }
+    };
+}
+
+instruction_ops! {
+    /// The operations of R-type instructions, encoded as an `(opcode, funct)` pair.
+    RTypeOp: (u32, u32) {
+        Sll => (OPCODE_SPECIAL, 0x00), "sll",
+        Srl => (OPCODE_SPECIAL, 0x02), "srl",
+        Sra => (OPCODE_SPECIAL, 0x03), "sra",
+        Sllv => (OPCODE_SPECIAL, 0x04), "sllv",
+        Srlv => (OPCODE_SPECIAL, 0x06), "srlv",
+        Srav => (OPCODE_SPECIAL, 0x07), "srav",
+        Jr => (OPCODE_SPECIAL, 0x08), "jr",
+        Jalr => (OPCODE_SPECIAL, 0x09), "jalr",
+        Movz => (OPCODE_SPECIAL, 0x0A), "movz",
+        Movn => (OPCODE_SPECIAL, 0x0B), "movn",
+        Syscall => (OPCODE_SPECIAL, 0x0C), "syscall",
+        Sync => (OPCODE_SPECIAL, 0x0F), "sync",
+        Mfhi => (OPCODE_SPECIAL, 0x10), "mfhi",
+        Mthi => (OPCODE_SPECIAL, 0x11), "mthi",
+        Mflo => (OPCODE_SPECIAL, 0x12), "mflo",
+        Mtlo => (OPCODE_SPECIAL, 0x13), "mtlo",
+        Mult => (OPCODE_SPECIAL, 0x18), "mult",
+        Multu => (OPCODE_SPECIAL, 0x19), "multu",
+        Div => (OPCODE_SPECIAL, 0x1A), "div",
+        Divu => (OPCODE_SPECIAL, 0x1B), "divu",
+        Add => (OPCODE_SPECIAL, 0x20), "add",
+        Addu => (OPCODE_SPECIAL, 0x21), "addu",
+        Sub => (OPCODE_SPECIAL, 0x22), "sub",
+        Subu => (OPCODE_SPECIAL, 0x23), "subu",
+        And => (OPCODE_SPECIAL, 0x24), "and",
+        Or => (OPCODE_SPECIAL, 0x25), "or",
+        Xor => (OPCODE_SPECIAL, 0x26), "xor",
+        Nor => (OPCODE_SPECIAL, 0x27), "nor",
+        Slt => (OPCODE_SPECIAL, 0x2A), "slt",
+        Sltu => (OPCODE_SPECIAL, 0x2B), "sltu",
+        Mul => (OPCODE_SPECIAL2, 0x02), "mul",
+        Clz => (OPCODE_SPECIAL2, 0x20), "clz",
+        Clo => (OPCODE_SPECIAL2, 0x21), "clo",
+    }
+}
+
+instruction_ops! {
+    /// The operations of I-type instructions, encoded as an opcode and, for `REGIMM` branches,
+    /// the value of the `rt` field.
+    ITypeOp: (u32, Option<u32>) {
+        Bltz => (OPCODE_REGIMM, Some(0x00)), "bltz",
+        Bgez => (OPCODE_REGIMM, Some(0x01)), "bgez",
+        Beq => (0x04, None), "beq",
+        Bne => (0x05, None), "bne",
+        Blez => (0x06, None), "blez",
+        Bgtz => (0x07, None), "bgtz",
+        Addi => (0x08, None), "addi",
+        Addiu => (0x09, None), "addiu",
+        Slti => (0x0A, None), "slti",
+        Sltiu => (0x0B, None), "sltiu",
+        Andi => (0x0C, None), "andi",
+        Ori => (0x0D, None), "ori",
+        Xori => (0x0E, None), "xori",
+        Lui => (0x0F, None), "lui",
+        Lb => (0x20, None), "lb",
+        Lh => (0x21, None), "lh",
+        Lwl => (0x22, None), "lwl",
+        Lw => (0x23, None), "lw",
+        Lbu => (0x24, None), "lbu",
+        Lhu => (0x25, None), "lhu",
+        Lwr => (0x26, None), "lwr",
+        Sb => (0x28, None), "sb",
+        Sh => (0x29, None), "sh",
+        Swl => (0x2A, None), "swl",
+        Sw => (0x2B, None), "sw",
+        Swr => (0x2E, None), "swr",
+        Ll => (0x30, None), "ll",
+        Sc => (0x38, None), "sc",
+    }
+}
+
+instruction_ops! {
+    /// The operations of J-type instructions, encoded as their opcode.
+    JTypeOp: u32 {
+        J => 0x02, "j",
+        Jal => 0x03, "jal",
+    }
+}
+
+impl ITypeOp {
+    /// Returns `true` if the operation is a conditional branch.
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            Self::Bltz | Self::Bgez | Self::Beq | Self::Bne | Self::Blez | Self::Bgtz
+        )
+    }
+
+    /// Returns `true` if the operation loads from or stores to memory.
+    pub fn is_memory(&self) -> bool {
+        self.encoding().0 >= 0x20
+    }
+
+    /// Returns `true` if the operation stores to memory.
+    pub fn is_store(&self) -> bool {
+        self.encoding().0 >= 0x28 && !matches!(self, Self::Ll)
+    }
+}
+
+/// An [Instruction] is a decoded MIPS32 instruction word, as supported by the MIPS VM.
+///
+/// Register fields hold the register numbers, and immediate fields hold the raw, unextended
+/// values, so that any valid instruction word survives a round trip through [Instruction::decode]
+/// and [Instruction::encode].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// A register-form instruction (`SPECIAL` or `SPECIAL2`).
+    R {
+        op: RTypeOp,
+        rs: u8,
+        rt: u8,
+        rd: u8,
+        shamt: u8,
+    },
+    /// An immediate-form instruction.
+    I {
+        op: ITypeOp,
+        rs: u8,
+        rt: u8,
+        imm: u16,
+    },
+    /// A jump-form instruction.
+    J {
+        op: JTypeOp,
+        /// The 26 bit word index of the jump target within the current 256MB region.
+        target: u32,
+    },
+}
+
+impl Instruction {
+    /// Decode a raw MIPS32 instruction word.
+    ///
+    /// ### Takes
+    /// - `instruction`: The big-endian instruction word, as read from [crate::Memory].
+    ///
+    /// ### Returns
+    /// - `Ok(instruction)`: The decoded [Instruction].
+    /// - `Err(_)`: The instruction word does not encode an instruction supported by the VM.
+    ///
+    /// `REGIMM` encodings other than `bltz` and `bgez` (such as `bltzal` and `bgezal`) are
+    /// rejected, even though the VM executes them as branches that are never taken and never
+    /// link, since disassembling them as their MIPS mnemonics would misrepresent what they do.
+    pub fn decode(instruction: u32) -> Result<Self> {
+        let opcode = instruction >> 26;
+        let rs = ((instruction >> 21) & 0x1F) as u8;
+        let rt = ((instruction >> 16) & 0x1F) as u8;
+
+        match opcode {
+            OPCODE_SPECIAL | OPCODE_SPECIAL2 => {
+                let fun = instruction & 0x3F;
+                let op = RTypeOp::from_encoding((opcode, fun)).ok_or(anyhow::anyhow!(
+                    "Invalid function code {:x} for opcode {:x}",
+                    fun,
+                    opcode
+                ))?;
+                Ok(Self::R {
+                    op,
+                    rs,
+                    rt,
+                    rd: ((instruction >> 11) & 0x1F) as u8,
+                    shamt: ((instruction >> 6) & 0x1F) as u8,
+                })
+            }
+            2 | 3 => Ok(Self::J {
+                op: JTypeOp::from_encoding(opcode).expect("opcode is a jump"),
+                target: instruction & 0x03FFFFFF,
+            }),
+            _ => {
+                let regimm = (opcode == OPCODE_REGIMM).then_some(rt as u32);
+                let op = ITypeOp::from_encoding((opcode, regimm)).ok_or_else(|| match regimm {
+                    Some(rt) => anyhow::anyhow!("Invalid regimm branch {:x}", rt),
+                    None => anyhow::anyhow!("Invalid opcode {:x}", opcode),
+                })?;
+                Ok(Self::I {
+                    op,
+                    rs,
+                    rt,
+                    imm: instruction as u16,
+                })
+            }
+        }
+    }
+
+    /// Encode the [Instruction] back into a raw MIPS32 instruction word.
+    pub fn encode(&self) -> u32 {
+        match *self {
+            Self::R {
+                op,
+                rs,
+                rt,
+                rd,
+                shamt,
+            } => {
+                let (opcode, fun) = op.encoding();
+                opcode << 26
+                    | (rs as u32) << 21
+                    | (rt as u32) << 16
+                    | (rd as u32) << 11
+                    | (shamt as u32) << 6
+                    | fun
+            }
+            Self::I { op, rs, rt, imm } => {
+                let (opcode, _) = op.encoding();
+                opcode << 26 | (rs as u32) << 21 | (rt as u32) << 16 | imm as u32
+            }
+            Self::J { op, target } => op.encoding() << 26 | (target & 0x03FFFFFF),
+        }
+    }
+
+    /// Returns the assembly mnemonic of the [Instruction].
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::R { op, .. } => op.mnemonic(),
+            Self::I { op, .. } => op.mnemonic(),
+            Self::J { op, .. } => op.mnemonic(),
+        }
+    }
+}
+
+/// Displays a register operand.
+struct Reg(u8);
+
+impl Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${}", REGISTER_NAMES[self.0 as usize & 0x1F])
+    }
+}
+
+impl Display for Instruction {
+    /// Formats the [Instruction] as MIPS assembly.
+    ///
+    /// Branch offsets are printed in bytes, relative to the delay slot, and jump targets are
+    /// printed as their offset within the current 256MB region, as the disassembly does not know
+    /// the address of the instruction.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = self.mnemonic();
+        match *self {
+            Self::R {
+                op,
+                rs,
+                rt,
+                rd,
+                shamt,
+            } => {
+                let (rs, rt, rd) = (Reg(rs), Reg(rt), Reg(rd));
+                match op {
+                    RTypeOp::Sll if self.encode() == 0 => write!(f, "nop"),
+                    RTypeOp::Sll | RTypeOp::Srl | RTypeOp::Sra => {
+                        write!(f, "{m} {rd}, {rt}, {shamt}")
+                    }
+                    RTypeOp::Sllv | RTypeOp::Srlv | RTypeOp::Srav => {
+                        write!(f, "{m} {rd}, {rt}, {rs}")
+                    }
+                    RTypeOp::Jr | RTypeOp::Mthi | RTypeOp::Mtlo => write!(f, "{m} {rs}"),
+                    RTypeOp::Jalr if rd.0 == 31 => write!(f, "{m} {rs}"),
+                    RTypeOp::Jalr => write!(f, "{m} {rd}, {rs}"),
+                    RTypeOp::Syscall | RTypeOp::Sync => write!(f, "{m}"),
+                    RTypeOp::Mfhi | RTypeOp::Mflo => write!(f, "{m} {rd}"),
+                    RTypeOp::Mult | RTypeOp::Multu | RTypeOp::Div | RTypeOp::Divu => {
+                        write!(f, "{m} {rs}, {rt}")
+                    }
+                    RTypeOp::Clz | RTypeOp::Clo => write!(f, "{m} {rd}, {rs}"),
+                    _ => write!(f, "{m} {rd}, {rs}, {rt}"),
+                }
+            }
+            Self::I { op, rs, rt, imm } => {
+                let (rs, rt) = (Reg(rs), Reg(rt));
+                let simm = imm as i16;
+                match op {
+                    ITypeOp::Beq | ITypeOp::Bne => {
+                        write!(f, "{m} {rs}, {rt}, {}", (simm as i32) << 2)
+                    }
+                    ITypeOp::Bltz | ITypeOp::Bgez | ITypeOp::Blez | ITypeOp::Bgtz => {
+                        write!(f, "{m} {rs}, {}", (simm as i32) << 2)
+                    }
+                    ITypeOp::Andi | ITypeOp::Ori | ITypeOp::Xori => {
+                        write!(f, "{m} {rt}, {rs}, 0x{imm:x}")
+                    }
+                    ITypeOp::Lui => write!(f, "{m} {rt}, 0x{imm:x}"),
+                    op if op.is_memory() => write!(f, "{m} {rt}, {simm}({rs})"),
+                    _ => write!(f, "{m} {rt}, {rs}, {simm}"),
+                }
+            }
+            Self::J { target, .. } => write!(f, "{m} 0x{:07x}", target << 2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::program;
+    use proptest::proptest;
+
+    #[test]
+    fn decode_display() {
+        let cases = [
+            (0x00000000, "nop"),
+            (0x00041080, "sll $v0, $a0, 2"),
+            (0x00851021, "addu $v0, $a0, $a1"),
+            (0x00a41007, "srav $v0, $a0, $a1"),
+            (0x03e00008, "jr $ra"),
+            (0x0320f809, "jalr $t9"),
+            (0x0000000c, "syscall"),
+            (0x0000000f, "sync"),
+            (0x00001010, "mfhi $v0"),
+            (0x00850018, "mult $a0, $a1"),
+            (0x70851002, "mul $v0, $a0, $a1"),
+            (0x70801020, "clz $v0, $a0"),
+            (0x2484fffc, "addiu $a0, $a0, -4"),
+            (0x3484beef, "ori $a0, $a0, 0xbeef"),
+            (0x3c04dead, "lui $a0, 0xdead"),
+            (0x8fbf0010, "lw $ra, 16($sp)"),
+            (0xafa4fff8, "sw $a0, -8($sp)"),
+            (0x1085fffe, "beq $a0, $a1, -8"),
+            (0x04810003, "bgez $a0, 12"),
+            (0x0c100004, "jal 0x0400010"),
+        ];
+
+        for (word, asm) in cases {
+            let instruction = Instruction::decode(word).unwrap();
+            assert_eq!(instruction.to_string(), asm, "disassembly of {:08x}", word);
+            assert_eq!(instruction.encode(), word, "re-encoding of {:08x}", word);
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        // Unsupported SPECIAL function, SPECIAL2 function, REGIMM branch, and opcode.
+        for word in [0x0000000d, 0x70000000, 0x04100000, 0xFFFFFFFF, 0x44000000] {
+            assert!(
+                Instruction::decode(word).is_err(),
+                "{:08x} must not decode",
+                word
+            );
+        }
+    }
+
+    #[test]
+    fn regimm_unsupported_never_taken() {
+        // bgezal $zero, 12: would branch and link on real MIPS.
+        let word = 0x04110003;
+        assert!(Instruction::decode(word).is_err());
+
+        let mut ins = program(&[word]);
+        ins.step(false).unwrap();
+
+        assert_eq!(ins.state.pc, 4);
+        assert_eq!(ins.state.next_pc, 8);
+        assert_eq!(ins.state.registers[31], 0);
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_encode_roundtrip(word: u32) {
+            if let Ok(instruction) = Instruction::decode(word) {
+                assert_eq!(instruction.encode(), word);
+            }
+        }
+    }
+}
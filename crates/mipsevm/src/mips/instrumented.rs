@@ -1,11 +1,21 @@
 //! This module contains the [InstrumentedState] definition.
 
-use crate::{traits::PreimageOracle, Address, State, StepWitness};
-use anyhow::Result;
-use std::io::{BufWriter, Write};
+use crate::{
+    mips::{block::BlockCache, Breakpoints, History},
+    stats::StatsCounter,
+    traits::PreimageOracle,
+    witness::THREAD_PROOF_SIZE,
+    Address, CallStack, CostMeter, Frame, RunStats, State, StepWitness, SyscallContext,
+    SyscallHandler, TraceRecorder, VmError,
+};
+use std::io::{BufWriter, Read, Write};
 
 pub(crate) const MIPS_EBADF: u32 = 0x9;
+pub(crate) const MIPS_EAGAIN: u32 = 0xb;
+pub(crate) const MIPS_ENOMEM: u32 = 0xc;
 pub(crate) const MIPS_EINVAL: u32 = 0x16;
+pub(crate) const MIPS_ENOSYS: u32 = 0x59;
+pub(crate) const MIPS_ETIMEDOUT: u32 = 0x91;
 
 /// The [InstrumentedState] is a wrapper around [State] that contains cached machine state,
 /// the input and output buffers, and an implementation of the MIPS VM.
@@ -26,6 +36,10 @@ pub struct InstrumentedState<O: Write, E: Write, P: PreimageOracle> {
     pub(crate) mem_proof_enabled: bool,
     /// The memory proof, if it is enabled.
     pub(crate) mem_proof: [u8; 28 * 32],
+    /// The second address we accessed in memory, in multithreaded mode.
+    pub(crate) last_mem_access2: Address,
+    /// The second memory proof, if it is enabled, in multithreaded mode.
+    pub(crate) mem_proof2: [u8; 28 * 32],
     /// The [PreimageOracle] used to fetch preimages.
     pub(crate) preimage_oracle: P,
     /// Cached pre-image data, including 8 byte length prefix
@@ -35,6 +49,28 @@ pub struct InstrumentedState<O: Write, E: Write, P: PreimageOracle> {
     /// The offset we last read from, or max u32 if nothing is read at
     /// the current step.
     pub(crate) last_preimage_offset: u32,
+    /// The [SyscallHandler] consulted before the built-in syscalls, if any.
+    pub(crate) syscall_handler: Option<Box<dyn SyscallHandler + Send>>,
+    /// Whether or not unknown syscalls fault instead of returning 0.
+    pub(crate) strict_syscalls: bool,
+    /// The address the heap may not grow past, if any.
+    pub(crate) heap_limit: Option<Address>,
+    /// The source of the MIPS thread context's stdin, if any.
+    pub(crate) std_in: Option<Box<dyn Read + Send>>,
+    /// The translated basic blocks of [InstrumentedState::run_blocks].
+    pub(crate) blocks: BlockCache<O, E, P>,
+    /// The [CostMeter] charged for every executed instruction, if any.
+    pub(crate) cost_meter: Option<CostMeter>,
+    /// The breakpoints of [InstrumentedState::run_until_break].
+    pub(crate) breakpoints: Breakpoints,
+    /// The checkpoints and recorded inputs used for reverse execution, if enabled.
+    pub(crate) history: Option<History>,
+    /// The [TraceRecorder] every executed instruction is recorded to, if any.
+    pub(crate) trace_recorder: Option<TraceRecorder>,
+    /// The [CallStack] reconstructed from the executed calls and returns, if enabled.
+    pub(crate) call_stack: Option<CallStack>,
+    /// The counters of the [RunStats], if enabled.
+    pub(crate) stats: Option<StatsCounter>,
 }
 
 impl<O, E, P> InstrumentedState<O, E, P>
@@ -51,42 +87,209 @@ where
             last_mem_access: 0,
             mem_proof_enabled: false,
             mem_proof: [0u8; 28 * 32],
+            last_mem_access2: 0,
+            mem_proof2: [0u8; 28 * 32],
             preimage_oracle: oracle,
             last_preimage: Vec::default(),
             last_preimage_key: [0u8; 32],
             last_preimage_offset: 0,
+            syscall_handler: None,
+            strict_syscalls: false,
+            heap_limit: None,
+            std_in: None,
+            blocks: BlockCache::default(),
+            cost_meter: None,
+            breakpoints: Breakpoints::default(),
+            history: None,
+            trace_recorder: None,
+            call_stack: None,
+            stats: None,
         }
     }
 
+    /// Sets the [SyscallHandler] that is consulted before the built-in syscalls are dispatched.
+    ///
+    /// ### Takes
+    /// - `handler`: The [SyscallHandler] to add or override syscalls with.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with the [SyscallHandler] set.
+    pub fn with_syscall_handler(mut self, handler: impl SyscallHandler + Send + 'static) -> Self {
+        self.syscall_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Sets whether unknown syscalls raise a [crate::FaultKind::UnknownSyscall] fault. By default,
+    /// unknown syscalls return 0 like they do in `MIPS.sol`, and are only logged.
+    ///
+    /// ### Takes
+    /// - `strict`: Whether or not unknown syscalls fault.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with strict syscalls set.
+    pub fn with_strict_syscalls(mut self, strict: bool) -> Self {
+        self.strict_syscalls = strict;
+        self
+    }
+
+    /// Sets the address the heap may not grow past. Anonymous `mmap` calls that would grow the
+    /// heap past the limit fail with `ENOMEM`. `MIPS.sol` does not limit the heap, so a guest
+    /// that hits the limit can no longer be proven on-chain.
+    ///
+    /// ### Takes
+    /// - `limit`: The heap limit, or `None` for an unbounded heap.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with the heap limit set.
+    pub fn with_heap_limit(mut self, limit: Option<Address>) -> Self {
+        self.heap_limit = limit;
+        self
+    }
+
+    /// Sets the source that `read` syscalls on stdin are served from. By default, stdin is empty,
+    /// like it is in `MIPS.sol`, so a guest that reads from it can no longer be proven on-chain.
+    ///
+    /// Like preimage reads, a single `read` syscall reads at most up to the next word boundary.
+    ///
+    /// ### Takes
+    /// - `std_in`: The source of the guest's stdin.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with stdin set.
+    pub fn with_stdin(mut self, std_in: impl Read + Send + 'static) -> Self {
+        self.std_in = Some(Box::new(std_in));
+        self
+    }
+
+    /// Sets the [CostMeter] that is charged for every executed instruction. Once its budget is
+    /// exhausted, stepping fails with [VmError::CostBudgetExhausted] without changing the state.
+    ///
+    /// ### Takes
+    /// - `meter`: The [CostMeter] to charge.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with the [CostMeter] set.
+    pub fn with_cost_meter(mut self, meter: CostMeter) -> Self {
+        self.cost_meter = Some(meter);
+        self
+    }
+
+    /// Returns the [CostMeter], if one is set.
+    pub fn cost_meter(&self) -> Option<&CostMeter> {
+        self.cost_meter.as_ref()
+    }
+
+    /// Sets the [TraceRecorder] that every executed instruction is recorded to.
+    ///
+    /// ### Takes
+    /// - `recorder`: The [TraceRecorder] to record to.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with the [TraceRecorder] set.
+    pub fn with_trace_recorder(mut self, recorder: TraceRecorder) -> Self {
+        self.trace_recorder = Some(recorder);
+        self
+    }
+
+    /// Returns the [TraceRecorder], if one is set.
+    pub fn trace_recorder_mut(&mut self) -> Option<&mut TraceRecorder> {
+        self.trace_recorder.as_mut()
+    }
+
+    /// Sets whether the guest's [CallStack] is reconstructed from the calls and returns it
+    /// executes. Tracking the call stack disables the fast path of
+    /// [InstrumentedState::run_blocks].
+    ///
+    /// ### Takes
+    /// - `enabled`: Whether or not the call stack is tracked.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with call stack tracking set.
+    pub fn with_call_stack(mut self, enabled: bool) -> Self {
+        self.call_stack = enabled.then(CallStack::default);
+        self
+    }
+
+    /// Returns the [Frame]s of the current thread's call stack, innermost last, if call stack
+    /// tracking is enabled.
+    pub fn call_stack(&self) -> Option<&[Frame]> {
+        let thread_id = self.state.threads.as_ref().and_then(|t| t.current());
+        let thread_id = thread_id.map_or(0, |t| t.thread_id);
+        self.call_stack
+            .as_ref()
+            .map(|stack| stack.frames(thread_id))
+    }
+
+    /// Sets whether the [RunStats] of executed instructions and syscalls are collected.
+    /// Collecting them disables the fast path of [InstrumentedState::run_blocks].
+    ///
+    /// ### Takes
+    /// - `enabled`: Whether or not the [RunStats] are collected.
+    ///
+    /// ### Returns
+    /// - The [InstrumentedState] with stats collection set.
+    pub fn with_stats(mut self, enabled: bool) -> Self {
+        self.stats = enabled.then(StatsCounter::default);
+        self
+    }
+
+    /// Returns the [RunStats] collected so far, if stats collection is enabled.
+    pub fn run_stats(&self) -> Option<RunStats> {
+        self.stats.as_ref().map(|stats| stats.stats(&self.state))
+    }
+
     /// Step the MIPS emulator forward one instruction.
     ///
     /// ### Returns
     /// - Ok(Some(witness)): The [StepWitness] for the current
-    /// - Err(_): An error occurred while processing the instruction step in the MIPS emulator.
+    /// - Err(_): A [VmError] occurred while processing the instruction step in the MIPS emulator.
     #[inline(always)]
-    pub fn step(&mut self, proof: bool) -> Result<Option<StepWitness>> {
+    pub fn step(&mut self, proof: bool) -> Result<Option<StepWitness>, VmError> {
         self.mem_proof_enabled = proof;
         self.last_mem_access = !0u32 as Address;
+        self.last_mem_access2 = !0u32 as Address;
         self.last_preimage_offset = !0u32;
 
         let mut witness = None;
         if proof {
+            // Rehash the invalidated pages in parallel, so that the proofs only fold cached nodes.
+            #[cfg(feature = "parallel")]
+            self.state.memory.par_merkle_root()?;
+
             let instruction_proof = self.state.memory.merkle_proof(self.state.pc as Address)?;
 
-            let mut mem_proof = vec![0; 28 * 32 * 2];
-            mem_proof[0..28 * 32].copy_from_slice(instruction_proof.as_slice());
+            // In multithreaded mode, the memory proofs are prefixed by the thread proof of the
+            // current thread, and followed by a second memory proof.
+            let mut mem_proof = if self.state.is_multithreaded() {
+                self.state.encode_thread_proof()?.to_vec()
+            } else {
+                Vec::with_capacity(28 * 32 * 2)
+            };
+            mem_proof.extend_from_slice(instruction_proof.as_slice());
+            mem_proof.extend_from_slice(&[0; 28 * 32]);
+            if self.state.is_multithreaded() {
+                mem_proof.extend_from_slice(&[0; 28 * 32]);
+            }
             witness = Some(StepWitness {
-                state: self.state.encode_witness()?,
+                state: self.state.encode_witness_bytes()?,
                 mem_proof,
                 ..Default::default()
             })
         }
 
         self.inner_step()?;
+        self.state.save_thread_context();
 
         if proof {
             witness = witness.map(|mut wit| {
-                wit.mem_proof[28 * 32..].copy_from_slice(self.mem_proof.as_slice());
+                let offset = THREAD_PROOF_SIZE + 28 * 32;
+                if self.state.is_multithreaded() {
+                    wit.mem_proof[offset..offset + 28 * 32]
+                        .copy_from_slice(self.mem_proof.as_slice());
+                    wit.mem_proof[offset + 28 * 32..].copy_from_slice(self.mem_proof2.as_slice());
+                } else {
+                    wit.mem_proof[28 * 32..].copy_from_slice(self.mem_proof.as_slice());
+                }
                 if self.last_preimage_offset != u32::MAX {
                     wit.preimage_key = Some(self.last_preimage_key);
                     wit.preimage_value = Some(self.last_preimage.clone());
@@ -99,6 +302,55 @@ where
         Ok(witness)
     }
 
+    /// Run the MIPS emulator without generating witnesses, until it exits or `max_steps` steps
+    /// have been performed.
+    ///
+    /// ### Takes
+    /// - `max_steps`: The maximum number of steps to perform.
+    ///
+    /// ### Returns
+    /// - Ok(steps): The number of steps performed.
+    /// - Err(_): A [VmError] occurred while processing an instruction step in the MIPS emulator.
+    pub fn run(&mut self, max_steps: u64) -> Result<u64, VmError> {
+        let end = self.state.step.saturating_add(max_steps);
+        self.run_until(|state| state.step >= end)
+    }
+
+    /// Run the MIPS emulator without generating witnesses, until it exits or `predicate` returns
+    /// `true`. The predicate is checked before every step. The final state is identical to
+    /// performing the same steps with `step(false)`.
+    ///
+    /// ### Takes
+    /// - `predicate`: Returns `true` for the [State] to stop at.
+    ///
+    /// ### Returns
+    /// - Ok(steps): The number of steps performed.
+    /// - Err(_): A [VmError] occurred while processing an instruction step in the MIPS emulator.
+    pub fn run_until(&mut self, mut predicate: impl FnMut(&State) -> bool) -> Result<u64, VmError> {
+        self.mem_proof_enabled = false;
+        self.last_preimage_offset = !0u32;
+
+        let start = self.state.step;
+        while !self.state.exited && !predicate(&self.state) {
+            self.inner_step()?;
+            self.state.save_thread_context();
+        }
+        Ok(self.state.step - start)
+    }
+
+    /// Returns the [SyscallContext] for the current step.
+    #[inline(always)]
+    pub(crate) fn syscall_context(&mut self) -> SyscallContext<'_> {
+        SyscallContext {
+            state: &mut self.state,
+            last_mem_access: &mut self.last_mem_access,
+            mem_proof_enabled: self.mem_proof_enabled,
+            mem_proof: &mut self.mem_proof,
+            last_mem_access2: &mut self.last_mem_access2,
+            mem_proof2: &mut self.mem_proof2,
+        }
+    }
+
     /// Returns the stdout buffer.
     pub fn std_out(&self) -> &[u8] {
         self.std_out.buffer()
@@ -114,10 +366,12 @@ where
 mod test {
     use alloy_primitives::keccak256;
 
-    use crate::test_utils::{ClaimTestOracle, BASE_ADDR_END, END_ADDR};
+    use super::MIPS_ENOMEM;
+    use crate::test_utils::{load_program, program, ClaimTestOracle, BASE_ADDR_END, END_ADDR};
     use crate::witness::STATE_WITNESS_SIZE;
-    use crate::{load_elf, patch, StateWitnessHasher};
+    use crate::StateWitnessHasher;
     use crate::{test_utils::StaticOracle, Address, InstrumentedState, Memory, State};
+    use crate::{FaultKind, VmError};
     use std::io::BufWriter;
     use std::{
         fs,
@@ -237,12 +491,338 @@ mod test {
         }
     }
 
+    #[test]
+    fn vm_fault() {
+        let cases = [
+            (
+                "illegal instruction",
+                0,
+                4,
+                0xFF_FF_FF_FFu32,
+                FaultKind::InvalidOpcode(0x3F),
+            ),
+            (
+                "branch in delay slot",
+                0,
+                8,
+                0x11_02_00_03,
+                FaultKind::BranchInDelaySlot,
+            ),
+            (
+                "jump in delay slot",
+                0,
+                8,
+                0x0c_00_00_0c,
+                FaultKind::JumpInDelaySlot,
+            ),
+            (
+                "div by zero",
+                0,
+                4,
+                0x00_85_00_1a,
+                FaultKind::DivisionByZero,
+            ),
+            ("unaligned pc", 2, 6, 0, FaultKind::UnalignedAccess(2)),
+        ];
+
+        for (name, pc, next_pc, instruction, expected) in cases {
+            let mut state = State {
+                pc,
+                next_pc,
+                ..Default::default()
+            };
+            state.memory.set_memory(0, instruction).unwrap();
+
+            let mut ins = InstrumentedState::new(
+                state,
+                StaticOracle::new(b"hello world".to_vec()),
+                io::stdout(),
+                io::stderr(),
+            );
+
+            match ins.step(true) {
+                Err(VmError::Fault {
+                    step,
+                    pc: fault_pc,
+                    instruction: fault_instruction,
+                    kind,
+                }) => {
+                    assert_eq!(step, 0, "{name}: fault step");
+                    assert_eq!(fault_pc, pc, "{name}: fault pc");
+                    assert_eq!(fault_instruction, instruction, "{name}: fault instruction");
+                    assert_eq!(kind, expected, "{name}: fault kind");
+                }
+                res => panic!("{name}: expected fault, got {:?}", res.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn signed_overflow() {
+        // div and mult of $a0 = i32::MIN by $a1 = -1 wrap instead of trapping.
+        let mut ins = program(&[
+            0x0085001A, // div $a0, $a1
+            0x00850018, // mult $a0, $a1
+        ]);
+        ins.state.registers[4] = 0x80000000;
+        ins.state.registers[5] = 0xFFFFFFFF;
+
+        ins.step(true).unwrap();
+        assert_eq!(ins.state.lo, 0x80000000);
+        assert_eq!(ins.state.hi, 0);
+
+        ins.step(true).unwrap();
+        assert_eq!(ins.state.lo, 0x80000000);
+        assert_eq!(ins.state.hi, 0);
+    }
+
+    #[test]
+    fn syscalls() {
+        let cases = [
+            ("unknown", 4005, false, Ok(0)),
+            (
+                "unknown strict",
+                4005,
+                true,
+                Err(FaultKind::UnknownSyscall(4005)),
+            ),
+            ("clock_gettime", 4263, true, Ok(0)),
+            ("gettid", 4222, true, Ok(0)),
+            ("rt_sigaction", 4194, true, Ok(0)),
+            ("munmap", 4091, true, Ok(0)),
+        ];
+
+        for (name, syscall, strict, expected) in cases {
+            let mut state = State {
+                next_pc: 4,
+                ..Default::default()
+            };
+            // syscall
+            state.memory.set_memory(0, 0x0000000c).unwrap();
+            state.memory.set_memory(0x100, 0xdeadbeef).unwrap();
+            state.registers[2] = syscall;
+            state.registers[5] = 0x100;
+
+            let mut ins = InstrumentedState::new(
+                state,
+                StaticOracle::new(b"hello world".to_vec()),
+                io::stdout(),
+                io::stderr(),
+            )
+            .with_strict_syscalls(strict);
+
+            match (ins.step(true), expected) {
+                (Ok(_), Ok(v0)) => {
+                    assert_eq!(ins.state.registers[2], v0, "{name}: v0");
+                    assert_eq!(ins.state.registers[7], 0, "{name}: v1");
+                    assert_eq!(ins.state.pc, 4, "{name}: pc");
+                    // Syscalls unknown to `MIPS.sol` must not have side effects.
+                    assert_eq!(
+                        ins.state.memory.get_memory(0x100).unwrap(),
+                        0xdeadbeef,
+                        "{name}: memory"
+                    );
+                }
+                (Err(VmError::Fault { kind, .. }), Err(expected)) => {
+                    assert_eq!(kind, expected, "{name}: fault kind")
+                }
+                (res, _) => panic!("{name}: unexpected result {:?}", res.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn mmap_heap_limit() {
+        let cases = [
+            (
+                "within limit",
+                0x20000000,
+                Some(0x20002000),
+                0x1001,
+                Ok(0x20002000),
+            ),
+            (
+                "exact limit",
+                0x20000000,
+                Some(0x20001000),
+                0x1000,
+                Ok(0x20001000),
+            ),
+            ("past limit", 0x20000000, Some(0x20001000), 0x1001, Err(())),
+            ("unbounded", 0x20000000, None, 0x10000000, Ok(0x30000000)),
+            ("wraparound", 0xFFFFF000, None, 0x1000, Err(())),
+            ("size overflow", 0x20000000, None, 0xFFFFFFFF, Err(())),
+        ];
+
+        for (name, heap, limit, size, expected) in cases {
+            let mut state = State {
+                next_pc: 4,
+                heap,
+                ..Default::default()
+            };
+            // syscall
+            state.memory.set_memory(0, 0x0000000c).unwrap();
+            state.registers[2] = 4090;
+            state.registers[5] = size;
+
+            let mut ins = InstrumentedState::new(
+                state,
+                StaticOracle::new(b"hello world".to_vec()),
+                io::stdout(),
+                io::stderr(),
+            )
+            .with_heap_limit(limit);
+            ins.step(false).unwrap();
+
+            match expected {
+                Ok(heap_end) => {
+                    assert_eq!(ins.state.registers[2], heap, "{name}: v0");
+                    assert_eq!(ins.state.registers[7], 0, "{name}: v1");
+                    assert_eq!(ins.state.heap, heap_end, "{name}: heap");
+                }
+                Err(()) => {
+                    assert_eq!(ins.state.registers[2], 0xFFFFFFFF, "{name}: v0");
+                    assert_eq!(ins.state.registers[7], MIPS_ENOMEM, "{name}: v1");
+                    assert_eq!(ins.state.heap, heap, "{name}: heap");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stdin() {
+        let read_stdin = |std_in: Option<&'static [u8]>, address: u32, count: u32| {
+            let mut state = State {
+                next_pc: 4,
+                ..Default::default()
+            };
+            // syscall
+            state.memory.set_memory(0, 0x0000000c).unwrap();
+            state.memory.set_memory(0x100, 0xAABBCCDD).unwrap();
+            state.registers[2] = 4003;
+            state.registers[4] = 0;
+            state.registers[5] = address;
+            state.registers[6] = count;
+
+            let mut ins = InstrumentedState::new(
+                state,
+                StaticOracle::new(b"hello world".to_vec()),
+                io::stdout(),
+                io::stderr(),
+            );
+            if let Some(std_in) = std_in {
+                ins = ins.with_stdin(std_in);
+            }
+            ins.step(true).unwrap();
+            (
+                ins.state.registers[2],
+                ins.state.memory.get_memory(0x100).unwrap(),
+                ins.last_mem_access,
+            )
+        };
+
+        // Stdin is empty by default, and memory is not touched.
+        assert_eq!(read_stdin(None, 0x100, 4), (0, 0xAABBCCDD, !0));
+        // Reads stop at the word boundary.
+        assert_eq!(read_stdin(Some(b"hello"), 0x101, 8), (3, 0xAA68656C, 0x100));
+        // Reads are limited by the count.
+        assert_eq!(read_stdin(Some(b"hello"), 0x100, 2), (2, 0x6865CCDD, 0x100));
+        // Short reads leave the rest of the word intact.
+        assert_eq!(read_stdin(Some(b"h"), 0x100, 4), (1, 0x68BBCCDD, 0x100));
+        assert_eq!(read_stdin(Some(b""), 0x100, 4), (0, 0xAABBCCDD, 0x100));
+    }
+
+    #[test]
+    fn run() {
+        let state = load_program(include_bytes!("../../../../example/bin/hello.elf"));
+        let new_vm = || {
+            InstrumentedState::new(
+                state.clone(),
+                StaticOracle::new(b"hello world".to_vec()),
+                Vec::default(),
+                Vec::default(),
+            )
+        };
+        let mut stepped = new_vm();
+        let mut ran = new_vm();
+
+        for _ in 0..100_000 {
+            stepped.step(false).unwrap();
+        }
+        assert_eq!(ran.run(100_000).unwrap(), 100_000);
+        assert_eq!(ran.state.step, stepped.state.step);
+        assert_eq!(
+            ran.state.encode_witness().unwrap(),
+            stepped.state.encode_witness().unwrap()
+        );
+
+        while !stepped.state.exited {
+            stepped.step(false).unwrap();
+        }
+        ran.run_until(|_| false).unwrap();
+        assert!(ran.state.exited);
+        assert_eq!(ran.state.step, stepped.state.step);
+        assert_eq!(ran.std_out(), stepped.std_out());
+        assert_eq!(
+            ran.state.encode_witness().unwrap(),
+            stepped.state.encode_witness().unwrap()
+        );
+
+        // An exited VM performs no steps.
+        assert_eq!(ran.run(10).unwrap(), 0);
+    }
+
+    #[test]
+    fn run_self_modifying() {
+        let mut state = State {
+            next_pc: 4,
+            ..Default::default()
+        };
+        // sw $t0, 8($zero)
+        state.memory.set_memory(0, 0xAC080008).unwrap();
+        // nop
+        state.memory.set_memory(4, 0).unwrap();
+        // addiu $t1, $zero, 1
+        state.memory.set_memory(8, 0x24090001).unwrap();
+        // addiu $t1, $zero, 2
+        state.registers[8] = 0x24090002;
+
+        let mut ins = InstrumentedState::new(
+            state,
+            StaticOracle::new(b"hello world".to_vec()),
+            io::stdout(),
+            io::stderr(),
+        );
+        assert_eq!(ins.run_until(|state| state.pc == 12).unwrap(), 3);
+        assert_eq!(ins.state.registers[9], 2);
+    }
+
+    #[test]
+    fn page_budget() {
+        // sw $zero, 0x1000($zero)
+        let mut state = State {
+            next_pc: 4,
+            ..Default::default()
+        };
+        state.memory.set_memory(0, 0xac001000).unwrap();
+        state.memory.page_budget = Some(1);
+
+        let mut ins = InstrumentedState::new(
+            state,
+            StaticOracle::new(b"hello world".to_vec()),
+            io::stdout(),
+            io::stderr(),
+        );
+        match ins.step(false) {
+            Err(VmError::PageBudgetExceeded(e)) => assert_eq!(e.budget, 1),
+            res => panic!("expected page budget error, got {:?}", res.map(|_| ())),
+        }
+    }
+
     #[test]
     fn test_hello() {
         let elf_bytes = include_bytes!("../../../../example/bin/hello.elf");
-        let mut state = load_elf(elf_bytes).unwrap();
-        patch::patch_go(elf_bytes, &mut state).unwrap();
-        patch::patch_stack(&mut state).unwrap();
+        let state = load_program(elf_bytes);
 
         let out = BufWriter::new(Vec::default());
         let err = BufWriter::new(Vec::default());
@@ -272,9 +852,7 @@ mod test {
     #[test]
     fn test_claim() {
         let elf_bytes = include_bytes!("../../../../example/bin/claim.elf");
-        let mut state = load_elf(elf_bytes).unwrap();
-        patch::patch_go(elf_bytes, &mut state).unwrap();
-        patch::patch_stack(&mut state).unwrap();
+        let state = load_program(elf_bytes);
 
         let out = BufWriter::new(Vec::default());
         let err = BufWriter::new(Vec::default());
@@ -1,13 +1,15 @@
 //! This module contains the MIPS VM implementation for the [InstrumentedState].
 
 use crate::{
+    icache::DecodedInstruction,
     memory::MemoryReader,
-    mips::instrumented::{MIPS_EBADF, MIPS_EINVAL},
+    mips::instrumented::{MIPS_EBADF, MIPS_EINVAL, MIPS_ENOMEM, MIPS_ENOSYS},
     page,
+    recorder::RegisterSnapshot,
     types::Syscall,
-    Address, Fd, InstrumentedState, PreimageOracle,
+    Address, FaultKind, Fd, InstructionClass, InstrumentedState, PreimageOracle, SyscallContext,
+    VmError,
 };
-use anyhow::Result;
 use std::io::{self, BufReader, Read, Write};
 
 impl<O, E, P> InstrumentedState<O, E, P>
@@ -30,9 +32,12 @@ where
         &mut self,
         key: [u8; 32],
         offset: u32,
-    ) -> Result<([u8; 32], usize)> {
+    ) -> Result<([u8; 32], usize), VmError> {
         if key != self.last_preimage_key {
-            let data = self.preimage_oracle.get(key)?;
+            let data = match &mut self.history {
+                Some(history) => history.preimage(key, &mut self.preimage_oracle)?,
+                None => self.preimage_oracle.get(key).map_err(VmError::Oracle)?,
+            };
             self.last_preimage_key = key;
 
             // Add the length prefix to the preimage
@@ -42,6 +47,9 @@ where
             self.last_preimage[8..].copy_from_slice(&data);
         }
 
+        if offset as usize > self.last_preimage.len() {
+            return Err(self.fault(FaultKind::PreimageOffsetOutOfBounds(offset)));
+        }
         self.last_preimage_offset = offset;
 
         let mut data = [0u8; 32];
@@ -58,16 +66,34 @@ where
     /// ### Returns
     /// - A [Result] indicating if the operation was successful.
     #[inline(always)]
-    pub(crate) fn track_mem_access(&mut self, effective_address: Address) -> Result<()> {
-        if self.mem_proof_enabled && self.last_mem_access != effective_address {
-            if self.last_mem_access != Address::MAX {
-                anyhow::bail!("Unexpected diffrent memory access at {:x}, already have access at {:x} buffered", effective_address, self.last_mem_access);
-            }
+    pub(crate) fn track_mem_access(&mut self, effective_address: Address) -> Result<(), VmError> {
+        self.syscall_context().track_mem_access(effective_address)
+    }
 
-            self.last_mem_access = effective_address;
-            self.mem_proof = self.state.memory.merkle_proof(effective_address)?;
-        }
-        Ok(())
+    /// Track a second access to [crate::Memory] at the given [Address]. See
+    /// [SyscallContext::track_mem_access2].
+    ///
+    /// ### Takes
+    /// - `effective_address`: The address in [crate::Memory] being accessed.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the operation was successful.
+    #[inline(always)]
+    pub(crate) fn track_mem_access2(&mut self, effective_address: Address) -> Result<(), VmError> {
+        self.syscall_context().track_mem_access2(effective_address)
+    }
+
+    /// Creates a [VmError::Fault] of the given [FaultKind] for the instruction at the current
+    /// program counter. See [SyscallContext::fault].
+    ///
+    /// ### Takes
+    /// - `kind`: The [FaultKind] of the fault.
+    ///
+    /// ### Returns
+    /// - The [VmError::Fault] describing the fault.
+    #[cold]
+    pub(crate) fn fault(&mut self, kind: FaultKind) -> VmError {
+        self.syscall_context().fault(kind)
     }
 
     /// Performs a single step of the MIPS thread context emulation.
@@ -75,16 +101,96 @@ where
     /// ### Returns
     /// - A [Result] indicating if the step was successful.
     #[inline(always)]
-    pub(crate) fn inner_step(&mut self) -> Result<()> {
+    pub(crate) fn inner_step(&mut self) -> Result<(), VmError> {
         if self.state.exited {
             return Ok(());
         }
+        // Replayed steps were already traced, charged and counted when they were first executed.
+        let hooks = !self
+            .history
+            .as_ref()
+            .is_some_and(|history| history.is_replay(&self.state));
+        if let (Some(meter), true) = (&self.cost_meter, hooks) {
+            meter.check().map_err(VmError::CostBudgetExhausted)?;
+        }
+        if let Some(history) = &mut self.history {
+            history.record(&self.state, self.call_stack.as_ref());
+        }
 
         self.state.step += 1;
 
+        if self.state.threads.is_some() && self.schedule_thread()? {
+            return Ok(());
+        }
+
         // Fetch the instruction
-        let instruction = self.state.memory.get_memory(self.state.pc as Address)?;
-        let opcode = instruction >> 26;
+        if self.state.pc & 0x3 != 0 {
+            return Err(self.fault(FaultKind::UnalignedAccess(self.state.pc)));
+        }
+        let decoded = self
+            .state
+            .memory
+            .fetch_instruction(self.state.pc as Address)?;
+
+        if self.call_stack.is_none()
+            && (!hooks
+                || self.cost_meter.is_none()
+                    && self.trace_recorder.is_none()
+                    && self.stats.is_none())
+        {
+            return self.execute_decoded(decoded);
+        }
+
+        // The class must be determined before the syscall arguments are overwritten.
+        let class = (hooks && self.cost_meter.is_some())
+            .then(|| InstructionClass::of(decoded, &self.state.registers));
+        let before = (hooks && self.trace_recorder.is_some()).then(|| {
+            self.state
+                .memory
+                .write_log
+                .get_or_insert_with(Vec::default)
+                .clear();
+            RegisterSnapshot::new(&self.state)
+        });
+        let call = self.call_stack.is_some().then(|| {
+            let thread = self.state.threads.as_ref().and_then(|t| t.current());
+            (thread.map_or(0, |t| t.thread_id), self.state.pc)
+        });
+        let syscall = match &mut self.stats {
+            Some(stats) if hooks => stats.count(decoded, &self.state),
+            _ => None,
+        };
+
+        self.execute_decoded(decoded)?;
+
+        if let (Some(meter), Some(class)) = (&mut self.cost_meter, class) {
+            meter.charge(class);
+        }
+        if let (Some(recorder), Some(before)) = (&mut self.trace_recorder, before) {
+            let writes = self.state.memory.write_log.as_deref().unwrap_or_default();
+            recorder.record(&before, decoded, &self.state, writes)?;
+        }
+        if let (Some(stack), Some((thread_id, pc))) = (&mut self.call_stack, call) {
+            stack.track(thread_id, pc, decoded, &self.state);
+        }
+        if let (Some(stats), Some(syscall)) = (&mut self.stats, syscall) {
+            stats.count_syscall(syscall, &self.state);
+        }
+        Ok(())
+    }
+
+    /// Executes a [DecodedInstruction] fetched from the current program counter. The step counter
+    /// must already have been incremented.
+    ///
+    /// ### Takes
+    /// - `decoded`: The [DecodedInstruction] at the current program counter.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the instruction was executed successfully.
+    #[inline(always)]
+    pub(crate) fn execute_decoded(&mut self, decoded: DecodedInstruction) -> Result<(), VmError> {
+        let instruction = decoded.word;
+        let opcode = decoded.opcode as u32;
 
         // j-type j/jal
         if (2..=3).contains(&opcode) {
@@ -96,26 +202,19 @@ where
         }
 
         // Register fetch
-        let mut rs = self.state.registers[((instruction >> 21) & 0x1F) as usize]; // source register 1 value
+        let mut rs = self.state.registers[decoded.rs as usize]; // source register 1 value
         let mut rt = 0; // source register 2 / temp value
-        let rt_reg = (instruction >> 16) & 0x1F;
+        let rt_reg = decoded.rt as u32;
 
         // R-type or I-type (stores rt)
         let mut rd_reg = rt_reg;
         if [0, 0x1c].contains(&opcode) {
             // R-type (stores rd)
             rt = self.state.registers[rt_reg as usize];
-            rd_reg = (instruction >> 11) & 0x1F;
+            rd_reg = decoded.rd as u32;
         } else if opcode < 20 {
-            // rt is SignExtImm
-            // Don't sign extend for andi, ori, xori
-            if (0x0c..=0x0e).contains(&opcode) {
-                // ZeroExtImm
-                rt = instruction & 0xFFFF;
-            } else {
-                // SignExtImm
-                rt = sign_extend(instruction & 0xFFFF, 16);
-            }
+            // rt is SignExtImm, or ZeroExtImm for andi, ori, xori
+            rt = decoded.imm;
         } else if opcode >= 0x28 || [0x22, 0x26].contains(&opcode) {
             // Store rt value with store
             rt = self.state.registers[rt_reg as usize];
@@ -134,12 +233,19 @@ where
         // We also do the load for stores
         if opcode >= 0x20 {
             // M[R[rs]+SignExtImm]
-            rs += sign_extend(instruction & 0xFFFF, 16);
+            rs += decoded.imm;
             let address = rs & 0xFFFFFFFC;
             self.track_mem_access(address as Address)?;
 
-            mem = self.state.memory.get_memory(address as Address)?;
-            if opcode >= 0x28 && opcode != 0x30 {
+            let store = opcode >= 0x28 && opcode != 0x30;
+            // Stores only load the word to merge the stored bytes into, which does not fire
+            // read watchpoints.
+            mem = if store {
+                self.state.memory.peek_memory(address as Address)?
+            } else {
+                self.state.memory.get_memory(address as Address)?
+            };
+            if store {
                 // Store
                 store_address = address;
                 // Store opcodes don't write back to a register
@@ -150,7 +256,7 @@ where
         // ALU
         let val = self.execute(instruction, rs, rt, mem)?;
 
-        let fun = instruction & 0x3F;
+        let fun = decoded.fun as u32;
         if opcode == 0 && (8..0x1c).contains(&fun) {
             match fun {
                 (8..=9) => {
@@ -199,31 +305,62 @@ where
     /// ### Returns
     /// - A [Result] indicating if the syscall dispatch was successful.
     #[inline(always)]
-    pub(crate) fn handle_syscall(&mut self) -> Result<()> {
+    pub(crate) fn handle_syscall(&mut self) -> Result<(), VmError> {
+        if let Some(handler) = self.syscall_handler.as_mut() {
+            let syscall = self.state.registers[2];
+            let mut context = SyscallContext {
+                state: &mut self.state,
+                last_mem_access: &mut self.last_mem_access,
+                mem_proof_enabled: self.mem_proof_enabled,
+                mem_proof: &mut self.mem_proof,
+                last_mem_access2: &mut self.last_mem_access2,
+                mem_proof2: &mut self.mem_proof2,
+            };
+            if let Some((v0, v1)) = handler.handle_syscall(syscall, &mut context)? {
+                self.complete_syscall(v0, v1);
+                return Ok(());
+            }
+        }
+
         let mut v0 = 0;
         let mut v1 = 0;
 
-        let (a0, a1, mut a2) = (
+        let (a0, a1, mut a2, a3) = (
             self.state.registers[4],
             self.state.registers[5],
             self.state.registers[6],
+            self.state.registers[7],
         );
+        let threaded = self.state.threads.is_some();
 
         if let Ok(syscall) = Syscall::try_from(self.state.registers[2]) {
             match syscall {
                 Syscall::Mmap => {
-                    let mut sz = a1;
+                    let mut sz = Some(a1);
 
                     // Adjust the size to align with the page size if the size
                     // cannot fit within the page address mask.
-                    let masked_size = sz & page::PAGE_ADDRESS_MASK as u32;
+                    let masked_size = a1 & page::PAGE_ADDRESS_MASK as u32;
                     if masked_size != 0 {
-                        sz += page::PAGE_SIZE as u32 - masked_size;
+                        sz = a1.checked_add(page::PAGE_SIZE as u32 - masked_size);
                     }
 
                     if a0 == 0 {
-                        v0 = self.state.heap;
-                        self.state.heap += sz;
+                        // The heap may neither wrap around the address space nor grow past the
+                        // heap limit.
+                        let heap_end = sz
+                            .and_then(|sz| self.state.heap.checked_add(sz))
+                            .filter(|end| *end <= self.heap_limit.unwrap_or(Address::MAX));
+                        match heap_end {
+                            Some(heap_end) => {
+                                v0 = self.state.heap;
+                                self.state.heap = heap_end;
+                            }
+                            None => {
+                                v0 = 0xFFFFFFFF;
+                                v1 = MIPS_ENOMEM;
+                            }
+                        }
                     } else {
                         v0 = a0;
                     }
@@ -232,6 +369,9 @@ where
                     v0 = 0x40000000;
                 }
                 Syscall::Clone => {
+                    if threaded {
+                        return self.handle_clone(a0, a1);
+                    }
                     // Clone is not supported, set the virtual register to 1.
                     v0 = 1;
                 }
@@ -240,7 +380,73 @@ where
                     self.state.exit_code = a0 as u8;
                     return Ok(());
                 }
+                Syscall::Exit if threaded => {
+                    return self.handle_thread_exit(a0);
+                }
+                Syscall::Futex if threaded => {
+                    return self.handle_futex(a0, a1, a2, a3);
+                }
+                Syscall::SchedYield | Syscall::Nanosleep if threaded => {
+                    return self.handle_yield();
+                }
+                Syscall::ClockGettime if threaded => {
+                    return self.handle_clock_gettime(a0, a1);
+                }
+                Syscall::Gettid if threaded => {
+                    v0 = self.current_thread_id();
+                }
+                Syscall::Getrlimit if threaded => {
+                    v0 = 0xFFFFFFFF;
+                    v1 = MIPS_ENOSYS;
+                }
+                Syscall::Exit
+                | Syscall::Futex
+                | Syscall::SchedYield
+                | Syscall::Nanosleep
+                | Syscall::ClockGettime
+                | Syscall::Gettid
+                | Syscall::Getrlimit => {
+                    // `MIPS.sol` does not implement these, so in single-threaded mode they return
+                    // 0 without side effects: there is only thread 0, it never sleeps, and the
+                    // clock never writes a time.
+                }
+                Syscall::Munmap
+                | Syscall::Madvise
+                | Syscall::RtSigaction
+                | Syscall::RtSigprocmask
+                | Syscall::Sigaltstack => {
+                    // Memory is never unmapped and signals are never delivered, so these are
+                    // no-ops that return 0.
+                }
                 Syscall::Read => match (a0 as u8).try_into() {
+                    Ok(Fd::StdIn) if self.std_in.is_some() => {
+                        let effective_address = (a1 & 0xFFFFFFFC) as Address;
+                        let alignment = (a1 & 0x3) as usize;
+                        let data_len = (4 - alignment).min(a2 as usize);
+
+                        if data_len > 0 {
+                            self.track_mem_access(effective_address)?;
+                            let mut out_mem = self
+                                .state
+                                .memory
+                                .peek_memory(effective_address)?
+                                .to_be_bytes();
+
+                            let std_in = self.std_in.as_mut().expect("stdin is set");
+                            let mut data = [0u8; 4];
+                            let n = match &mut self.history {
+                                Some(history) => {
+                                    history.read_stdin(std_in, &mut data[..data_len])?
+                                }
+                                None => std_in.read(&mut data[..data_len])?,
+                            };
+                            out_mem[alignment..alignment + n].copy_from_slice(&data[..n]);
+                            self.state
+                                .memory
+                                .set_memory(effective_address, u32::from_be_bytes(out_mem))?;
+                            v0 = n as u32;
+                        }
+                    }
                     Ok(Fd::StdIn) => {
                         // Nothing to do; Leave v0 and v1 zero, read nothing, and give no error.
                     }
@@ -248,7 +454,7 @@ where
                         let effective_address = (a1 & 0xFFFFFFFC) as Address;
 
                         self.track_mem_access(effective_address)?;
-                        let memory = self.state.memory.get_memory(effective_address)?;
+                        let memory = self.state.memory.peek_memory(effective_address)?;
 
                         let (data, mut data_len) = self
                             .read_preimage(self.state.preimage_key, self.state.preimage_offset)?;
@@ -281,6 +487,10 @@ where
                     }
                 },
                 Syscall::Write => match (a0 as u8).try_into() {
+                    Ok(Fd::Stdout | Fd::StdErr) if self.replaying() => {
+                        // The output was already written when the step was first executed.
+                        v0 = a2;
+                    }
                     Ok(fd @ (Fd::Stdout | Fd::StdErr)) => {
                         let mut reader =
                             MemoryReader::new(&mut self.state.memory, a1 as Address, a2);
@@ -302,13 +512,18 @@ where
                         // Continue processing while there is enough data to check if there are any
                         // hints.
                         while self.state.last_hint.len() >= 4 {
-                            let hint_len =
-                                u32::from_be_bytes(self.state.last_hint[..4].try_into()?);
+                            let hint_len = u32::from_be_bytes(
+                                self.state.last_hint[..4]
+                                    .try_into()
+                                    .expect("hint length prefix is 4 bytes"),
+                            );
                             if hint_len >= self.state.last_hint.len() as u32 - 4 {
                                 let hint = &self.state.last_hint[4..4 + hint_len as usize];
 
                                 // TODO(clabby): Ordering could be an issue here.
-                                self.preimage_oracle.hint(hint)?;
+                                if !self.replaying() {
+                                    self.preimage_oracle.hint(hint).map_err(VmError::Oracle)?;
+                                }
                                 self.state.last_hint =
                                     self.state.last_hint[4 + hint_len as usize..].into();
                             } else {
@@ -370,15 +585,30 @@ where
                     }
                 }
             }
+        } else {
+            let syscall = self.state.registers[2];
+            crate::warn!(target: "mipsevm::syscall", "Unknown syscall {} at pc {:08x}", syscall, self.state.pc);
+            if self.strict_syscalls {
+                return Err(self.fault(FaultKind::UnknownSyscall(syscall)));
+            }
         }
 
+        self.complete_syscall(v0, v1);
+        Ok(())
+    }
+
+    /// Completes a syscall by writing its return values and advancing the program counter.
+    ///
+    /// ### Takes
+    /// - `v0`: The return value of the syscall.
+    /// - `v1`: The error number of the syscall.
+    #[inline(always)]
+    pub(crate) fn complete_syscall(&mut self, v0: u32, v1: u32) {
         self.state.registers[2] = v0;
         self.state.registers[7] = v1;
 
         self.state.pc = self.state.next_pc;
         self.state.next_pc += 4;
-
-        Ok(())
     }
 
     /// Handles a branch within the MIPS thread context emulation.
@@ -398,9 +628,9 @@ where
         instruction: u32,
         rt_reg: u32,
         rs: u32,
-    ) -> Result<()> {
+    ) -> Result<(), VmError> {
         if self.state.next_pc != self.state.pc + 4 {
-            anyhow::bail!("Unexpected branch in delay slot at {:x}", self.state.pc,);
+            return Err(self.fault(FaultKind::BranchInDelaySlot));
         }
 
         let should_branch = match opcode {
@@ -460,7 +690,11 @@ where
         rs: u32,
         rt: u32,
         store_reg: u32,
-    ) -> Result<()> {
+    ) -> Result<(), VmError> {
+        if matches!(fun, 0x1a | 0x1b) && rt == 0 {
+            return Err(self.fault(FaultKind::DivisionByZero));
+        }
+
         let val = match fun {
             0x10 => {
                 // mfhi
@@ -482,7 +716,7 @@ where
             }
             0x18 => {
                 // mult
-                let acc = ((rs as i32) as i64) as u64 * ((rt as i32) as i64) as u64;
+                let acc = ((rs as i32) as i64 * (rt as i32) as i64) as u64;
                 self.state.hi = (acc >> 32) as u32;
                 self.state.lo = acc as u32;
                 0
@@ -496,8 +730,8 @@ where
             }
             0x1a => {
                 // div
-                self.state.hi = (rs as i32 % rt as i32) as u32;
-                self.state.lo = (rs as i32 / rt as i32) as u32;
+                self.state.hi = (rs as i32).wrapping_rem(rt as i32) as u32;
+                self.state.lo = (rs as i32).wrapping_div(rt as i32) as u32;
                 0
             }
             0x1b => {
@@ -528,9 +762,9 @@ where
     /// ### Returns
     /// - A [Result] indicating if the branch dispatch was successful.
     #[inline(always)]
-    pub(crate) fn handle_jump(&mut self, link_reg: u32, dest: u32) -> Result<()> {
+    pub(crate) fn handle_jump(&mut self, link_reg: u32, dest: u32) -> Result<(), VmError> {
         if self.state.next_pc != self.state.pc + 4 {
-            anyhow::bail!("Unexpected jump in delay slot at {:x}", self.state.pc);
+            return Err(self.fault(FaultKind::JumpInDelaySlot));
         }
 
         let prev_pc = self.state.pc;
@@ -552,9 +786,14 @@ where
     /// ### Returns
     /// - A [Result] indicating if the branch dispatch was successful.
     #[inline(always)]
-    pub(crate) fn handle_rd(&mut self, store_reg: u32, val: u32, conditional: bool) -> Result<()> {
+    pub(crate) fn handle_rd(
+        &mut self,
+        store_reg: u32,
+        val: u32,
+        conditional: bool,
+    ) -> Result<(), VmError> {
         if store_reg >= 32 {
-            anyhow::bail!("Invalid register index {}", store_reg);
+            return Err(self.fault(FaultKind::InvalidRegister(store_reg)));
         }
 
         if store_reg != 0 && conditional {
@@ -578,7 +817,13 @@ where
     /// - `Ok(n)` - The result of the instruction execution.
     /// - `Err(_)`: An error occurred while executing the instruction.
     #[inline(always)]
-    pub(crate) fn execute(&mut self, instruction: u32, rs: u32, rt: u32, mem: u32) -> Result<u32> {
+    pub(crate) fn execute(
+        &mut self,
+        instruction: u32,
+        rs: u32,
+        rt: u32,
+        mem: u32,
+    ) -> Result<u32, VmError> {
         // Opcodes in MIPS are 6 bits in size, and stored in the high-order bits of the big-endian
         // instruction.
         let opcode = instruction >> 26;
@@ -642,7 +887,7 @@ where
                 0x2a => Ok(((rs as i32) < (rt as i32)) as u32),
                 // sltiu
                 0x2b => Ok((rs < rt) as u32),
-                _ => anyhow::bail!("Invalid function code {:x}", fun),
+                _ => Err(self.fault(FaultKind::InvalidFunction(fun))),
             }
         } else {
             match opcode {
@@ -667,7 +912,7 @@ where
                             }
                             Ok(i)
                         }
-                        _ => anyhow::bail!("Invalid function code {:x}", fun),
+                        _ => Err(self.fault(FaultKind::InvalidFunction(fun))),
                     }
                 }
                 // lui
@@ -730,7 +975,7 @@ where
                 0x30 => Ok(mem),
                 // sc
                 0x38 => Ok(rt),
-                _ => anyhow::bail!("Invalid opcode {:x}", opcode),
+                _ => Err(self.fault(FaultKind::InvalidOpcode(opcode))),
             }
         }
     }
@@ -1,6 +1,30 @@
 //! The MIPS module contains the implementation of the [InstrumentedState] and the MIPS emulator.
 
+mod block;
+
+mod breakpoints;
+pub use self::breakpoints::{
+    Breakpoint, Comparison, RegisterCondition, Stop, StopReason, WatchKind, Watchpoint,
+    WatchpointHit,
+};
+pub(crate) use self::breakpoints::{Breakpoints, Watchpoints};
+
+mod delta;
+pub use self::delta::{StepDelta, ThreadsDelta};
+
+mod history;
+pub(crate) use self::history::History;
+
 mod instrumented;
 pub use self::instrumented::InstrumentedState;
 
+mod instruction;
+pub use self::instruction::{ITypeOp, Instruction, JTypeOp, RTypeOp, REGISTER_NAMES};
+
 mod mips_vm;
+pub(crate) use self::mips_vm::sign_extend;
+
+mod syscall_context;
+pub use self::syscall_context::SyscallContext;
+
+mod threaded;
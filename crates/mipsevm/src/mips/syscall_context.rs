@@ -0,0 +1,232 @@
+//! This module contains the [SyscallContext], the view of the machine state that is handed to a
+//! [crate::SyscallHandler].
+
+use crate::{Address, FaultKind, Memory, State, VmError};
+
+/// The [SyscallContext] gives a [crate::SyscallHandler] access to the registers and [Memory] of
+/// the MIPS emulator, as well as to the memory proof tracking of the current step.
+pub struct SyscallContext<'a> {
+    /// The [State] of the MIPS emulator.
+    pub(crate) state: &'a mut State,
+    /// The last address accessed in memory during the current step.
+    pub(crate) last_mem_access: &'a mut Address,
+    /// Whether or not the memory proof generation is enabled.
+    pub(crate) mem_proof_enabled: bool,
+    /// The memory proof of the current step.
+    pub(crate) mem_proof: &'a mut [u8; 28 * 32],
+    /// The second address accessed in memory during the current step, in multithreaded mode.
+    pub(crate) last_mem_access2: &'a mut Address,
+    /// The second memory proof of the current step, in multithreaded mode.
+    pub(crate) mem_proof2: &'a mut [u8; 28 * 32],
+}
+
+impl SyscallContext<'_> {
+    /// Returns the registers of the current thread.
+    pub fn registers(&self) -> &[u32; 32] {
+        &self.state.registers
+    }
+
+    /// Returns the registers of the current thread mutably. `$v0` and `$a3` are overwritten with
+    /// the return values of the syscall once it completes.
+    pub fn registers_mut(&mut self) -> &mut [u32; 32] {
+        &mut self.state.registers
+    }
+
+    /// Returns the [Memory] of the MIPS emulator. Every word read or written must first be
+    /// passed to [SyscallContext::track_mem_access].
+    pub fn memory(&mut self) -> &mut Memory {
+        &mut self.state.memory
+    }
+
+    /// Returns the current step of the MIPS emulator.
+    pub fn step(&self) -> u64 {
+        self.state.step
+    }
+
+    /// Track an access to [Memory] at the given [Address], so that it is included in the memory
+    /// proof of the current step. Only a single address may be accessed per step.
+    ///
+    /// ### Takes
+    /// - `effective_address`: The address in [Memory] being accessed.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the operation was successful.
+    #[inline(always)]
+    pub fn track_mem_access(&mut self, effective_address: Address) -> Result<(), VmError> {
+        if self.mem_proof_enabled && *self.last_mem_access != effective_address {
+            if *self.last_mem_access != Address::MAX {
+                let buffered = *self.last_mem_access;
+                return Err(self.fault(FaultKind::UnexpectedMemoryAccess {
+                    address: effective_address,
+                    buffered,
+                }));
+            }
+
+            *self.last_mem_access = effective_address;
+            *self.mem_proof = self.state.memory.merkle_proof(effective_address)?;
+        }
+        Ok(())
+    }
+
+    /// Track a second access to [Memory] at the given [Address], for the syscalls of the
+    /// multithreaded mode that write two words. The access must be tracked after the first
+    /// access has been written, so that its proof is against the updated memory.
+    ///
+    /// ### Takes
+    /// - `effective_address`: The address in [Memory] being accessed.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the operation was successful.
+    #[inline(always)]
+    pub(crate) fn track_mem_access2(&mut self, effective_address: Address) -> Result<(), VmError> {
+        if self.mem_proof_enabled && *self.last_mem_access2 != effective_address {
+            if *self.last_mem_access2 != Address::MAX {
+                let buffered = *self.last_mem_access2;
+                return Err(self.fault(FaultKind::UnexpectedMemoryAccess {
+                    address: effective_address,
+                    buffered,
+                }));
+            }
+
+            *self.last_mem_access2 = effective_address;
+            *self.mem_proof2 = self.state.memory.merkle_proof(effective_address)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a [VmError::Fault] of the given [FaultKind] for the instruction at the current
+    /// program counter.
+    ///
+    /// Faults are raised before the faulting step writes to memory, so the instruction word is
+    /// re-fetched here rather than threaded through every handler.
+    ///
+    /// ### Takes
+    /// - `kind`: The [FaultKind] of the fault.
+    ///
+    /// ### Returns
+    /// - The [VmError::Fault] describing the fault.
+    #[cold]
+    pub fn fault(&mut self, kind: FaultKind) -> VmError {
+        let pc = self.state.pc;
+        VmError::Fault {
+            step: self.state.step.saturating_sub(1),
+            pc,
+            instruction: self.state.memory.peek_memory(pc & !0x3).unwrap_or_default(),
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_utils::StaticOracle, FaultKind, Instruction, InstrumentedState, RTypeOp, State,
+        SyscallContext, SyscallHandler, VmError,
+    };
+    use std::io;
+
+    /// Adds syscall `5000`, which returns the word at `$a0` incremented by one, and overrides
+    /// `brk`.
+    struct TestHandler;
+
+    impl SyscallHandler for TestHandler {
+        fn handle_syscall(
+            &mut self,
+            syscall: u32,
+            context: &mut SyscallContext<'_>,
+        ) -> Result<Option<(u32, u32)>, VmError> {
+            match syscall {
+                5000 => {
+                    let address = context.registers()[4];
+                    context.track_mem_access(address)?;
+                    let word = context.memory().get_memory(address)?;
+                    if context.registers()[5] != 0 {
+                        context.track_mem_access(address + 4)?;
+                    }
+                    Ok(Some((word + 1, 0)))
+                }
+                4045 => Ok(Some((0x50000000, 0))),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    fn run_syscall(
+        registers: &[(usize, u32)],
+        proof: bool,
+    ) -> (
+        Result<(), VmError>,
+        InstrumentedState<io::Sink, io::Sink, StaticOracle>,
+    ) {
+        let mut state = State {
+            next_pc: 4,
+            ..Default::default()
+        };
+        let syscall = Instruction::R {
+            op: RTypeOp::Syscall,
+            rs: 0,
+            rt: 0,
+            rd: 0,
+            shamt: 0,
+        };
+        state.memory.set_memory(0, syscall.encode()).unwrap();
+        state.memory.set_memory(0x100, 41).unwrap();
+        for (register, value) in registers {
+            state.registers[*register] = *value;
+        }
+
+        let mut ins = InstrumentedState::new(
+            state,
+            StaticOracle::new(b"hello world".to_vec()),
+            io::sink(),
+            io::sink(),
+        )
+        .with_syscall_handler(TestHandler);
+        let res = ins.step(proof).map(|_| ());
+        (res, ins)
+    }
+
+    #[test]
+    fn added_syscall() {
+        let (res, ins) = run_syscall(&[(2, 5000), (4, 0x100)], true);
+        res.unwrap();
+        assert_eq!(ins.state.registers[2], 42);
+        assert_eq!(ins.state.registers[7], 0);
+        assert_eq!(ins.state.pc, 4);
+        assert_eq!(ins.last_mem_access, 0x100);
+    }
+
+    #[test]
+    fn overridden_syscall() {
+        let (res, ins) = run_syscall(&[(2, 4045)], false);
+        res.unwrap();
+        assert_eq!(ins.state.registers[2], 0x50000000);
+    }
+
+    #[test]
+    fn unhandled_syscall() {
+        let (res, ins) = run_syscall(&[(2, 4090), (5, 0x1000)], false);
+        res.unwrap();
+        assert_eq!(ins.state.registers[2], 0);
+        assert_eq!(ins.state.heap, 0x1000);
+    }
+
+    #[test]
+    fn tracked_access_fault() {
+        let (res, _) = run_syscall(&[(2, 5000), (4, 0x100), (5, 1)], true);
+        match res {
+            Err(VmError::Fault { kind, .. }) => assert_eq!(
+                kind,
+                FaultKind::UnexpectedMemoryAccess {
+                    address: 0x104,
+                    buffered: 0x100
+                }
+            ),
+            res => panic!("expected fault, got {:?}", res),
+        }
+
+        // Without proofs, accesses are not tracked.
+        let (res, _) = run_syscall(&[(2, 5000), (4, 0x100), (5, 1)], false);
+        res.unwrap();
+    }
+}
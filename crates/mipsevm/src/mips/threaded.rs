@@ -0,0 +1,615 @@
+//! This module contains the thread scheduler and the thread syscalls of the [InstrumentedState]
+//! when it is running in multithreaded mode.
+
+use crate::{
+    mips::instrumented::{MIPS_EAGAIN, MIPS_EINVAL, MIPS_ETIMEDOUT},
+    Address, InstrumentedState, PreimageOracle, ThreadState, VMStatus, VmError, FUTEX_EMPTY_ADDR,
+    FUTEX_NO_TIMEOUT, FUTEX_TIMEOUT_STEPS, SCHED_QUANTUM, STEPS_PER_SECOND, VALID_CLONE_FLAGS,
+};
+use std::io::Write;
+
+/// The `futex` operation that waits on an address.
+pub(crate) const FUTEX_WAIT_PRIVATE: u32 = 128;
+/// The `futex` operation that wakes the threads waiting on an address.
+pub(crate) const FUTEX_WAKE_PRIVATE: u32 = 129;
+/// The `clock_gettime` clock measuring wall-clock time.
+pub(crate) const CLOCK_REALTIME: u32 = 0;
+/// The `clock_gettime` clock measuring monotonic time.
+pub(crate) const CLOCK_MONOTONIC: u32 = 1;
+
+impl<O, E, P> InstrumentedState<O, E, P>
+where
+    O: Write,
+    E: Write,
+    P: PreimageOracle,
+{
+    /// Schedules the current thread. Steps that wake up, time out, preempt or retire threads do
+    /// not execute an instruction.
+    ///
+    /// ### Returns
+    /// - `Ok(true)`: The step was consumed by the scheduler.
+    /// - `Ok(false)`: The current thread may execute its next instruction.
+    /// - `Err(_)`: An error occurred while checking the futex of the current thread.
+    #[inline(always)]
+    pub(crate) fn schedule_thread(&mut self) -> Result<bool, VmError> {
+        let threads = self.state.threads.as_mut().expect("State is multithreaded");
+        let thread = threads.current().expect("A thread is alive");
+        let (exited, futex_addr, futex_val, futex_timeout_step) = (
+            thread.exited,
+            thread.futex_addr,
+            thread.futex_val,
+            thread.futex_timeout_step,
+        );
+
+        // During a wakeup traversal, search for the first thread waiting on the wakeup address.
+        // No thread may execute until one is found, or until all threads have been visited.
+        if threads.wakeup != FUTEX_EMPTY_ADDR {
+            if threads.wakeup == futex_addr {
+                self.complete_futex_wait(false);
+            } else {
+                let traversing_right = threads.traverse_right;
+                let changed_directions = self.state.preempt_thread();
+                if traversing_right && changed_directions {
+                    // The traversal started on the left stack and has now walked all the way
+                    // right, so every thread has been visited.
+                    let threads = self.state.threads.as_mut().expect("State is multithreaded");
+                    threads.wakeup = FUTEX_EMPTY_ADDR;
+                }
+            }
+            return Ok(true);
+        }
+
+        if exited {
+            self.state.pop_thread();
+            return Ok(true);
+        }
+
+        if futex_addr != FUTEX_EMPTY_ADDR {
+            if self.state.step > futex_timeout_step {
+                self.complete_futex_wait(true);
+            } else {
+                self.track_mem_access(futex_addr)?;
+                if self.state.memory.get_memory(futex_addr)? == futex_val {
+                    // The value is unchanged, keep waiting and try the next thread.
+                    self.state.preempt_thread();
+                } else {
+                    // The value changed, so wake the thread up. Spurious wakeups are handled in
+                    // userspace.
+                    self.complete_futex_wait(false);
+                }
+            }
+            return Ok(true);
+        }
+
+        if threads.steps_since_last_context_switch >= SCHED_QUANTUM {
+            self.state.preempt_thread();
+            return Ok(true);
+        }
+        threads.steps_since_last_context_switch += 1;
+
+        Ok(false)
+    }
+
+    /// Handles the `clone` syscall by creating a new thread that shares the memory of the
+    /// current thread and scheduling it.
+    ///
+    /// ### Takes
+    /// - `flags`: The clone flags. Only [VALID_CLONE_FLAGS] are supported.
+    /// - `stack_ptr`: The stack pointer of the new thread.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the syscall was successful.
+    pub(crate) fn handle_clone(&mut self, flags: u32, stack_ptr: u32) -> Result<(), VmError> {
+        if flags != VALID_CLONE_FLAGS {
+            self.state.exited = true;
+            self.state.exit_code = VMStatus::Panic as u8;
+            return Ok(());
+        }
+
+        let threads = self.state.threads.as_mut().expect("State is multithreaded");
+        let thread_id = threads.next_thread_id;
+        threads.next_thread_id += 1;
+
+        // The child perceives a return value of 0 and no error.
+        let mut thread = ThreadState::new(thread_id);
+        thread.pc = self.state.next_pc;
+        thread.next_pc = self.state.next_pc + 4;
+        thread.lo = self.state.lo;
+        thread.hi = self.state.hi;
+        thread.registers = self.state.registers;
+        thread.registers[29] = stack_ptr;
+        thread.registers[2] = 0;
+        thread.registers[7] = 0;
+
+        self.complete_syscall(thread_id, 0);
+        self.state.push_thread(thread);
+        Ok(())
+    }
+
+    /// Handles the `exit` syscall by marking the current thread as exited. The thread is
+    /// retired by the scheduler on the next step. If it is the last thread, the VM exits.
+    ///
+    /// ### Takes
+    /// - `exit_code`: The exit code of the thread.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the syscall was successful.
+    pub(crate) fn handle_thread_exit(&mut self, exit_code: u32) -> Result<(), VmError> {
+        let threads = self.state.threads.as_mut().expect("State is multithreaded");
+        let last_thread = threads.thread_count() == 1;
+        let thread = threads.current_mut().expect("A thread is alive");
+        thread.exited = true;
+        thread.exit_code = exit_code as u8;
+
+        if last_thread {
+            self.state.exited = true;
+            self.state.exit_code = exit_code as u8;
+        }
+        Ok(())
+    }
+
+    /// Handles the `futex` syscall. Only `FUTEX_WAIT_PRIVATE` and `FUTEX_WAKE_PRIVATE` are
+    /// supported.
+    ///
+    /// ### Takes
+    /// - `addr`: The address of the futex word.
+    /// - `op`: The futex operation.
+    /// - `val`: The value expected at `addr` when waiting.
+    /// - `timeout`: The timeout pointer. Any non-zero timeout expires after
+    ///   [FUTEX_TIMEOUT_STEPS] steps.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the syscall was successful.
+    pub(crate) fn handle_futex(
+        &mut self,
+        addr: u32,
+        op: u32,
+        val: u32,
+        timeout: u32,
+    ) -> Result<(), VmError> {
+        let effective_address = (addr & 0xFFFFFFFC) as Address;
+        match op {
+            FUTEX_WAIT_PRIVATE => {
+                self.track_mem_access(effective_address)?;
+                if self.state.memory.get_memory(effective_address)? != val {
+                    self.complete_syscall(0xFFFFFFFF, MIPS_EAGAIN);
+                    return Ok(());
+                }
+
+                let step = self.state.step;
+                let threads = self.state.threads.as_mut().expect("State is multithreaded");
+                let thread = threads.current_mut().expect("A thread is alive");
+                thread.futex_addr = effective_address;
+                thread.futex_val = val;
+                thread.futex_timeout_step = if timeout == 0 {
+                    FUTEX_NO_TIMEOUT
+                } else {
+                    step + FUTEX_TIMEOUT_STEPS
+                };
+                // The syscall is completed by the scheduler once the wait is over.
+            }
+            FUTEX_WAKE_PRIVATE => {
+                // Don't indicate to the program whether a waiting thread was woken up, as there
+                // are no guarantees. The woken up thread signals this in userspace.
+                self.complete_syscall(0, 0);
+                self.state.preempt_thread();
+
+                // Traverse the threads, starting from the left stack, until one is found that is
+                // waiting on the wakeup address.
+                let threads = self.state.threads.as_mut().expect("State is multithreaded");
+                threads.wakeup = effective_address;
+                threads.traverse_right = threads.left_thread_stack.is_empty();
+            }
+            _ => self.complete_syscall(0xFFFFFFFF, MIPS_EINVAL),
+        }
+        Ok(())
+    }
+
+    /// Handles the `sched_yield` and `nanosleep` syscalls by preempting the current thread.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the syscall was successful.
+    pub(crate) fn handle_yield(&mut self) -> Result<(), VmError> {
+        self.complete_syscall(0, 0);
+        self.state.preempt_thread();
+        Ok(())
+    }
+
+    /// Handles the `clock_gettime` syscall by writing a time derived from the current step, at
+    /// [STEPS_PER_SECOND], to the `timespec` at the given address.
+    ///
+    /// ### Takes
+    /// - `clock_id`: The clock to read. Only `CLOCK_REALTIME` and `CLOCK_MONOTONIC` are
+    ///   supported, and both report the same time.
+    /// - `timespec`: The address of the `timespec` to write.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the syscall was successful.
+    pub(crate) fn handle_clock_gettime(
+        &mut self,
+        clock_id: u32,
+        timespec: u32,
+    ) -> Result<(), VmError> {
+        if !matches!(clock_id, CLOCK_REALTIME | CLOCK_MONOTONIC) {
+            self.complete_syscall(0xFFFFFFFF, MIPS_EINVAL);
+            return Ok(());
+        }
+
+        let secs = (self.state.step / STEPS_PER_SECOND) as u32;
+        let nsecs =
+            (self.state.step % STEPS_PER_SECOND * (1_000_000_000 / STEPS_PER_SECOND)) as u32;
+
+        let effective_address = (timespec & 0xFFFFFFFC) as Address;
+        self.track_mem_access(effective_address)?;
+        self.state.memory.set_memory(effective_address, secs)?;
+        self.track_mem_access2(effective_address + 4)?;
+        self.state.memory.set_memory(effective_address + 4, nsecs)?;
+
+        self.complete_syscall(0, 0);
+        Ok(())
+    }
+
+    /// Returns the identifier of the current thread.
+    pub(crate) fn current_thread_id(&self) -> u32 {
+        self.state
+            .threads
+            .as_ref()
+            .and_then(|threads| threads.current())
+            .map_or(0, |thread| thread.thread_id)
+    }
+
+    /// Completes the `futex` wait of the current thread and clears the wakeup address.
+    ///
+    /// ### Takes
+    /// - `timed_out`: Whether the wait timed out.
+    fn complete_futex_wait(&mut self, timed_out: bool) {
+        let threads = self.state.threads.as_mut().expect("State is multithreaded");
+        threads.wakeup = FUTEX_EMPTY_ADDR;
+        let thread = threads.current_mut().expect("A thread is alive");
+        thread.futex_addr = FUTEX_EMPTY_ADDR;
+        thread.futex_val = 0;
+        thread.futex_timeout_step = 0;
+
+        if timed_out {
+            self.complete_syscall(0xFFFFFFFF, MIPS_ETIMEDOUT);
+        } else {
+            self.complete_syscall(0, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        load_elf, patch,
+        test_utils::{program, StaticOracle},
+        ITypeOp, Instruction, InstrumentedState, RTypeOp, FUTEX_EMPTY_ADDR, MT_STATE_WITNESS_SIZE,
+        SCHED_QUANTUM, STEPS_PER_SECOND, THREAD_PROOF_SIZE, VALID_CLONE_FLAGS,
+    };
+    use std::io::{self, BufWriter};
+
+    /// Assemble `ori $rt, $zero, imm`.
+    fn li(rt: u8, imm: u16) -> u32 {
+        Instruction::I {
+            op: ITypeOp::Ori,
+            rs: 0,
+            rt,
+            imm,
+        }
+        .encode()
+    }
+
+    /// Assemble `syscall`.
+    fn syscall() -> u32 {
+        Instruction::R {
+            op: RTypeOp::Syscall,
+            rs: 0,
+            rt: 0,
+            rd: 0,
+            shamt: 0,
+        }
+        .encode()
+    }
+
+    /// Build a multithreaded [InstrumentedState] running `instructions` from address 0.
+    fn threaded(instructions: &[u32]) -> InstrumentedState<io::Sink, io::Sink, StaticOracle> {
+        let mut ins = program(instructions);
+        ins.state.enable_threading();
+        ins
+    }
+
+    /// The program counters of the threads on the left and right thread stacks.
+    fn thread_pcs(ins: &mut InstrumentedState<io::Sink, io::Sink, StaticOracle>) -> Vec<u32> {
+        ins.state.save_thread_context();
+        let threads = ins.state.threads.as_ref().unwrap();
+        threads
+            .left_thread_stack
+            .iter()
+            .chain(threads.right_thread_stack.iter())
+            .map(|t| t.pc)
+            .collect()
+    }
+
+    #[test]
+    fn clone_thread() {
+        let mut ins = threaded(&[
+            li(2, 4120),
+            Instruction::I {
+                op: ITypeOp::Lui,
+                rs: 0,
+                rt: 4,
+                imm: (VALID_CLONE_FLAGS >> 16) as u16,
+            }
+            .encode(),
+            Instruction::I {
+                op: ITypeOp::Ori,
+                rs: 4,
+                rt: 4,
+                imm: VALID_CLONE_FLAGS as u16,
+            }
+            .encode(),
+            li(5, 0x8000),
+            syscall(),
+        ]);
+
+        for _ in 0..5 {
+            ins.step(false).unwrap();
+        }
+
+        // The child is now the current thread.
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.thread_count(), 2);
+        assert_eq!(threads.next_thread_id, 2);
+        assert_eq!(threads.current().unwrap().thread_id, 1);
+        assert_eq!(ins.state.pc, 20);
+        assert_eq!(ins.state.registers[2], 0);
+        assert_eq!(ins.state.registers[29], 0x8000);
+
+        let parent = &threads.left_thread_stack[0];
+        assert_eq!(parent.thread_id, 0);
+        assert_eq!(parent.pc, 20);
+        assert_eq!(parent.registers[2], 1);
+        assert_eq!(parent.registers[29], 0);
+    }
+
+    #[test]
+    fn invalid_clone_flags() {
+        let mut ins = threaded(&[li(2, 4120), li(4, 0x100), syscall()]);
+        for _ in 0..3 {
+            ins.step(false).unwrap();
+        }
+
+        assert!(ins.state.exited);
+        assert_eq!(ins.state.exit_code, 2);
+    }
+
+    #[test]
+    fn preemption() {
+        // Two threads spinning on `j 0` / `nop`.
+        let mut ins = threaded(&[
+            Instruction::J {
+                op: crate::JTypeOp::J,
+                target: 0,
+            }
+            .encode(),
+            0,
+        ]);
+        let mut thread = crate::ThreadState::new(1);
+        thread.pc = 0;
+        thread.next_pc = 4;
+        ins.state.push_thread(thread);
+
+        for _ in 0..SCHED_QUANTUM {
+            ins.step(false).unwrap();
+        }
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.current().unwrap().thread_id, 1);
+        assert_eq!(threads.steps_since_last_context_switch, SCHED_QUANTUM);
+
+        ins.step(false).unwrap();
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.current().unwrap().thread_id, 0);
+        assert_eq!(threads.steps_since_last_context_switch, 0);
+        assert_eq!(thread_pcs(&mut ins), [0, 0]);
+    }
+
+    #[test]
+    fn futex_wait_wake() {
+        // Thread 0 waits on the word at 0x100 while it holds 0, then exits.
+        // Thread 1 stores 1 to 0x100, wakes the waiters, then spins.
+        let sw = Instruction::I {
+            op: ITypeOp::Sw,
+            rs: 0,
+            rt: 6,
+            imm: 0x100,
+        }
+        .encode();
+        let mut ins = threaded(&[
+            // 0x00: thread 0
+            li(2, 4238),
+            li(4, 0x100),
+            li(5, 128),
+            li(6, 0),
+            syscall(),
+            li(2, 4001),
+            li(4, 7),
+            syscall(),
+            // 0x20: thread 1
+            li(6, 1),
+            sw,
+            li(2, 4238),
+            li(4, 0x100),
+            li(5, 129),
+            syscall(),
+            Instruction::J {
+                op: crate::JTypeOp::J,
+                target: 0x38 >> 2,
+            }
+            .encode(),
+            0,
+        ]);
+
+        // Schedule thread 1 below thread 0 on the left stack.
+        let threads = ins.state.threads.as_mut().unwrap();
+        let mut thread = crate::ThreadState::new(1);
+        thread.pc = 0x20;
+        thread.next_pc = 0x24;
+        threads.left_thread_stack.insert(0, thread);
+        threads.next_thread_id = 2;
+
+        // Thread 0 enters the wait, and is preempted on the next step.
+        for _ in 0..5 {
+            ins.step(false).unwrap();
+        }
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.current().unwrap().futex_addr, 0x100);
+        assert_eq!(ins.state.pc, 0x10);
+        ins.step(true).unwrap();
+        assert_eq!(ins.state.pc, 0x20);
+
+        // Thread 1 stores and wakes.
+        for _ in 0..6 {
+            ins.step(false).unwrap();
+        }
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.wakeup, 0x100);
+        assert!(threads.left_thread_stack.is_empty());
+
+        // The traversal preempts thread 1, then finds thread 0.
+        ins.step(false).unwrap();
+        assert_eq!(ins.state.threads.as_ref().unwrap().wakeup, 0x100);
+        ins.step(false).unwrap();
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.wakeup, FUTEX_EMPTY_ADDR);
+        assert_eq!(threads.current().unwrap().thread_id, 0);
+        assert_eq!(threads.current().unwrap().futex_addr, FUTEX_EMPTY_ADDR);
+        assert_eq!(ins.state.pc, 0x14);
+        assert_eq!(ins.state.registers[2], 0);
+
+        // Thread 0 exits, is retired, and thread 1 keeps spinning.
+        for _ in 0..4 {
+            ins.step(false).unwrap();
+        }
+        let threads = ins.state.threads.as_ref().unwrap();
+        assert_eq!(threads.thread_count(), 1);
+        assert_eq!(threads.current().unwrap().thread_id, 1);
+        assert!(!ins.state.exited);
+    }
+
+    #[test]
+    fn futex_wait_mismatch() {
+        let mut ins = threaded(&[li(2, 4238), li(4, 0x100), li(5, 128), li(6, 1), syscall()]);
+        for _ in 0..5 {
+            ins.step(false).unwrap();
+        }
+
+        assert_eq!(ins.state.registers[2], 0xFFFFFFFF);
+        assert_eq!(ins.state.registers[7], super::MIPS_EAGAIN);
+        assert_eq!(ins.state.pc, 0x14);
+    }
+
+    #[test]
+    fn last_thread_exit() {
+        let mut ins = threaded(&[li(2, 4001), li(4, 3), syscall()]);
+        for _ in 0..3 {
+            ins.step(true).unwrap();
+        }
+
+        assert!(ins.state.exited);
+        assert_eq!(ins.state.exit_code, 3);
+    }
+
+    #[test]
+    fn clock_gettime() {
+        let mut ins = threaded(&[li(2, 4263), li(4, 1), li(5, 0x104), syscall()]);
+        ins.state.step = 3 * STEPS_PER_SECOND + 41;
+        // The syscall is executed at step `3 * STEPS_PER_SECOND + 45`.
+        for _ in 0..4 {
+            ins.step(true).unwrap();
+        }
+
+        assert_eq!(ins.state.registers[2], 0);
+        assert_eq!(ins.state.memory.get_memory(0x104).unwrap(), 3);
+        assert_eq!(ins.state.memory.get_memory(0x108).unwrap(), 4500);
+
+        // Both words are proven, the second one against the memory with the first one written.
+        let mut ins = threaded(&[li(2, 4263), li(4, 0), li(5, 0x11c), syscall()]);
+        for _ in 0..3 {
+            ins.step(false).unwrap();
+        }
+        let witness = ins.step(true).unwrap().unwrap();
+        assert_eq!(witness.state.len(), MT_STATE_WITNESS_SIZE);
+        assert_eq!(witness.mem_proof.len(), THREAD_PROOF_SIZE + 28 * 32 * 3);
+
+        let mem_proof = &witness.mem_proof[THREAD_PROOF_SIZE + 28 * 32..];
+        ins.state.memory.set_memory(0x120, 0).unwrap();
+        assert_eq!(
+            mem_proof[..28 * 32],
+            ins.state.memory.merkle_proof(0x11c).unwrap()
+        );
+        assert_eq!(
+            mem_proof[28 * 32..],
+            ins.state.memory.merkle_proof(0x120).unwrap()
+        );
+
+        let mut ins = threaded(&[li(2, 4263), li(4, 2), li(5, 0x100), syscall()]);
+        for _ in 0..4 {
+            ins.step(false).unwrap();
+        }
+        assert_eq!(ins.state.registers[2], 0xFFFFFFFF);
+        assert_eq!(ins.state.registers[7], super::MIPS_EINVAL);
+    }
+
+    #[test]
+    fn gettid_getrlimit() {
+        let mut ins = threaded(&[li(2, 4222), syscall(), li(2, 4076), syscall()]);
+        let mut thread = crate::ThreadState::new(5);
+        thread.next_pc = 4;
+        ins.state.push_thread(thread);
+
+        for _ in 0..2 {
+            ins.step(false).unwrap();
+        }
+        assert_eq!(ins.state.registers[2], 5);
+
+        for _ in 0..2 {
+            ins.step(false).unwrap();
+        }
+        assert_eq!(ins.state.registers[2], 0xFFFFFFFF);
+        assert_eq!(
+            ins.state.registers[7],
+            crate::mips::instrumented::MIPS_ENOSYS
+        );
+    }
+
+    #[test]
+    fn test_hello_threaded() {
+        let elf_bytes = include_bytes!("../../../../example/bin/hello.elf");
+        let mut state = load_elf(elf_bytes).unwrap();
+        patch::patch_stack(&mut state).unwrap();
+        state.enable_threading();
+
+        let out = BufWriter::new(Vec::default());
+        let err = BufWriter::new(Vec::default());
+        let mut ins =
+            InstrumentedState::new(state, StaticOracle::new(b"hello world".to_vec()), out, err);
+
+        for _ in 0..2_000_000 {
+            if ins.state.exited {
+                break;
+            }
+            ins.step(false).unwrap();
+        }
+
+        assert!(ins.state.exited, "must exit");
+        assert_eq!(ins.state.exit_code, 0, "must exit with 0");
+        assert!(
+            ins.state.threads.as_ref().unwrap().next_thread_id > 1,
+            "runtime must spawn threads"
+        );
+        assert_eq!(
+            String::from_utf8(ins.std_out.buffer().to_vec()).unwrap(),
+            "hello world!\n"
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! This module contains the [TraceRecorder], which writes a compact binary [TraceRecord] for every
+//! instruction executed by the MIPS emulator.
+//!
+//! ## Format
+//!
+//! A trace starts with the 4 byte [TRACE_MAGIC] and the big-endian `u16` [TRACE_VERSION],
+//! followed by one record per executed instruction. All integers are big-endian.
+//!
+//! | Field            | Size                  | Description                                     |
+//! |------------------|-----------------------|-------------------------------------------------|
+//! | `step`           | 8                     | The step the instruction was executed at.       |
+//! | `pc`             | 4                     | The program counter of the instruction.         |
+//! | `instruction`    | 4                     | The instruction word.                           |
+//! | `flags`          | 1                     | Bit 0 is set if the record has a syscall.       |
+//! | `register_count` | 1                     | The number of register writes.                  |
+//! | `memory_count`   | 4                     | The number of memory writes.                    |
+//! | `registers`      | 5 * `register_count`  | The `u8` register index and `u32` new value.    |
+//! | `memory`         | 8 * `memory_count`    | The `u32` address and `u32` new value.          |
+//! | `syscall`        | 12, if flagged        | The `u32` syscall number, `v0` and `v1`.        |
+
+use crate::{icache::DecodedInstruction, Address, State};
+use std::io::{self, BufWriter, Write};
+
+/// The magic bytes at the start of a trace.
+pub const TRACE_MAGIC: [u8; 4] = *b"CNTR";
+
+/// The version of the trace format.
+pub const TRACE_VERSION: u16 = 1;
+
+/// The index of `lo` in [TraceRecord::register_writes].
+pub const TRACE_REGISTER_LO: u8 = 32;
+
+/// The index of `hi` in [TraceRecord::register_writes].
+pub const TRACE_REGISTER_HI: u8 = 33;
+
+/// The flag set on records that have a [SyscallRecord].
+const FLAG_SYSCALL: u8 = 1;
+
+/// A [SyscallRecord] describes a syscall made by a traced instruction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallRecord {
+    /// The syscall number, from `$v0` before the syscall.
+    pub number: u32,
+    /// The first result, in `$v0` after the syscall.
+    pub v0: u32,
+    /// The second result, in `$a3` after the syscall.
+    pub v1: u32,
+}
+
+/// A [TraceRecord] describes the effects of a single executed instruction.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// The step the instruction was executed at, counting from 0.
+    pub step: u64,
+    /// The program counter of the instruction.
+    pub pc: Address,
+    /// The instruction word.
+    pub instruction: u32,
+    /// The registers whose value changed, with their new value. Registers 0-31 are the general
+    /// purpose registers, followed by [TRACE_REGISTER_LO] and [TRACE_REGISTER_HI].
+    pub register_writes: Vec<(u8, u32)>,
+    /// The words written to memory, with their new value.
+    pub memory_writes: Vec<(Address, u32)>,
+    /// The syscall made by the instruction, if any.
+    pub syscall: Option<SyscallRecord>,
+}
+
+impl TraceRecord {
+    /// Encodes the [TraceRecord] into the given writer.
+    ///
+    /// ### Takes
+    /// - `writer`: The writer to encode the record into.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the record was written successfully. Fails without writing
+    ///   anything if a count does not fit its field.
+    pub fn encode(&self, writer: &mut impl Write) -> io::Result<()> {
+        let flags = if self.syscall.is_some() {
+            FLAG_SYSCALL
+        } else {
+            0
+        };
+        let register_count = u8::try_from(self.register_writes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Too many register writes"))?;
+        let memory_count = u32::try_from(self.memory_writes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Too many memory writes"))?;
+        writer.write_all(&self.step.to_be_bytes())?;
+        writer.write_all(&self.pc.to_be_bytes())?;
+        writer.write_all(&self.instruction.to_be_bytes())?;
+        writer.write_all(&[flags, register_count])?;
+        writer.write_all(&memory_count.to_be_bytes())?;
+        for (register, value) in &self.register_writes {
+            writer.write_all(&[*register])?;
+            writer.write_all(&value.to_be_bytes())?;
+        }
+        for (address, value) in &self.memory_writes {
+            writer.write_all(&address.to_be_bytes())?;
+            writer.write_all(&value.to_be_bytes())?;
+        }
+        if let Some(syscall) = &self.syscall {
+            writer.write_all(&syscall.number.to_be_bytes())?;
+            writer.write_all(&syscall.v0.to_be_bytes())?;
+            writer.write_all(&syscall.v1.to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// The registers of a [State] before an instruction is executed, used to find the registers
+/// it changed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RegisterSnapshot {
+    /// The program counter.
+    pc: Address,
+    /// The general purpose registers, followed by `lo` and `hi`.
+    registers: [u32; 34],
+}
+
+impl RegisterSnapshot {
+    /// Takes a [RegisterSnapshot] of the given [State].
+    #[inline(always)]
+    pub(crate) fn new(state: &State) -> Self {
+        let mut registers = [0u32; 34];
+        registers[..32].copy_from_slice(&state.registers);
+        registers[TRACE_REGISTER_LO as usize] = state.lo;
+        registers[TRACE_REGISTER_HI as usize] = state.hi;
+        Self {
+            pc: state.pc,
+            registers,
+        }
+    }
+}
+
+/// The [TraceRecorder] writes a [TraceRecord] for every instruction executed by the
+/// [crate::InstrumentedState] it is attached to.
+///
+/// Faulting instructions and steps that only switch threads in multithreaded mode are not
+/// recorded.
+pub struct TraceRecorder {
+    /// The buffered trace output.
+    writer: BufWriter<Box<dyn Write + Send>>,
+    /// The record of the current step, reused across steps.
+    record: TraceRecord,
+}
+
+impl TraceRecorder {
+    /// Creates a new [TraceRecorder], writing the trace header to the given writer.
+    ///
+    /// ### Takes
+    /// - `writer`: The writer to write the trace to.
+    ///
+    /// ### Returns
+    /// - The new [TraceRecorder], or an error if the header could not be written.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer = BufWriter::new(Box::new(writer) as Box<dyn Write + Send>);
+        writer.write_all(&TRACE_MAGIC)?;
+        writer.write_all(&TRACE_VERSION.to_be_bytes())?;
+        Ok(Self {
+            writer,
+            record: TraceRecord::default(),
+        })
+    }
+
+    /// Records an executed instruction.
+    ///
+    /// ### Takes
+    /// - `before`: The [RegisterSnapshot] taken before the instruction was executed.
+    /// - `decoded`: The executed [DecodedInstruction].
+    /// - `state`: The [State] after the instruction was executed.
+    /// - `memory_writes`: The address, previous value and new value of the words written to
+    ///   memory by the instruction.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the record was written successfully.
+    pub(crate) fn record(
+        &mut self,
+        before: &RegisterSnapshot,
+        decoded: DecodedInstruction,
+        state: &State,
+        memory_writes: &[(Address, u32, u32)],
+    ) -> io::Result<()> {
+        let after = RegisterSnapshot::new(state);
+        let record = &mut self.record;
+        record.step = state.step - 1;
+        record.pc = before.pc;
+        record.instruction = decoded.word;
+        record.register_writes.clear();
+        record.register_writes.extend(
+            (0..after.registers.len())
+                .filter(|i| before.registers[*i] != after.registers[*i])
+                .map(|i| (i as u8, after.registers[i])),
+        );
+        record.memory_writes.clear();
+        record.memory_writes.extend(
+            memory_writes
+                .iter()
+                .map(|(address, _, value)| (*address, *value)),
+        );
+        record.syscall = (decoded.opcode == 0 && decoded.fun == 0x0C).then(|| SyscallRecord {
+            number: before.registers[2],
+            v0: after.registers[2],
+            v1: after.registers[7],
+        });
+        record.encode(&mut self.writer)
+    }
+
+    /// Flushes the buffered trace to the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SyscallRecord, TraceRecord, TraceRecorder, TRACE_REGISTER_LO};
+    use crate::test_utils::{program, SharedBuffer};
+
+    #[test]
+    fn record() {
+        let instructions = [
+            0x24080007, // addiu $t0, $zero, 7
+            0xAC080100, // sw $t0, 0x100($zero)
+            0x01080019, // multu $t0, $t0
+            0x24021096, // addiu $v0, $zero, 4246 (exit_group)
+            0x0000000C, // syscall
+        ];
+
+        let buffer = SharedBuffer::default();
+        let mut ins =
+            program(&instructions).with_trace_recorder(TraceRecorder::new(buffer.clone()).unwrap());
+        ins.run(u64::MAX).unwrap();
+        assert!(ins.state.exited);
+        ins.trace_recorder.as_mut().unwrap().flush().unwrap();
+
+        let expected = [
+            TraceRecord {
+                step: 0,
+                pc: 0,
+                instruction: instructions[0],
+                register_writes: vec![(8, 7)],
+                ..Default::default()
+            },
+            TraceRecord {
+                step: 1,
+                pc: 4,
+                instruction: instructions[1],
+                memory_writes: vec![(0x100, 7)],
+                ..Default::default()
+            },
+            TraceRecord {
+                step: 2,
+                pc: 8,
+                instruction: instructions[2],
+                register_writes: vec![(TRACE_REGISTER_LO, 49)],
+                ..Default::default()
+            },
+            TraceRecord {
+                step: 3,
+                pc: 12,
+                instruction: instructions[3],
+                register_writes: vec![(2, 4246)],
+                ..Default::default()
+            },
+            TraceRecord {
+                step: 4,
+                pc: 16,
+                instruction: instructions[4],
+                syscall: Some(SyscallRecord {
+                    number: 4246,
+                    v0: 4246,
+                    v1: 0,
+                }),
+                ..Default::default()
+            },
+        ];
+        let mut encoded = b"CNTR\x00\x01".to_vec();
+        for record in &expected {
+            record.encode(&mut encoded).unwrap();
+        }
+        assert_eq!(*buffer.0.lock().unwrap(), encoded);
+    }
+}
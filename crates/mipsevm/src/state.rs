@@ -1,6 +1,9 @@
 //! This module contains the data structure for the state of the MIPS emulator.
 
-use crate::{witness::STATE_WITNESS_SIZE, Memory, StateWitness, VMStatus};
+use crate::{
+    witness::{MT_STATE_WITNESS_SIZE, STATE_WITNESS_SIZE},
+    Memory, MtStateWitness, StateWitness, StateWitnessHasher, Threads, VMStatus,
+};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
@@ -18,7 +21,8 @@ pub struct State {
     pub preimage_key: [u8; 32],
     /// The preimage offset.
     pub preimage_offset: u32,
-    /// The current program counter.
+    /// The current program counter. In multithreaded mode, `pc`, `next_pc`, `lo`, `hi` and
+    /// `registers` hold the execution context of the current thread.
     pub pc: u32,
     /// The next program counter.
     pub next_pc: u32,
@@ -39,16 +43,48 @@ pub struct State {
     /// The last hint sent to the host.
     #[serde(with = "crate::ser::vec_u8_hex")]
     pub last_hint: Vec<u8>,
+    /// The thread stacks and scheduler state, if the MIPS emulator is running in multithreaded
+    /// mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<Threads>,
 }
 
 impl State {
+    /// Forks the [State] like [crate::Memory::fork] forks its memory, sharing its pages with the
+    /// original until either of them writes to a page.
+    ///
+    /// ### Returns
+    /// - `Ok(fork)`: The forked [State], with the same state hash as the original.
+    /// - `Err(_)`: The memory could not be merkleized.
+    pub fn fork(&mut self) -> Result<Self> {
+        self.memory.merkle_root()?;
+        Ok(self.clone())
+    }
+
     /// Encode the current [State] into a [StateWitness].
     ///
     /// ### Returns
     /// - A [Result] containing the encoded [StateWitness] or an error if the encoding failed.
     pub fn encode_witness(&mut self) -> Result<StateWitness> {
+        if self.threads.is_some() {
+            anyhow::bail!("State is multithreaded, use `State::encode_mt_witness`");
+        }
+
+        let root = self.memory.merkle_root()?;
+        Ok(self.encode_witness_with_root(root))
+    }
+
+    /// Encode the current single-threaded [State] into a [StateWitness] with the given memory
+    /// root, instead of merkleizing the [crate::Memory].
+    ///
+    /// ### Takes
+    /// - `root`: The memory root to encode.
+    ///
+    /// ### Returns
+    /// - The encoded [StateWitness].
+    pub(crate) fn encode_witness_with_root(&self, root: [u8; 32]) -> StateWitness {
         let mut witness: StateWitness = [0u8; STATE_WITNESS_SIZE];
-        witness[..32].copy_from_slice(self.memory.merkle_root()?.as_slice());
+        witness[..32].copy_from_slice(root.as_slice());
         witness[32..64].copy_from_slice(self.preimage_key.as_slice());
         witness[64..68].copy_from_slice(&self.preimage_offset.to_be_bytes());
         witness[68..72].copy_from_slice(&self.pc.to_be_bytes());
@@ -63,9 +99,62 @@ impl State {
             let start = 98 + i * 4;
             witness[start..start + 4].copy_from_slice(&r.to_be_bytes());
         }
+        witness
+    }
+
+    /// Encode the current multithreaded [State] into a [MtStateWitness].
+    ///
+    /// ### Returns
+    /// - A [Result] containing the encoded [MtStateWitness] or an error if the encoding failed.
+    pub fn encode_mt_witness(&mut self) -> Result<MtStateWitness> {
+        self.save_thread_context();
+        let threads = self
+            .threads
+            .as_ref()
+            .ok_or(anyhow::anyhow!("State is not multithreaded"))?;
+
+        let mut witness: MtStateWitness = [0u8; MT_STATE_WITNESS_SIZE];
+        witness[..32].copy_from_slice(self.memory.merkle_root()?.as_slice());
+        witness[32..64].copy_from_slice(self.preimage_key.as_slice());
+        witness[64..68].copy_from_slice(&self.preimage_offset.to_be_bytes());
+        witness[68..72].copy_from_slice(&self.heap.to_be_bytes());
+        witness[72] = self.exit_code;
+        witness[73] = self.exited as u8;
+        witness[74..82].copy_from_slice(&self.step.to_be_bytes());
+        witness[82..90].copy_from_slice(&threads.steps_since_last_context_switch.to_be_bytes());
+        witness[90..94].copy_from_slice(&threads.wakeup.to_be_bytes());
+        witness[94] = threads.traverse_right as u8;
+        witness[95..127].copy_from_slice(&Threads::stack_root(&threads.left_thread_stack));
+        witness[127..159].copy_from_slice(&Threads::stack_root(&threads.right_thread_stack));
+        witness[159..163].copy_from_slice(&threads.next_thread_id.to_be_bytes());
         Ok(witness)
     }
 
+    /// Encode the current [State] into the witness for its mode: a [StateWitness], or a
+    /// [MtStateWitness] if the [State] is multithreaded.
+    ///
+    /// ### Returns
+    /// - A [Result] containing the encoded witness or an error if the encoding failed.
+    pub fn encode_witness_bytes(&mut self) -> Result<Vec<u8>> {
+        if self.threads.is_some() {
+            Ok(self.encode_mt_witness()?.to_vec())
+        } else {
+            Ok(self.encode_witness()?.to_vec())
+        }
+    }
+
+    /// Compute the hash of the witness for the [State]'s mode.
+    ///
+    /// ### Returns
+    /// - A [Result] containing the state hash or an error if the encoding failed.
+    pub fn state_hash(&mut self) -> Result<[u8; 32]> {
+        if self.threads.is_some() {
+            Ok(self.encode_mt_witness()?.state_hash())
+        } else {
+            Ok(self.encode_witness()?.state_hash())
+        }
+    }
+
     /// Return the [VMStatus] given `exited` and `exit_code` statuses.
     pub fn vm_status(exited: bool, exit_code: u8) -> VMStatus {
         if !exited {
@@ -0,0 +1,216 @@
+//! This module contains the [RunStats] of a run of the MIPS emulator, and the counters they are
+//! collected from.
+
+use crate::{icache::DecodedInstruction, types::Syscall, Fd, Instruction, State};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The interval, in steps, at which the number of allocated pages is sampled.
+pub const PAGE_SAMPLE_INTERVAL: u64 = 1_000_000;
+
+/// A [PageSample] is the number of memory pages allocated at a step.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageSample {
+    /// The step the sample was taken at.
+    pub step: u64,
+    /// The number of allocated pages.
+    pub pages: usize,
+}
+
+/// The [RunStats] summarize the instructions and syscalls executed by the MIPS emulator, and
+/// the i/o and memory they used.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunStats {
+    /// The number of executed instructions.
+    pub instructions: u64,
+    /// The number of times every instruction was executed, by mnemonic. Invalid instructions
+    /// are counted as `invalid`.
+    pub instruction_mix: BTreeMap<String, u64>,
+    /// The number of times every syscall was made, by syscall number.
+    pub syscalls: BTreeMap<u32, u64>,
+    /// The number of bytes read from the preimage oracle.
+    pub preimage_bytes_read: u64,
+    /// The number of bytes written to the hint channel.
+    pub hint_bytes_written: u64,
+    /// The number of allocated pages, every [PAGE_SAMPLE_INTERVAL] steps and at the last step.
+    pub pages: Vec<PageSample>,
+}
+
+/// The counters that [RunStats] are built from, updated for every executed instruction.
+#[derive(Debug, Clone)]
+pub(crate) struct StatsCounter {
+    /// The execution counts, indexed by the opcode followed by the function code of R-type
+    /// instructions or the `rt` field of regimm branches.
+    instructions: Box<[u64; 64 * 64]>,
+    /// The syscall counts, by syscall number.
+    syscalls: BTreeMap<u32, u64>,
+    /// The number of bytes read from the preimage oracle.
+    preimage_bytes_read: u64,
+    /// The number of bytes written to the hint channel.
+    hint_bytes_written: u64,
+    /// The page samples taken so far.
+    pages: Vec<PageSample>,
+}
+
+impl Default for StatsCounter {
+    fn default() -> Self {
+        Self {
+            instructions: Box::new([0; 64 * 64]),
+            syscalls: BTreeMap::default(),
+            preimage_bytes_read: 0,
+            hint_bytes_written: 0,
+            pages: Vec::default(),
+        }
+    }
+}
+
+impl StatsCounter {
+    /// Counts an instruction before it is executed.
+    ///
+    /// ### Takes
+    /// - `decoded`: The [DecodedInstruction] about to be executed.
+    /// - `state`: The [State] before the instruction is executed.
+    ///
+    /// ### Returns
+    /// - The syscall number and first argument, if the instruction is a syscall.
+    #[inline(always)]
+    pub(crate) fn count(
+        &mut self,
+        decoded: DecodedInstruction,
+        state: &State,
+    ) -> Option<(u32, u32)> {
+        let sub = match decoded.opcode {
+            0x00 | 0x1C => decoded.fun,
+            0x01 => decoded.rt,
+            _ => 0,
+        };
+        self.instructions[(decoded.opcode as usize) << 6 | sub as usize] += 1;
+
+        if state.step % PAGE_SAMPLE_INTERVAL == 0 {
+            self.pages.push(PageSample {
+                step: state.step,
+                pages: state.memory.page_count(),
+            });
+        }
+
+        (decoded.opcode == 0 && decoded.fun == 0x0C).then(|| {
+            let number = state.registers[2];
+            *self.syscalls.entry(number).or_default() += 1;
+            (number, state.registers[4])
+        })
+    }
+
+    /// Counts the i/o of a syscall after it was executed.
+    ///
+    /// ### Takes
+    /// - `(number, a0)`: The syscall number and first argument, as returned by
+    ///   [StatsCounter::count].
+    /// - `state`: The [State] after the syscall was executed.
+    #[inline(always)]
+    pub(crate) fn count_syscall(&mut self, (number, a0): (u32, u32), state: &State) {
+        // Failed reads and writes return -1 with an error number in $a3.
+        if state.registers[7] != 0 {
+            return;
+        }
+        let v0 = state.registers[2] as u64;
+        if number == Syscall::Read as u32 && a0 == Fd::PreimageRead as u32 {
+            self.preimage_bytes_read += v0;
+        } else if number == Syscall::Write as u32 && a0 == Fd::HintWrite as u32 {
+            self.hint_bytes_written += v0;
+        }
+    }
+
+    /// Builds the [RunStats] of the instructions counted so far.
+    ///
+    /// ### Takes
+    /// - `state`: The current [State], for the last page sample.
+    ///
+    /// ### Returns
+    /// - The [RunStats].
+    pub(crate) fn stats(&self, state: &State) -> RunStats {
+        let mut instruction_mix = BTreeMap::<String, u64>::new();
+        for (index, count) in self.instructions.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let (opcode, sub) = ((index >> 6) as u32, (index & 0x3F) as u32);
+            let word = opcode << 26
+                | match opcode {
+                    0x00 | 0x1C => sub,
+                    0x01 => sub << 16,
+                    _ => 0,
+                };
+            let mnemonic = Instruction::decode(word).map_or("invalid", |i| i.mnemonic());
+            *instruction_mix.entry(mnemonic.to_string()).or_default() += count;
+        }
+
+        let mut pages = self.pages.clone();
+        if pages.last().is_none_or(|sample| sample.step != state.step) {
+            pages.push(PageSample {
+                step: state.step,
+                pages: state.memory.page_count(),
+            });
+        }
+
+        RunStats {
+            instructions: self.instructions.iter().sum(),
+            instruction_mix,
+            syscalls: self.syscalls.clone(),
+            preimage_bytes_read: self.preimage_bytes_read,
+            hint_bytes_written: self.hint_bytes_written,
+            pages,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_utils::{load_program, ClaimTestOracle},
+        types::Syscall,
+        InstrumentedState,
+    };
+
+    #[test]
+    fn run_stats() {
+        let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
+        let state = load_program(elf_bytes);
+
+        let oracle = ClaimTestOracle::default();
+        let mut ins =
+            InstrumentedState::new(state, oracle, Vec::default(), Vec::default()).with_stats(true);
+        ins.run(u64::MAX).unwrap();
+        assert!(ins.state.exited);
+
+        let stats = ins.run_stats().unwrap();
+        assert_eq!(stats.instructions, ins.state.step);
+        assert_eq!(
+            stats.instruction_mix.values().sum::<u64>(),
+            stats.instructions
+        );
+        assert!(stats.instruction_mix["addiu"] > 0);
+        assert!(!stats.instruction_mix.contains_key("invalid"));
+        assert_eq!(stats.syscalls[&(Syscall::ExitGroup as u32)], 1);
+
+        // The claim program reads the three local inputs, the pre-state, the diff and its two
+        // halves, each with its 8 byte length prefix, and sends the `fetch-state` and
+        // `fetch-diff` hints, each with their 4 byte length prefix.
+        assert_eq!(
+            stats.preimage_bytes_read,
+            32 + 32 + 8 + 8 + 64 + 8 + 8 + 7 * 8
+        );
+        assert_eq!(stats.hint_bytes_written, 4 + 76 + 4 + 75);
+
+        let last = stats.pages.last().unwrap();
+        assert_eq!(last.step, ins.state.step);
+        assert_eq!(last.pages, ins.state.memory.page_count());
+        assert_eq!(stats.pages.len() as u64, ins.state.step / 1_000_000 + 1);
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::RunStats>(&json).unwrap(),
+            stats
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! This module contains the [PageStore] that a [crate::Memory] evicts its cold pages to, and the
+//! [FilePageStore], which spills them to a local file.
+
+use crate::{Page, PageIndex};
+use anyhow::Result;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug},
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// A [PageStore] holds the pages evicted from a [crate::Memory].
+///
+/// A stored page is not overwritten until it is freed, which happens once no clone of the
+/// [crate::Memory] that evicted it refers to it anymore. Its key may then be reused.
+pub trait PageStore: Send + Sync {
+    /// Writes an evicted page to the store.
+    ///
+    /// ### Takes
+    /// - `page`: The data of the page.
+    ///
+    /// ### Returns
+    /// - `Ok(key)`: The key the page can be read back with.
+    /// - `Err(_)`: The page could not be written.
+    fn write_page(&self, page: &Page) -> Result<u64>;
+
+    /// Reads a page back from the store.
+    ///
+    /// ### Takes
+    /// - `key`: The key returned when the page was written.
+    /// - `page`: The buffer to read the data of the page into.
+    ///
+    /// ### Returns
+    /// - A [Result] indicating if the page was read successfully.
+    fn read_page(&self, key: u64, page: &mut Page) -> Result<()>;
+
+    /// Frees a page, so that its key may be reused by a later write.
+    ///
+    /// ### Takes
+    /// - `key`: The key returned when the page was written.
+    fn free_page(&self, key: u64);
+}
+
+/// The [FilePageStore] is a [PageStore] that keeps evicted pages in a local file. Freed pages
+/// are overwritten by later writes, so the file grows to the largest number of pages stored at
+/// once: the evicted pages of the [crate::Memory] and its clones, and the resident pages that
+/// were read back unchanged. The file is deleted when the store is dropped.
+pub struct FilePageStore {
+    /// The path of the file.
+    path: PathBuf,
+    /// The file the pages are stored in.
+    file: Mutex<PageFile>,
+}
+
+/// The file of a [FilePageStore].
+struct PageFile {
+    /// The file the pages are stored in.
+    file: File,
+    /// The offsets of the freed pages, which are overwritten before the file grows.
+    free: Vec<u64>,
+}
+
+impl FilePageStore {
+    /// Creates a new [FilePageStore], truncating the file if it exists.
+    ///
+    /// ### Takes
+    /// - `path`: The path of the file to store the pages in.
+    ///
+    /// ### Returns
+    /// - `Ok(store)`: The new [FilePageStore].
+    /// - `Err(_)`: The file could not be created.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(PageFile {
+                file,
+                free: Vec::default(),
+            }),
+        })
+    }
+}
+
+impl PageStore for FilePageStore {
+    fn write_page(&self, page: &Page) -> Result<u64> {
+        let mut file = self.file.lock().map_err(|_| anyhow::anyhow!("Poisoned"))?;
+        let key = match file.free.pop() {
+            Some(key) => file.file.seek(SeekFrom::Start(key))?,
+            None => file.file.seek(SeekFrom::End(0))?,
+        };
+        if let Err(e) = file.file.write_all(page) {
+            // A partially written page at the end of the file is overwritten by the next write.
+            file.free.push(key);
+            return Err(e.into());
+        }
+        Ok(key)
+    }
+
+    fn read_page(&self, key: u64, page: &mut Page) -> Result<()> {
+        let mut file = self.file.lock().map_err(|_| anyhow::anyhow!("Poisoned"))?;
+        file.file.seek(SeekFrom::Start(key))?;
+        file.file.read_exact(page)?;
+        Ok(())
+    }
+
+    fn free_page(&self, key: u64) {
+        if let Ok(mut file) = self.file.lock() {
+            file.free.push(key);
+        }
+    }
+}
+
+impl Drop for FilePageStore {
+    fn drop(&mut self) {
+        // The pages are only needed while the store is in use.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A [Slot] is a page written to a [PageStore]. The page is freed once the last clone of the
+/// [Slot] is dropped.
+pub(crate) struct Slot {
+    /// The store the page was written to.
+    store: Arc<dyn PageStore>,
+    /// The key the page is stored under.
+    pub(crate) key: u64,
+}
+
+impl Slot {
+    /// Writes a page to a [PageStore].
+    ///
+    /// ### Takes
+    /// - `store`: The [PageStore] to write the page to.
+    /// - `page`: The data of the page.
+    ///
+    /// ### Returns
+    /// - `Ok(slot)`: The [Slot] of the written page.
+    /// - `Err(_)`: The page could not be written.
+    pub(crate) fn write(store: &Arc<dyn PageStore>, page: &Page) -> Result<Arc<Self>> {
+        let key = store.write_page(page)?;
+        Ok(Arc::new(Self {
+            store: Arc::clone(store),
+            key,
+        }))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.store.free_page(self.key);
+    }
+}
+
+/// A page in a [PageStore].
+#[derive(Clone)]
+pub(crate) struct StoredPage {
+    /// The [Slot] the page is stored in, shared by the clones of the [crate::Memory].
+    pub(crate) slot: Arc<Slot>,
+    /// The merkle root of the page, checked when it is read back.
+    pub(crate) root: [u8; 32],
+}
+
+/// The [Paging] state of a [crate::Memory] that evicts its cold pages to a [PageStore].
+#[derive(Clone)]
+pub(crate) struct Paging {
+    /// The store the pages are evicted to.
+    pub(crate) store: Arc<dyn PageStore>,
+    /// The maximum number of pages kept in memory.
+    pub(crate) resident_pages: usize,
+    /// The pages that were evicted.
+    pub(crate) evicted: FxHashMap<PageIndex, StoredPage>,
+    /// The resident pages that were read back from the store, so that they are not written
+    /// again if they are evicted unchanged.
+    pub(crate) loaded: FxHashMap<PageIndex, StoredPage>,
+    /// The resident pages, in the order of the clock that evicts them. A page that was
+    /// accessed since the clock last passed it gets a second chance at the back of the queue.
+    pub(crate) queue: VecDeque<PageIndex>,
+    /// The resident pages that were accessed since the clock last passed them.
+    pub(crate) referenced: FxHashSet<PageIndex>,
+}
+
+impl Paging {
+    /// Creates a new [Paging] state.
+    ///
+    /// ### Takes
+    /// - `store`: The [PageStore] to evict pages to.
+    /// - `resident_pages`: The maximum number of pages kept in memory. Must be nonzero.
+    /// - `resident`: The pages that are currently resident.
+    ///
+    /// ### Returns
+    /// - The new [Paging] state.
+    pub(crate) fn new(
+        store: Arc<dyn PageStore>,
+        resident_pages: usize,
+        resident: impl IntoIterator<Item = PageIndex>,
+    ) -> Self {
+        let mut queue = resident.into_iter().collect::<Vec<_>>();
+        queue.sort_unstable();
+        Self {
+            store,
+            resident_pages: resident_pages.max(1),
+            evicted: FxHashMap::default(),
+            loaded: FxHashMap::default(),
+            queue: queue.into(),
+            referenced: FxHashSet::default(),
+        }
+    }
+}
+
+impl PartialEq for Paging {
+    fn eq(&self, other: &Self) -> bool {
+        self.resident_pages == other.resident_pages
+            && self.evicted.len() == other.evicted.len()
+            && self
+                .evicted
+                .iter()
+                .all(|(index, page)| other.evicted.get(index).map(|p| p.root) == Some(page.root))
+    }
+}
+
+impl Eq for Paging {}
+
+impl Debug for Paging {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Paging")
+            .field("resident_pages", &self.resident_pages)
+            .field("evicted", &self.evicted.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FilePageStore;
+    use crate::{
+        test_utils::{load_program, ClaimTestOracle},
+        InstrumentedState, Memory,
+    };
+    use std::{env, fs, sync::Arc};
+
+    #[test]
+    fn file_page_store() {
+        let path = env::temp_dir().join(format!("cannon-pages-{}", std::process::id()));
+        let store = Arc::new(FilePageStore::create(&path).unwrap());
+
+        let mut memory = Memory::default();
+        let mut reference = Memory::default();
+        memory.set_page_store(store, 4).unwrap();
+        for i in 0..64 {
+            memory.set_memory(i << 12 | 0x10, i).unwrap();
+            reference.set_memory(i << 12 | 0x10, i).unwrap();
+        }
+        assert_eq!(memory.page_count(), 64);
+        assert_eq!(memory.pages.len(), 4);
+        assert_eq!(
+            memory.merkle_root().unwrap(),
+            reference.merkle_root().unwrap()
+        );
+
+        // Evicted pages are read back on access, and written again once they change.
+        let mut fork = memory.fork().unwrap();
+        for i in (0..64).step_by(3) {
+            assert_eq!(memory.get_memory(i << 12 | 0x10).unwrap(), i);
+            memory.set_memory(i << 12 | 0x20, !i).unwrap();
+            reference.set_memory(i << 12 | 0x20, !i).unwrap();
+        }
+        assert_eq!(memory.pages.len(), 4);
+        assert_eq!(
+            memory.merkle_root().unwrap(),
+            reference.merkle_root().unwrap()
+        );
+        assert_eq!(
+            memory.merkle_proof(0x5010).unwrap(),
+            reference.merkle_proof(0x5010).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_string(&memory).unwrap(),
+            serde_json::to_string(&reference).unwrap()
+        );
+
+        // The fork reads the pages it evicted, not the ones the original wrote since.
+        assert_eq!(fork.get_memory(0x3020).unwrap(), 0);
+        assert_eq!(memory.get_memory(0x3020).unwrap(), !3);
+
+        // The file is deleted once no memory uses the store anymore.
+        drop(memory);
+        assert!(path.exists());
+        drop(fork);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn clock_and_reuse() {
+        let path = env::temp_dir().join(format!("cannon-page-reuse-{}", std::process::id()));
+        let mut memory = Memory::default();
+        memory.set_memory(0, 0).unwrap();
+        memory
+            .set_page_store(Arc::new(FilePageStore::create(&path).unwrap()), 4)
+            .unwrap();
+
+        for round in 0..20 {
+            for i in 1..16 {
+                // A page that is accessed between evictions stays resident.
+                assert_eq!(memory.get_memory(0).unwrap(), round);
+                assert!(memory.pages.contains_key(&0));
+                memory.set_memory(i << 12, round).unwrap();
+            }
+            memory.set_memory(0, round + 1).unwrap();
+        }
+        for i in 1..16 {
+            assert_eq!(memory.get_memory(i << 12).unwrap(), 19);
+        }
+
+        // Changed pages reuse the slots of their previous versions, so the file stays as large
+        // as the pages that are stored at once.
+        assert!(fs::metadata(&path).unwrap().len() <= 20 * 4096);
+    }
+
+    #[test]
+    fn paged_run() {
+        let path = env::temp_dir().join(format!("cannon-paged-run-{}", std::process::id()));
+        let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
+        let mut state = load_program(elf_bytes);
+
+        let mut reference = InstrumentedState::new(
+            state.clone(),
+            ClaimTestOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        );
+        state
+            .memory
+            .set_page_store(Arc::new(FilePageStore::create(&path).unwrap()), 64)
+            .unwrap();
+        let mut ins = InstrumentedState::new(
+            state,
+            ClaimTestOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        );
+
+        reference.run(u64::MAX).unwrap();
+        ins.run(u64::MAX).unwrap();
+        assert!(ins.state.exited);
+        assert_eq!(ins.state.memory.pages.len(), 64);
+        assert_eq!(
+            ins.state.encode_witness().unwrap(),
+            reference.state.encode_witness().unwrap()
+        );
+    }
+}
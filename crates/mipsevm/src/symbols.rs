@@ -0,0 +1,274 @@
+//! This module contains the [SymbolTable] of a guest program, and the best-effort [CallStack]
+//! reconstructed from the calls and returns it executes.
+
+use crate::{icache::DecodedInstruction, Address, State};
+use anyhow::{anyhow, Result};
+use elf::{abi::STT_FUNC, endian::AnyEndian, ElfBytes};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of [Frame]s tracked per thread. Calls past it drop the outermost frame.
+pub const MAX_CALL_DEPTH: usize = 1024;
+
+/// A [Symbol] is a named function in the guest program.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Symbol {
+    /// The name of the function.
+    pub name: String,
+    /// The address of the first instruction of the function.
+    pub start: Address,
+    /// The size of the function, in bytes.
+    pub size: u32,
+}
+
+impl Symbol {
+    /// Returns whether the given [Address] is within the function.
+    pub fn contains(&self, address: Address) -> bool {
+        address.wrapping_sub(self.start) < self.size.max(1)
+    }
+}
+
+/// The [SymbolTable] holds the function [Symbol]s of a guest program, sorted by their start
+/// address. It is serialized in the same format as the metadata written by `cannon load-elf`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymbolTable {
+    /// The function symbols, sorted by their start address.
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Creates a new [SymbolTable] from the given [Symbol]s.
+    ///
+    /// ### Takes
+    /// - `symbols`: The function symbols, in any order.
+    ///
+    /// ### Returns
+    /// - The new [SymbolTable].
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.start);
+        Self { symbols }
+    }
+
+    /// Builds a [SymbolTable] from the function symbols of a raw ELF file.
+    ///
+    /// ### Takes
+    /// - `raw`: The raw contents of the ELF file.
+    ///
+    /// ### Returns
+    /// - `Ok(table)` if the symbol table was read successfully
+    /// - `Err(_)` if the ELF file could not be parsed or has no symbol table
+    pub fn from_elf(raw: &[u8]) -> Result<Self> {
+        let elf = ElfBytes::<AnyEndian>::minimal_parse(raw)?;
+        let (parsing_table, string_table) = elf
+            .symbol_table()?
+            .ok_or(anyhow!("Failed to load ELF symbol table"))?;
+
+        let symbols = parsing_table
+            .iter()
+            .filter(|symbol| symbol.st_symtype() == STT_FUNC && symbol.st_value != 0)
+            .map(|symbol| {
+                Ok(Symbol {
+                    name: string_table.get(symbol.st_name as usize)?.to_string(),
+                    start: symbol.st_value as Address,
+                    size: symbol.st_size as u32,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(symbols))
+    }
+
+    /// Returns the [Symbol]s, sorted by their start address.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Looks up the function that contains the given [Address].
+    ///
+    /// ### Takes
+    /// - `address`: The address to look up.
+    ///
+    /// ### Returns
+    /// - The [Symbol] of the function, or `None` if no function contains the address.
+    pub fn lookup(&self, address: Address) -> Option<&Symbol> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.start <= address);
+        self.symbols[..index]
+            .last()
+            .filter(|symbol| symbol.contains(address))
+    }
+
+    /// Looks up a function by its name.
+    ///
+    /// ### Takes
+    /// - `name`: The name of the function.
+    ///
+    /// ### Returns
+    /// - The [Symbol] of the function, or `None` if there is no function with the name.
+    pub fn lookup_name(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Formats an [Address] as `function+offset`, or as a hex address if no function contains it.
+    ///
+    /// ### Takes
+    /// - `address`: The address to format.
+    ///
+    /// ### Returns
+    /// - The formatted address.
+    pub fn symbolize(&self, address: Address) -> String {
+        match self.lookup(address) {
+            Some(symbol) if symbol.start == address => symbol.name.clone(),
+            Some(symbol) => format!("{}+0x{:x}", symbol.name, address - symbol.start),
+            None => format!("0x{:08x}", address),
+        }
+    }
+}
+
+/// A [Frame] is a call that has not returned yet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The address of the call instruction.
+    pub call_site: Address,
+    /// The address that was called.
+    pub target: Address,
+    /// The address the call returns to.
+    pub return_address: Address,
+}
+
+/// The [CallStack] reconstructs the guest's call stack from the `jal` and `jalr` calls and
+/// `jr $ra` returns it executes, with a separate stack per thread.
+///
+/// The reconstruction is best-effort: returns that do not match a tracked call, such as
+/// goroutine switches or unwinding, leave the stack as it is.
+#[derive(Debug, Default, Clone)]
+pub struct CallStack {
+    /// The frames of every thread, innermost last.
+    stacks: FxHashMap<u32, Vec<Frame>>,
+}
+
+impl CallStack {
+    /// Returns the [Frame]s of the given thread, innermost last. The single thread of a
+    /// non-threaded [State] has ID 0.
+    pub fn frames(&self, thread_id: u32) -> &[Frame] {
+        self.stacks.get(&thread_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Replaces the [Frame]s of the given thread, innermost last.
+    pub(crate) fn set_frames(&mut self, thread_id: u32, frames: Vec<Frame>) {
+        self.stacks.insert(thread_id, frames);
+    }
+
+    /// Tracks an executed instruction.
+    ///
+    /// ### Takes
+    /// - `thread_id`: The ID of the thread that executed the instruction.
+    /// - `pc`: The address of the instruction.
+    /// - `instruction`: The [DecodedInstruction].
+    /// - `state`: The [State] after the instruction was executed.
+    #[inline(always)]
+    pub(crate) fn track(
+        &mut self,
+        thread_id: u32,
+        pc: Address,
+        instruction: DecodedInstruction,
+        state: &State,
+    ) {
+        let target = state.next_pc;
+        let call = match instruction.opcode {
+            // jal
+            0x03 => true,
+            // jalr
+            0x00 if instruction.fun == 0x09 => true,
+            // jr $ra
+            0x00 if instruction.fun == 0x08 && instruction.rs == 31 => {
+                let stack = self.stacks.entry(thread_id).or_default();
+                if let Some(index) = stack.iter().rposition(|f| f.return_address == target) {
+                    stack.truncate(index);
+                }
+                return;
+            }
+            _ => false,
+        };
+
+        if call {
+            let stack = self.stacks.entry(thread_id).or_default();
+            if stack.len() == MAX_CALL_DEPTH {
+                stack.remove(0);
+            }
+            stack.push(Frame {
+                call_site: pc,
+                target,
+                return_address: pc.wrapping_add(8),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Symbol, SymbolTable};
+    use crate::{
+        test_utils::{load_program, StaticOracle},
+        InstrumentedState,
+    };
+    use std::io;
+
+    #[test]
+    fn lookup() {
+        let table = SymbolTable::new(vec![
+            Symbol {
+                name: "b".to_string(),
+                start: 0x200,
+                size: 0x10,
+            },
+            Symbol {
+                name: "a".to_string(),
+                start: 0x100,
+                size: 0x80,
+            },
+        ]);
+        assert_eq!(table.symbols()[0].name, "a");
+        assert_eq!(table.symbolize(0x100), "a");
+        assert_eq!(table.symbolize(0x17c), "a+0x7c");
+        assert_eq!(table.symbolize(0x180), "0x00000180");
+        assert_eq!(table.symbolize(0x208), "b+0x8");
+        assert_eq!(table.symbolize(0xfc), "0x000000fc");
+        assert_eq!(table.lookup_name("b").unwrap().start, 0x200);
+
+        let json = serde_json::to_string(&table).unwrap();
+        assert_eq!(serde_json::from_str::<SymbolTable>(&json).unwrap(), table);
+    }
+
+    #[test]
+    fn call_stack() {
+        let elf_bytes = include_bytes!("../../../example/bin/hello.elf");
+        let table = SymbolTable::from_elf(elf_bytes).unwrap();
+        let main = table.lookup_name("main.main").unwrap().clone();
+        let runtime_main = table.lookup_name("runtime.main").unwrap().start;
+
+        let mut ins = InstrumentedState::new(
+            load_program(elf_bytes),
+            StaticOracle::new(Vec::default()),
+            io::sink(),
+            io::sink(),
+        )
+        .with_call_stack(true);
+
+        // Entering `main.main`, the innermost frame is its call from `runtime.main`.
+        ins.add_breakpoint(main.start, None);
+        ins.run_until_break(u64::MAX).unwrap();
+        let frames = ins.call_stack().unwrap().to_vec();
+        let frame = frames.last().unwrap();
+        assert_eq!(frame.target, main.start);
+        assert_eq!(table.lookup(frame.call_site).unwrap().start, runtime_main);
+
+        // Once `main.main` returns, its frame is popped again.
+        let depth = frames.len();
+        while main.contains(ins.state.pc) || ins.call_stack().unwrap().len() >= depth {
+            ins.step(false).unwrap();
+        }
+        assert_eq!(ins.call_stack().unwrap(), &frames[..depth - 1]);
+        assert_eq!(table.lookup(ins.state.pc).unwrap().start, runtime_main);
+    }
+}
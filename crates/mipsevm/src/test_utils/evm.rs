@@ -222,8 +222,11 @@ mod test {
     use super::*;
     use crate::{
         patch,
-        test_utils::{ClaimTestOracle, StaticOracle, BASE_ADDR_END, END_ADDR},
-        Address, InstrumentedState, Memory, State,
+        test_utils::{
+            load_program, program, ClaimTestOracle, StaticOracle, BASE_ADDR_END, END_ADDR,
+        },
+        types::Syscall,
+        Address, Fd, InstrumentedState, Memory, State,
     };
     use revm::primitives::ExecutionResult;
     use std::{
@@ -440,7 +443,7 @@ mod test {
             };
             let instruction_proof = initial_state.memory.merkle_proof(0).unwrap();
             let step_witness = StepWitness {
-                state: initial_state.encode_witness().unwrap(),
+                state: initial_state.encode_witness().unwrap().to_vec(),
                 mem_proof: instruction_proof.to_vec(),
                 preimage_key: None,
                 preimage_value: None,
@@ -450,6 +453,69 @@ mod test {
         }
     }
 
+    #[test]
+    fn verify_step_evm() {
+        let mut mips_evm = MipsEVM::new();
+        mips_evm.try_init().unwrap();
+
+        let elf_bytes = include_bytes!("../../../../example/bin/claim.elf");
+        let mut instrumented = InstrumentedState::new(
+            load_program(elf_bytes),
+            ClaimTestOracle::default(),
+            io::sink(),
+            io::sink(),
+        );
+
+        // Find the first load, store and preimage read of the program.
+        let (mut load, mut store, mut preimage_read) = (None, None, None);
+        while !instrumented.state.exited {
+            let state = &mut instrumented.state;
+            let instruction = state.memory.peek_memory(state.pc).unwrap();
+            let (v0, a0) = (state.registers[2], state.registers[4]);
+            let slot = match instruction >> 26 {
+                0x20..=0x26 | 0x30 => Some(&mut load),
+                0x28..=0x2E | 0x38 => Some(&mut store),
+                _ if instruction == 0x0000000C
+                    && v0 == Syscall::Read as u32
+                    && a0 == Fd::PreimageRead as u32 =>
+                {
+                    Some(&mut preimage_read)
+                }
+                _ => None,
+            };
+            match slot {
+                Some(slot) if slot.is_none() => *slot = instrumented.step(true).unwrap(),
+                _ => {
+                    instrumented.step(false).unwrap();
+                }
+            }
+        }
+        let exited = instrumented.step(true).unwrap().unwrap();
+
+        // The quotient of a signed division that overflows wraps around.
+        let mut div = program(&[
+            0x0109001A, // div $t0, $t1
+        ]);
+        div.state.registers[8] = 0x80000000;
+        div.state.registers[9] = 0xFFFFFFFF;
+        let div = div.step(true).unwrap().unwrap();
+
+        let cases = [
+            ("load", load.unwrap()),
+            ("store", store.unwrap()),
+            ("preimage read", preimage_read.unwrap()),
+            ("exited", exited),
+            ("div overflow", div),
+        ];
+        for (name, witness) in cases {
+            println!(" -> Running test: {name}");
+
+            let native_post = witness.verify().unwrap();
+            let evm_post = mips_evm.step(witness).unwrap();
+            assert_eq!(native_post, evm_post);
+        }
+    }
+
     #[test]
     fn test_hello_evm() {
         let mut mips_evm = MipsEVM::new();
@@ -1,10 +1,17 @@
 //! Testing utilities.
 
-use crate::{utils::concat_fixed, utils::keccak256, PreimageOracle};
+use crate::{
+    load_elf, patch_go, patch_stack, utils::concat_fixed, utils::keccak256, InstrumentedState,
+    PreimageOracle, State,
+};
 use alloy_primitives::hex;
 use anyhow::Result;
 use preimage_oracle::{Hint, Keccak256Key, Key, LocalIndexKey};
 use rustc_hash::FxHashMap;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
 
 pub mod evm;
 
@@ -14,6 +21,58 @@ pub const BASE_ADDR_END: u32 = 0xBF_FF_FF_F0;
 /// Used as the return-address for tests
 pub const END_ADDR: u32 = 0xA7_EF_00_D0;
 
+/// Loads a program from an ELF file, patched to run in the VM.
+///
+/// ### Takes
+/// - `elf_bytes`: The raw bytes of the ELF file.
+///
+/// ### Returns
+/// - The [State] at the entry point of the program.
+pub fn load_program(elf_bytes: &[u8]) -> State {
+    let mut state = load_elf(elf_bytes).unwrap();
+    patch_go(elf_bytes, &mut state).unwrap();
+    patch_stack(&mut state).unwrap();
+    state
+}
+
+/// Creates a VM that runs a program from address `0`.
+///
+/// ### Takes
+/// - `instructions`: The instructions of the program.
+///
+/// ### Returns
+/// - The [InstrumentedState] at the first instruction of the program.
+pub fn program(instructions: &[u32]) -> InstrumentedState<io::Sink, io::Sink, StaticOracle> {
+    let mut state = State {
+        next_pc: 4,
+        ..Default::default()
+    };
+    for (i, instruction) in instructions.iter().enumerate() {
+        state.memory.set_memory(i as u32 * 4, *instruction).unwrap();
+    }
+    InstrumentedState::new(
+        state,
+        StaticOracle::new(b"hello world".to_vec()),
+        io::sink(),
+        io::sink(),
+    )
+}
+
+/// A writer that can be inspected after it was moved into a [crate::TraceRecorder].
+#[derive(Default, Clone)]
+pub struct SharedBuffer(pub Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 pub struct StaticOracle {
     preimage_data: Vec<u8>,
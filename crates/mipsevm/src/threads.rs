@@ -0,0 +1,403 @@
+//! This module contains the data structures for the threads of the MIPS emulator when it is
+//! running in multithreaded mode.
+//!
+//! Threads are kept on two stacks. The thread at the top of the active stack (selected by
+//! [Threads::traverse_right]) is the current thread. Preempting a thread moves it to the top of
+//! the other stack, and once the active stack runs empty the traversal direction flips. Both
+//! stacks are committed to in the [crate::MtStateWitness] as a hash-chain of [ThreadWitness]es.
+
+use crate::{
+    utils::{keccak256, keccak_concat_hashes},
+    witness::{THREAD_PROOF_SIZE, THREAD_WITNESS_SIZE},
+    Address, State, ThreadWitness,
+};
+use serde::{Deserialize, Serialize};
+
+/// The number of steps a thread may execute before it is preempted.
+pub const SCHED_QUANTUM: u64 = 100_000;
+
+/// The number of steps after which a `futex` wait with a timeout expires.
+pub const FUTEX_TIMEOUT_STEPS: u64 = 10_000;
+
+/// The number of steps per second of the clock reported by `clock_gettime`.
+pub const STEPS_PER_SECOND: u64 = 10_000_000;
+
+/// The futex address of a thread that is not waiting, and the wakeup address when no wakeup
+/// traversal is in progress.
+pub const FUTEX_EMPTY_ADDR: Address = Address::MAX;
+
+/// The futex timeout step of a `futex` wait without a timeout.
+pub const FUTEX_NO_TIMEOUT: u64 = u64::MAX;
+
+/// The `clone` flags used by the Go runtime to create a thread. `CLONE_VM | CLONE_FS |
+/// CLONE_FILES | CLONE_SIGHAND | CLONE_SYSVSEM | CLONE_THREAD`
+pub const VALID_CLONE_FLAGS: u32 = 0x100 | 0x200 | 0x400 | 0x800 | 0x10000 | 0x40000;
+
+/// The [ThreadState] struct contains the execution context of a single guest thread.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadState {
+    /// The unique identifier of the thread.
+    pub thread_id: u32,
+    /// The exit code of the thread.
+    pub exit_code: u8,
+    /// Whether or not the thread has exited.
+    pub exited: bool,
+    /// The address the thread is waiting on, or [FUTEX_EMPTY_ADDR] if it is not waiting.
+    pub futex_addr: Address,
+    /// The value the thread expects at `futex_addr` while it is waiting.
+    pub futex_val: u32,
+    /// The step after which the thread's wait times out.
+    pub futex_timeout_step: u64,
+    /// The program counter of the thread.
+    pub pc: u32,
+    /// The next program counter of the thread.
+    pub next_pc: u32,
+    /// The lo register of the thread.
+    pub lo: u32,
+    /// The hi register of the thread.
+    pub hi: u32,
+    /// The registers of the thread.
+    pub registers: [u32; 32],
+}
+
+impl ThreadState {
+    /// Create a new [ThreadState] with the given identifier and a zeroed execution context.
+    ///
+    /// ### Takes
+    /// - `thread_id`: The identifier of the thread.
+    ///
+    /// ### Returns
+    /// - The new [ThreadState].
+    pub fn new(thread_id: u32) -> Self {
+        Self {
+            thread_id,
+            exit_code: 0,
+            exited: false,
+            futex_addr: FUTEX_EMPTY_ADDR,
+            futex_val: 0,
+            futex_timeout_step: 0,
+            pc: 0,
+            next_pc: 0,
+            lo: 0,
+            hi: 0,
+            registers: [0; 32],
+        }
+    }
+
+    /// Encode the [ThreadState] into a [ThreadWitness].
+    ///
+    /// ### Returns
+    /// - The encoded [ThreadWitness].
+    pub fn encode_witness(&self) -> ThreadWitness {
+        let mut witness: ThreadWitness = [0u8; THREAD_WITNESS_SIZE];
+        witness[..4].copy_from_slice(&self.thread_id.to_be_bytes());
+        witness[4] = self.exit_code;
+        witness[5] = self.exited as u8;
+        witness[6..10].copy_from_slice(&self.futex_addr.to_be_bytes());
+        witness[10..14].copy_from_slice(&self.futex_val.to_be_bytes());
+        witness[14..22].copy_from_slice(&self.futex_timeout_step.to_be_bytes());
+        witness[22..26].copy_from_slice(&self.pc.to_be_bytes());
+        witness[26..30].copy_from_slice(&self.next_pc.to_be_bytes());
+        witness[30..34].copy_from_slice(&self.lo.to_be_bytes());
+        witness[34..38].copy_from_slice(&self.hi.to_be_bytes());
+        for (i, r) in self.registers.iter().enumerate() {
+            let start = 38 + i * 4;
+            witness[start..start + 4].copy_from_slice(&r.to_be_bytes());
+        }
+        witness
+    }
+}
+
+/// The [Threads] struct contains the thread stacks and scheduler state of the MIPS emulator
+/// when it is running in multithreaded mode.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Threads {
+    /// The left thread stack. The last element is the top of the stack.
+    pub left_thread_stack: Vec<ThreadState>,
+    /// The right thread stack. The last element is the top of the stack.
+    pub right_thread_stack: Vec<ThreadState>,
+    /// Whether the right thread stack is the active one.
+    pub traverse_right: bool,
+    /// The identifier assigned to the next thread that is created.
+    pub next_thread_id: u32,
+    /// The number of steps the current thread has executed since it was scheduled.
+    pub steps_since_last_context_switch: u64,
+    /// The address being woken up by a `futex` wake, or [FUTEX_EMPTY_ADDR] if no wakeup
+    /// traversal is in progress.
+    pub wakeup: Address,
+}
+
+impl Threads {
+    /// Returns the current thread, if any thread is alive.
+    pub fn current(&self) -> Option<&ThreadState> {
+        self.active_stack().last()
+    }
+
+    /// Returns the current thread mutably, if any thread is alive.
+    pub fn current_mut(&mut self) -> Option<&mut ThreadState> {
+        if self.traverse_right {
+            self.right_thread_stack.last_mut()
+        } else {
+            self.left_thread_stack.last_mut()
+        }
+    }
+
+    /// Returns the number of threads on both stacks.
+    pub fn thread_count(&self) -> usize {
+        self.left_thread_stack.len() + self.right_thread_stack.len()
+    }
+
+    /// Returns the active thread stack.
+    pub fn active_stack(&self) -> &[ThreadState] {
+        if self.traverse_right {
+            &self.right_thread_stack
+        } else {
+            &self.left_thread_stack
+        }
+    }
+
+    /// Computes the commitment to a thread stack. The root of an empty stack is the hash of 64
+    /// zero bytes, and pushing a thread onto a stack hashes the previous root together with the
+    /// hash of the thread's [ThreadWitness].
+    ///
+    /// ### Takes
+    /// - `stack`: The thread stack, with the top of the stack as its last element.
+    ///
+    /// ### Returns
+    /// - The root of the thread stack.
+    pub fn stack_root(stack: &[ThreadState]) -> [u8; 32] {
+        stack.iter().fold(*keccak256([0u8; 64]), |root, thread| {
+            *keccak_concat_hashes(root, *keccak256(thread.encode_witness()))
+        })
+    }
+}
+
+impl State {
+    /// Switch the [State] into multithreaded mode. The current execution context becomes the
+    /// context of the main thread. Does nothing if the [State] is already multithreaded.
+    pub fn enable_threading(&mut self) {
+        if self.threads.is_some() {
+            return;
+        }
+
+        self.threads = Some(Threads {
+            left_thread_stack: vec![ThreadState::new(0)],
+            right_thread_stack: Vec::new(),
+            traverse_right: false,
+            next_thread_id: 1,
+            steps_since_last_context_switch: 0,
+            wakeup: FUTEX_EMPTY_ADDR,
+        });
+        self.save_thread_context();
+    }
+
+    /// Returns `true` if the [State] is in multithreaded mode.
+    pub fn is_multithreaded(&self) -> bool {
+        self.threads.is_some()
+    }
+
+    /// Encode the thread proof for the current thread: its [ThreadWitness] followed by the root
+    /// of the active thread stack without it.
+    ///
+    /// ### Returns
+    /// - A [Result] containing the thread proof, or an error if the [State] is not multithreaded
+    ///   or no thread is alive.
+    pub fn encode_thread_proof(&mut self) -> anyhow::Result<[u8; THREAD_PROOF_SIZE]> {
+        self.save_thread_context();
+        let threads = self
+            .threads
+            .as_ref()
+            .ok_or(anyhow::anyhow!("State is not multithreaded"))?;
+        let (current, rest) = threads
+            .active_stack()
+            .split_last()
+            .ok_or(anyhow::anyhow!("No thread is alive"))?;
+
+        let mut proof = [0u8; THREAD_PROOF_SIZE];
+        proof[..THREAD_WITNESS_SIZE].copy_from_slice(&current.encode_witness());
+        proof[THREAD_WITNESS_SIZE..].copy_from_slice(&Threads::stack_root(rest));
+        Ok(proof)
+    }
+
+    /// Copy the execution context held in the [State] into the current thread.
+    ///
+    /// The execution context of the current thread lives in the [State]'s `pc`, `next_pc`, `lo`,
+    /// `hi` and `registers` fields while it runs, so that the VM can operate on it directly.
+    pub(crate) fn save_thread_context(&mut self) {
+        if let Some(thread) = self.threads.as_mut().and_then(Threads::current_mut) {
+            thread.pc = self.pc;
+            thread.next_pc = self.next_pc;
+            thread.lo = self.lo;
+            thread.hi = self.hi;
+            thread.registers = self.registers;
+        }
+    }
+
+    /// Copy the execution context of the current thread into the [State].
+    pub(crate) fn load_thread_context(&mut self) {
+        if let Some(thread) = self.threads.as_ref().and_then(Threads::current) {
+            self.pc = thread.pc;
+            self.next_pc = thread.next_pc;
+            self.lo = thread.lo;
+            self.hi = thread.hi;
+            self.registers = thread.registers;
+        }
+    }
+
+    /// Push a new thread onto the active thread stack, making it the current thread.
+    ///
+    /// ### Takes
+    /// - `thread`: The [ThreadState] to push.
+    pub(crate) fn push_thread(&mut self, thread: ThreadState) {
+        self.save_thread_context();
+        let threads = self.threads.as_mut().expect("State is multithreaded");
+        if threads.traverse_right {
+            threads.right_thread_stack.push(thread);
+        } else {
+            threads.left_thread_stack.push(thread);
+        }
+        threads.steps_since_last_context_switch = 0;
+        self.load_thread_context();
+    }
+
+    /// Pop the current thread off of the active thread stack.
+    pub(crate) fn pop_thread(&mut self) {
+        let threads = self.threads.as_mut().expect("State is multithreaded");
+        if threads.traverse_right {
+            threads.right_thread_stack.pop();
+        } else {
+            threads.left_thread_stack.pop();
+        }
+        if threads.active_stack().is_empty() {
+            threads.traverse_right = !threads.traverse_right;
+        }
+        threads.steps_since_last_context_switch = 0;
+        self.load_thread_context();
+    }
+
+    /// Move the current thread onto the other thread stack, scheduling the next thread.
+    ///
+    /// ### Returns
+    /// - `true` if the active thread stack ran empty and the traversal direction changed.
+    pub(crate) fn preempt_thread(&mut self) -> bool {
+        self.save_thread_context();
+        let threads = self.threads.as_mut().expect("State is multithreaded");
+        let (from, to) = if threads.traverse_right {
+            (
+                &mut threads.right_thread_stack,
+                &mut threads.left_thread_stack,
+            )
+        } else {
+            (
+                &mut threads.left_thread_stack,
+                &mut threads.right_thread_stack,
+            )
+        };
+        to.push(from.pop().expect("Active thread stack is not empty"));
+
+        let changed_directions = from.is_empty();
+        if changed_directions {
+            threads.traverse_right = !threads.traverse_right;
+        }
+        threads.steps_since_last_context_switch = 0;
+        self.load_thread_context();
+        changed_directions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{witness::MT_STATE_WITNESS_SIZE, StateWitnessHasher};
+    use alloy_primitives::{hex, keccak256};
+
+    #[test]
+    fn empty_stack_root() {
+        assert_eq!(
+            Threads::stack_root(&[]),
+            hex!("ad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5")
+        );
+    }
+
+    #[test]
+    fn stack_root() {
+        let threads = [ThreadState::new(0), ThreadState::new(1)];
+
+        let mut expected = keccak256([0u8; 64]);
+        for thread in threads.iter() {
+            let mut preimage = [0u8; 64];
+            preimage[..32].copy_from_slice(expected.as_slice());
+            preimage[32..].copy_from_slice(keccak256(thread.encode_witness()).as_slice());
+            expected = keccak256(preimage);
+        }
+
+        assert_eq!(Threads::stack_root(&threads), expected);
+    }
+
+    #[test]
+    fn thread_witness() {
+        let mut thread = ThreadState::new(7);
+        thread.exited = true;
+        thread.exit_code = 3;
+        thread.pc = 0x1000;
+        thread.next_pc = 0x1004;
+        thread.registers[31] = 0xdeadbeef;
+
+        let witness = thread.encode_witness();
+        assert_eq!(witness[..4], 7u32.to_be_bytes());
+        assert_eq!(witness[4..6], [3, 1]);
+        assert_eq!(witness[6..10], FUTEX_EMPTY_ADDR.to_be_bytes());
+        assert_eq!(witness[22..30], hex!("0000100000001004"));
+        assert_eq!(
+            witness[THREAD_WITNESS_SIZE - 4..],
+            0xdeadbeefu32.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn mt_state_witness() {
+        let mut state = State {
+            pc: 0x1000,
+            next_pc: 0x1004,
+            exited: true,
+            exit_code: 1,
+            ..Default::default()
+        };
+        assert!(state.encode_mt_witness().is_err());
+        state.enable_threading();
+        assert!(state.encode_witness().is_err());
+
+        let witness = state.encode_mt_witness().unwrap();
+        assert_eq!(witness.len(), MT_STATE_WITNESS_SIZE);
+        assert_eq!(
+            witness[..32],
+            *state.memory.merkle_root().unwrap().as_slice()
+        );
+        assert_eq!(witness[72..74], [1, 1]);
+        assert_eq!(witness[90..94], FUTEX_EMPTY_ADDR.to_be_bytes());
+        assert_eq!(witness[94], 0);
+
+        let threads = state.threads.clone().unwrap();
+        assert_eq!(threads.left_thread_stack[0].pc, 0x1000);
+        assert_eq!(
+            witness[95..127],
+            Threads::stack_root(&threads.left_thread_stack)
+        );
+        assert_eq!(witness[127..159], Threads::stack_root(&[]));
+        assert_eq!(witness[159..], 1u32.to_be_bytes());
+
+        let mut expected_hash = keccak256(witness);
+        expected_hash[0] = State::vm_status(true, 1) as u8;
+        assert_eq!(witness.state_hash(), expected_hash);
+        assert_eq!(state.state_hash().unwrap(), expected_hash);
+
+        let proof = state.encode_thread_proof().unwrap();
+        assert_eq!(
+            proof[..THREAD_WITNESS_SIZE],
+            threads.left_thread_stack[0].encode_witness()
+        );
+        assert_eq!(proof[THREAD_WITNESS_SIZE..], Threads::stack_root(&[]));
+    }
+}
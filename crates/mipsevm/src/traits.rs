@@ -1,5 +1,6 @@
 //! This module contains the various traits used in this crate.
 
+use crate::{SyscallContext, VmError};
 use anyhow::Result;
 use preimage_oracle::Hint;
 
@@ -30,3 +31,27 @@ pub trait PreimageOracle {
     /// - `Err(_)`: An error occurred while fetching the preimage.
     fn get(&mut self, key: [u8; 32]) -> Result<Vec<u8>>;
 }
+
+/// A [SyscallHandler] is a trait describing a handler for syscalls that are added to, or override
+/// those of, the MIPS emulator. It is consulted before the built-in syscalls are dispatched.
+///
+/// Syscalls added with a [SyscallHandler] are not supported by `MIPS.sol`, so they may only be
+/// used for off-chain execution.
+pub trait SyscallHandler {
+    /// Handle the given syscall.
+    ///
+    /// ### Takes
+    /// - `syscall`: The syscall number, read from `$v0`.
+    /// - `context`: The [SyscallContext] giving access to the registers and memory.
+    ///
+    /// ### Returns
+    /// - `Ok(Some((v0, v1)))`: The syscall was handled, and returns `v0` with the error number
+    ///   `v1`.
+    /// - `Ok(None)`: The syscall is not handled, and is dispatched to the built-in syscalls.
+    /// - `Err(_)`: An error occurred while handling the syscall.
+    fn handle_syscall(
+        &mut self,
+        syscall: u32,
+        context: &mut SyscallContext<'_>,
+    ) -> Result<Option<(u32, u32)>, VmError>;
+}
@@ -1,17 +1,24 @@
 //! This module contains all of the type aliases and enums used within this crate.
 
 use crate::CachedPage;
-use std::{cell::RefCell, rc::Rc};
+use std::sync::Arc;
 
 /// A [Page] is a portion of memory of size `PAGE_SIZE`.
 pub type Page = [u8; crate::page::PAGE_SIZE];
 
-/// A [CachedPage] with shared ownership.
-pub type SharedCachedPage = Rc<RefCell<CachedPage>>;
+/// A [CachedPage] with shared ownership. Shared pages are copied on write.
+pub type SharedCachedPage = Arc<CachedPage>;
 
 /// A [StateWitness] is an encoded commitment to the current [crate::State] of the MIPS emulator.
 pub type StateWitness = [u8; crate::witness::STATE_WITNESS_SIZE];
 
+/// A [MtStateWitness] is an encoded commitment to the current [crate::State] of the MIPS emulator
+/// when it is running in multithreaded mode.
+pub type MtStateWitness = [u8; crate::witness::MT_STATE_WITNESS_SIZE];
+
+/// A [ThreadWitness] is an encoded commitment to a single [crate::ThreadState].
+pub type ThreadWitness = [u8; crate::witness::THREAD_WITNESS_SIZE];
+
 /// A [PageIndex] is the index of a [Page] within the [crate::Memory] mappings.
 pub type PageIndex = u64;
 
@@ -62,6 +69,7 @@ impl TryFrom<u8> for Fd {
 
 /// A [Syscall] is a system call that can be made within the MIPS emulator.
 pub enum Syscall {
+    Exit = 4001,
     Mmap = 4090,
     Brk = 4045,
     Clone = 4120,
@@ -69,6 +77,17 @@ pub enum Syscall {
     Read = 4003,
     Write = 4004,
     Fcntl = 4055,
+    Getrlimit = 4076,
+    Munmap = 4091,
+    SchedYield = 4162,
+    Nanosleep = 4166,
+    RtSigaction = 4194,
+    RtSigprocmask = 4195,
+    Sigaltstack = 4206,
+    Madvise = 4218,
+    Gettid = 4222,
+    Futex = 4238,
+    ClockGettime = 4263,
 }
 
 impl TryFrom<u32> for Syscall {
@@ -76,6 +95,7 @@ impl TryFrom<u32> for Syscall {
 
     fn try_from(n: u32) -> Result<Self, Self::Error> {
         match n {
+            4001 => Ok(Syscall::Exit),
             4090 => Ok(Syscall::Mmap),
             4045 => Ok(Syscall::Brk),
             4120 => Ok(Syscall::Clone),
@@ -83,6 +103,17 @@ impl TryFrom<u32> for Syscall {
             4003 => Ok(Syscall::Read),
             4004 => Ok(Syscall::Write),
             4055 => Ok(Syscall::Fcntl),
+            4076 => Ok(Syscall::Getrlimit),
+            4091 => Ok(Syscall::Munmap),
+            4162 => Ok(Syscall::SchedYield),
+            4166 => Ok(Syscall::Nanosleep),
+            4194 => Ok(Syscall::RtSigaction),
+            4195 => Ok(Syscall::RtSigprocmask),
+            4206 => Ok(Syscall::Sigaltstack),
+            4218 => Ok(Syscall::Madvise),
+            4222 => Ok(Syscall::Gettid),
+            4238 => Ok(Syscall::Futex),
+            4263 => Ok(Syscall::ClockGettime),
             _ => anyhow::bail!("Failed to convert {} to Syscall", n),
         }
     }
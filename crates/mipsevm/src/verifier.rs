@@ -0,0 +1,281 @@
+//! This module contains a native, stateless verifier of single instruction steps, which mirrors
+//! the `step` function of `MIPS.sol`.
+
+use crate::{
+    memory::proof_root, witness::STATE_WITNESS_SIZE, Address, InstrumentedState, PreimageOracle,
+    State, StateWitness, StepWitness, MEMORY_PROOF_SIZE,
+};
+use anyhow::{anyhow, bail, Result};
+use preimage_oracle::Hint;
+use std::io;
+
+/// Verifies a single instruction step the way `MIPS.sol` does, without access to the memory of
+/// the VM. The instruction and the memory word accessed by the step are checked against the
+/// memory root of the pre-state with their merkle proofs, and the memory root of the post-state
+/// is computed from the proof of the accessed word.
+///
+/// Only single-threaded [StateWitness]es are supported.
+///
+/// ### Takes
+/// - `state`: The encoded [StateWitness] of the pre-state.
+/// - `proof`: The merkle proof of the instruction, followed by the merkle proof of the memory
+///   word accessed by the step, as in [StepWitness::mem_proof].
+/// - `preimage`: The key and the length prefixed value of the preimage read by the step, if any,
+///   as in [StepWitness::preimage_key] and [StepWitness::preimage_value].
+///
+/// ### Returns
+/// - `Ok(post)`: The [StateWitness] of the post-state.
+/// - `Err(_)`: The witness or a proof is invalid, a required preimage is missing, or the
+///   instruction faults.
+pub fn verify_step(
+    state: &[u8],
+    proof: &[u8],
+    preimage: Option<([u8; 32], &[u8])>,
+) -> Result<StateWitness> {
+    let witness: StateWitness = state.try_into().map_err(|_| {
+        anyhow!(
+            "Invalid state witness size {}, expected {}",
+            state.len(),
+            STATE_WITNESS_SIZE
+        )
+    })?;
+    if proof.len() != 2 * MEMORY_PROOF_SIZE {
+        bail!(
+            "Invalid memory proof size {}, expected {}",
+            proof.len(),
+            2 * MEMORY_PROOF_SIZE
+        );
+    }
+    let root: [u8; 32] = witness[..32].try_into()?;
+    let state = decode_witness(&witness);
+
+    // An exited VM does not step, and its proofs are not checked.
+    if state.exited {
+        return Ok(witness);
+    }
+
+    let (instruction_proof, memory_proof) = proof.split_at(MEMORY_PROOF_SIZE);
+    if proof_root(state.pc, instruction_proof) != root {
+        bail!("Invalid instruction proof for pc 0x{:08x}", state.pc);
+    }
+    let mut state = state;
+    state
+        .memory
+        .set_memory_range(state.pc & !31, &instruction_proof[..32])?;
+
+    // The accessed word is only known after the instruction is decoded, so the step is first
+    // executed with every other word unset to find it.
+    let oracle = WitnessOracle::new(preimage)?;
+    let (post, address) = execute(state.clone(), oracle.clone())?;
+    if address == Address::MAX {
+        return Ok(post.encode_witness_with_root(root));
+    }
+
+    if proof_root(address, memory_proof) != root {
+        bail!("Invalid memory proof for address 0x{:08x}", address);
+    }
+    state
+        .memory
+        .set_memory_range(address & !31, &memory_proof[..32])?;
+    let (mut post, _) = execute(state, oracle)?;
+
+    // Only the accessed word may have been written, so the post-state root is the root of its
+    // updated leaf.
+    let mut leaf = memory_proof.to_vec();
+    for (i, word) in leaf[..32].chunks_exact_mut(4).enumerate() {
+        let value = post.memory.peek_memory((address & !31) + 4 * i as u32)?;
+        word.copy_from_slice(&value.to_be_bytes());
+    }
+    Ok(post.encode_witness_with_root(proof_root(address, &leaf)))
+}
+
+impl StepWitness {
+    /// Verifies the step of the [StepWitness] with [verify_step].
+    ///
+    /// ### Returns
+    /// - `Ok(post)`: The [StateWitness] of the post-state.
+    /// - `Err(_)`: The step could not be verified.
+    pub fn verify(&self) -> Result<StateWitness> {
+        let preimage = self.preimage_key.zip(self.preimage_value.as_deref());
+        verify_step(&self.state, &self.mem_proof, preimage)
+    }
+}
+
+/// Decodes a [StateWitness] into a [State] with empty memory.
+fn decode_witness(witness: &StateWitness) -> State {
+    let word =
+        |offset: usize| u32::from_be_bytes(witness[offset..offset + 4].try_into().expect("word"));
+    let mut state = State {
+        preimage_key: witness[32..64].try_into().expect("key is 32 bytes"),
+        preimage_offset: word(64),
+        pc: word(68),
+        next_pc: word(72),
+        lo: word(76),
+        hi: word(80),
+        heap: word(84),
+        exit_code: witness[88],
+        exited: witness[89] == 1,
+        step: u64::from_be_bytes(witness[90..98].try_into().expect("step is 8 bytes")),
+        ..Default::default()
+    };
+    for (i, register) in state.registers.iter_mut().enumerate() {
+        *register = word(98 + i * 4);
+    }
+    state
+}
+
+/// Executes a single instruction on a [State] whose memory only holds the proven words.
+///
+/// ### Takes
+/// - `state`: The pre-state.
+/// - `oracle`: The [WitnessOracle] to read preimages from.
+///
+/// ### Returns
+/// - `Ok((post, address))`: The post-state, and the word accessed by the instruction or
+///   [Address::MAX] if it accessed none.
+/// - `Err(_)`: The instruction faults or a preimage is missing.
+fn execute(state: State, oracle: WitnessOracle) -> Result<(State, Address)> {
+    let mut ins = InstrumentedState::new(state, oracle, io::sink(), io::sink());
+    // Proofs are enabled so that accesses are tracked, and a second access faults like it
+    // reverts in `MIPS.sol`.
+    ins.mem_proof_enabled = true;
+    ins.last_mem_access = Address::MAX;
+    ins.inner_step()
+        .map_err(|e| anyhow!("Step failed: {}", e))?;
+    Ok((ins.state, ins.last_mem_access))
+}
+
+/// A [PreimageOracle] that serves the single preimage of a step witness.
+#[derive(Clone)]
+struct WitnessOracle {
+    /// The key and value of the preimage, if any.
+    preimage: Option<([u8; 32], Vec<u8>)>,
+}
+
+impl WitnessOracle {
+    /// Creates a new [WitnessOracle] from a key and length prefixed value.
+    fn new(preimage: Option<([u8; 32], &[u8])>) -> Result<Self> {
+        let preimage = preimage
+            .map(|(key, value)| {
+                let (prefix, data) = value
+                    .split_first_chunk::<8>()
+                    .ok_or(anyhow!("Preimage value is missing its length prefix"))?;
+                if u64::from_be_bytes(*prefix) != data.len() as u64 {
+                    bail!("Preimage length prefix does not match its length");
+                }
+                Ok((key, data.to_vec()))
+            })
+            .transpose()?;
+        Ok(Self { preimage })
+    }
+}
+
+impl PreimageOracle for WitnessOracle {
+    fn hint(&mut self, _value: impl Hint) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&mut self, key: [u8; 32]) -> Result<Vec<u8>> {
+        match &self.preimage {
+            Some((k, value)) if *k == key => Ok(value.clone()),
+            _ => bail!(
+                "Missing preimage for key 0x{}",
+                alloy_primitives::hex::encode(key)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::verify_step;
+    use crate::{
+        test_utils::{load_program, ClaimTestOracle},
+        types::Syscall,
+        Fd, InstrumentedState,
+    };
+
+    #[test]
+    fn verify_claim() {
+        let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
+        let mut ins = InstrumentedState::new(
+            load_program(elf_bytes),
+            ClaimTestOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        );
+
+        // Verify the first steps, and every read and write syscall after them.
+        let mut preimage_reads = 0;
+        while !ins.state.exited {
+            let instruction = ins.state.memory.peek_memory(ins.state.pc).unwrap();
+            let io =
+                [Syscall::Read as u32, Syscall::Write as u32].contains(&ins.state.registers[2]);
+            if ins.state.step >= 5_000 && (instruction != 0x0000000C || !io) {
+                ins.step(false).unwrap();
+                continue;
+            }
+            let witness = ins.step(true).unwrap().unwrap();
+            preimage_reads += witness.has_preimage() as u64;
+            assert_eq!(
+                witness.verify().unwrap(),
+                ins.state.encode_witness().unwrap()
+            );
+        }
+        assert!(preimage_reads > 0);
+
+        // Exited states do not step.
+        let mut exited = ins.state.encode_witness().unwrap();
+        assert_eq!(
+            verify_step(&exited, &[0; 2 * 28 * 32], None).unwrap(),
+            exited
+        );
+        exited[89] = 0;
+        assert!(verify_step(&exited, &[0; 2 * 28 * 32], None).is_err());
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let elf_bytes = include_bytes!("../../../example/bin/claim.elf");
+        let mut ins = InstrumentedState::new(
+            load_program(elf_bytes),
+            ClaimTestOracle::default(),
+            Vec::default(),
+            Vec::default(),
+        );
+
+        // Find a store, which checks both proofs.
+        let witness = loop {
+            let instruction = ins.state.memory.get_memory(ins.state.pc).unwrap();
+            let witness = ins.step(true).unwrap().unwrap();
+            if instruction >> 26 == 0x2B {
+                break witness;
+            }
+        };
+        let post = ins.state.encode_witness().unwrap();
+        assert_eq!(witness.verify().unwrap(), post);
+
+        for offset in [0, 31, 28 * 32 - 1, 28 * 32, 28 * 32 + 100, 2 * 28 * 32 - 1] {
+            let mut proof = witness.mem_proof.clone();
+            proof[offset] ^= 1;
+            assert!(verify_step(&witness.state, &proof, None).is_err());
+        }
+        assert!(verify_step(&witness.state, &witness.mem_proof[1..], None).is_err());
+        assert!(verify_step(&witness.state[1..], &witness.mem_proof, None).is_err());
+
+        // A preimage read fails without the preimage.
+        let witness = loop {
+            let instruction = ins.state.memory.peek_memory(ins.state.pc).unwrap();
+            let (v0, a0) = (ins.state.registers[2], ins.state.registers[4]);
+            if instruction == 0x0000000C
+                && v0 == Syscall::Read as u32
+                && a0 == Fd::PreimageRead as u32
+            {
+                break ins.step(true).unwrap().unwrap();
+            }
+            ins.step(false).unwrap();
+        };
+        assert!(witness.verify().is_ok());
+        assert!(verify_step(&witness.state, &witness.mem_proof, None).is_err());
+    }
+}
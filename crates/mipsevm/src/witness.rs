@@ -1,6 +1,6 @@
 //! This module contains the various witness types.
 
-use crate::{utils::keccak256, State, StateWitness, StateWitnessHasher};
+use crate::{utils::keccak256, MtStateWitness, State, StateWitness, StateWitnessHasher};
 use alloy_primitives::{B256, U256};
 use alloy_sol_types::{sol, SolCall};
 use preimage_oracle::KeyType;
@@ -9,6 +9,16 @@ use revm::primitives::Bytes;
 /// The size of an encoded [StateWitness] in bytes.
 pub const STATE_WITNESS_SIZE: usize = 226;
 
+/// The size of an encoded [MtStateWitness] in bytes.
+pub const MT_STATE_WITNESS_SIZE: usize = 163;
+
+/// The size of an encoded [crate::ThreadWitness] in bytes.
+pub const THREAD_WITNESS_SIZE: usize = 166;
+
+/// The size of the thread proof that prefixes the memory proofs of a multithreaded step: the
+/// encoded current thread followed by the root of the rest of its thread stack.
+pub const THREAD_PROOF_SIZE: usize = THREAD_WITNESS_SIZE + 32;
+
 impl StateWitnessHasher for StateWitness {
     fn state_hash(&self) -> [u8; 32] {
         let mut hash = keccak256(self);
@@ -20,13 +30,25 @@ impl StateWitnessHasher for StateWitness {
     }
 }
 
+impl StateWitnessHasher for MtStateWitness {
+    fn state_hash(&self) -> [u8; 32] {
+        let mut hash = keccak256(self);
+        let offset = 32 * 2 + 4 * 2;
+        let exit_code = self[offset];
+        let exited = self[offset + 1] == 1;
+        hash[0] = State::vm_status(exited, exit_code) as u8;
+        *hash
+    }
+}
+
 /// A [StepWitness] is produced after each instruction step of the MIPS emulator. It contains
-/// the encoded [StateWitness], the proof of memory access, and the preimage key, value, and
-/// offset.
+/// the encoded [StateWitness] (or [MtStateWitness] in multithreaded mode), the proof of memory
+/// access, and the preimage key, value, and offset.
 pub struct StepWitness {
     /// The encoded state witness
-    pub state: StateWitness,
-    /// The proof of memory access
+    pub state: Vec<u8>,
+    /// The proof of memory access. In multithreaded mode, this is prefixed by the thread proof
+    /// and followed by a second proof of memory access.
     pub mem_proof: Vec<u8>,
     /// The preimage key
     pub preimage_key: Option<[u8; 32]>,
@@ -39,7 +61,7 @@ pub struct StepWitness {
 impl Default for StepWitness {
     fn default() -> Self {
         Self {
-            state: [0u8; crate::witness::STATE_WITNESS_SIZE],
+            state: Vec::with_capacity(STATE_WITNESS_SIZE),
             mem_proof: Vec::with_capacity(28 * 32 * 2),
             preimage_key: Default::default(),
             preimage_value: Default::default(),